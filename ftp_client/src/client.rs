@@ -10,30 +10,66 @@ use std::io;
 use std::time::Duration;
 use std::io::Write;
 use std::io::prelude::*;
-use std::io::BufReader;
-use std::net::{TcpStream, Ipv4Addr, Shutdown, SocketAddrV4};
+use std::io::{BufReader, Seek, SeekFrom};
+use std::fs::OpenOptions;
+use std::net::{TcpListener, TcpStream, Ipv4Addr, Shutdown, SocketAddr, SocketAddrV4};
 
-use std::sync::{Arc, Mutex};
+use std::sync::Mutex;
+
+use ascii::{self, FromCrlf, ToCrlf};
+use block::{BlockDecoder, BlockWriter};
+use flate2::Compression;
+use flate2::read::ZlibDecoder;
+use flate2::write::ZlibEncoder;
+use stream::{FtpStream, TlsContext, TlsOptions};
 
 
 #[derive(Debug, Copy, Clone)]
 pub enum FtpMode {
-    Active(SocketAddrV4),
+    //Address to listen on for the server to connect back to
+    Active(SocketAddr),
     Passive,
+    //EPRT and EPSV from RFC 2428, used over IPv6 whatever the mode says
+    ExtendedActive(SocketAddr),
+    ExtendedPassive,
+}
+
+/// Where the data connection of the next transfer comes from
+pub enum DataPort {
+    //Passive, we connect to the server
+    Connect(SocketAddr),
+    //Active, the server connects back to us
+    Listen(TcpListener),
+    //The connection MODE B left open after the last transfer
+    Open,
 }
 
+//How long to wait for the server to connect back in active mode
+const ACCEPT_TIMEOUT: u64 = 10;
+
 #[derive(Debug, Copy, Clone)]
 pub enum FtpType {
     Binary,
     ASCII,
 }
 
+//How data goes over the data connection, MODE S, MODE Z or MODE B
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum TransferMode {
+    Stream,
+    Deflate,
+    Block,
+}
+
+//The data connection MODE B left open, the next transfer goes over it
+static KEPT_DATA: Mutex<Option<FtpStream>> = Mutex::new(None);
+
 
 
 
 //Writes commands to the server
 //
-pub fn write_command(client: &mut BufReader<TcpStream>, cmd: &str, debug: bool) {
+pub fn write_command(client: &mut BufReader<FtpStream>, cmd: &str, debug: bool) {
     client.get_mut()
         .write(cmd.to_string().as_bytes())
         .expect("Something went wrong writing command");
@@ -46,14 +82,21 @@ pub fn write_command(client: &mut BufReader<TcpStream>, cmd: &str, debug: bool)
 
 }
 
-//reads the response back
-pub fn read_message(client: &mut BufReader<TcpStream>, verbose: bool) -> String {
+//reads the response back, skipping the 110 restart markers of a MODE B
+//upload
+pub fn read_message(client: &mut BufReader<FtpStream>, verbose: bool) -> String {
     let mut response = String::new();
-    client.read_line(&mut response).expect("Could not read message");
-    info!("SERVER: {}", response);
+    loop {
+        client.read_line(&mut response).expect("Could not read message");
+        info!("SERVER: {}", response);
 
-    if verbose {
-        println!("SERVER: {}", response);
+        if verbose {
+            println!("SERVER: {}", response);
+        }
+        if !response.starts_with("110 ") {
+            break;
+        }
+        response.clear();
     }
 
     return response;
@@ -61,7 +104,7 @@ pub fn read_message(client: &mut BufReader<TcpStream>, verbose: bool) -> String
 }
 
 //reads multi line message
-pub fn read_multi_message(client: &mut BufReader<TcpStream>) -> String {
+pub fn read_multi_message(client: &mut BufReader<FtpStream>) -> String {
     let response = "end of transmission".to_string();
 
     client.get_mut().set_read_timeout(Some(Duration::from_millis(500))).expect("Could set timeout");
@@ -93,7 +136,7 @@ pub fn get_code_from_respone(line: &str) -> Result<i32, &'static str> {
     Ok(number)
 }
 
-pub fn make_dir(mut stream: &mut BufReader<TcpStream>, args: &str, debug: bool, verbose: bool) {
+pub fn make_dir(mut stream: &mut BufReader<FtpStream>, args: &str, debug: bool, verbose: bool) {
     let cmd = format!("MKD {}\r\n", args);
     info!("Sending MKD command");
 
@@ -101,7 +144,7 @@ pub fn make_dir(mut stream: &mut BufReader<TcpStream>, args: &str, debug: bool,
     let _ = read_message(&mut stream, verbose);
 }
 
-pub fn change_dir(mut stream: &mut BufReader<TcpStream>, args: &str, debug: bool, verbose: bool) {
+pub fn change_dir(mut stream: &mut BufReader<FtpStream>, args: &str, debug: bool, verbose: bool) {
     let cmd = format!("CWD {}\r\n", args);
     info!("Sending CWD command");
 
@@ -109,7 +152,7 @@ pub fn change_dir(mut stream: &mut BufReader<TcpStream>, args: &str, debug: bool
     let _ = read_message(&mut stream, verbose);
 }
 
-pub fn change_dir_up(mut stream: &mut BufReader<TcpStream>, debug: bool, verbose: bool) {
+pub fn change_dir_up(mut stream: &mut BufReader<FtpStream>, debug: bool, verbose: bool) {
     let cmd = "CDUP\r\n".to_string();
 
     info!("Sending CUP command");
@@ -191,7 +234,7 @@ pub fn print_locoal_dir() {
 
 //Remove a directory
 
-pub fn remove_dir(mut stream: &mut BufReader<TcpStream>, args: &str, debug: bool, verbose: bool) {
+pub fn remove_dir(mut stream: &mut BufReader<FtpStream>, args: &str, debug: bool, verbose: bool) {
     let cmd = format!("RMD {}\r\n", args);
     info!("SENDING CMD command");
 
@@ -200,7 +243,7 @@ pub fn remove_dir(mut stream: &mut BufReader<TcpStream>, args: &str, debug: bool
 }
 
 //Rhelp
-pub fn r_help(mut stream: &mut BufReader<TcpStream>, debug: bool, verbose: bool) {
+pub fn r_help(mut stream: &mut BufReader<FtpStream>, debug: bool, verbose: bool) {
     let cmd = "HELP\r\n".to_string();
 
     info!("SENDING help command");
@@ -210,7 +253,7 @@ pub fn r_help(mut stream: &mut BufReader<TcpStream>, debug: bool, verbose: bool)
 
 //Delete  a File
 
-pub fn dele(mut stream: &mut BufReader<TcpStream>, args: &str, debug: bool, verbose: bool) {
+pub fn dele(mut stream: &mut BufReader<FtpStream>, args: &str, debug: bool, verbose: bool) {
     info!("SENDING DELE command");
     let cmd = format!("DELE {}\r\n", args);
 
@@ -220,7 +263,7 @@ pub fn dele(mut stream: &mut BufReader<TcpStream>, args: &str, debug: bool, verb
 
 //Print working dir
 
-pub fn print_working_dir(mut stream: &mut BufReader<TcpStream>, debug: bool, verbose: bool) {
+pub fn print_working_dir(mut stream: &mut BufReader<FtpStream>, debug: bool, verbose: bool) {
     let cmd = "PWD\r\n".to_string();
     info!("SENDING PWD command");
 
@@ -229,7 +272,7 @@ pub fn print_working_dir(mut stream: &mut BufReader<TcpStream>, debug: bool, ver
 }
 
 //QUIT
-pub fn quit_server(mut stream: &mut BufReader<TcpStream>, debug: bool, verbose: bool) {
+pub fn quit_server(mut stream: &mut BufReader<FtpStream>, debug: bool, verbose: bool) {
     let cmd = "QUIT\r\n".to_string();
     info!("EXITING CLIENT");
     write_command(&mut stream, &cmd, debug);
@@ -237,10 +280,11 @@ pub fn quit_server(mut stream: &mut BufReader<TcpStream>, debug: bool, verbose:
 }
 
 //Put a file
-pub fn put(mut stream: &mut BufReader<TcpStream>,
+pub fn put(mut stream: &mut BufReader<FtpStream>,
            args: &str,
            ftp_mode: FtpMode,
            ftp_type: FtpType,
+           transfer_mode: TransferMode,
            debug: bool,
            verbose: bool,
            sunique: bool) {
@@ -264,31 +308,19 @@ pub fn put(mut stream: &mut BufReader<TcpStream>,
     response = read_message(&mut stream, verbose);
     response.clear();
 
-    match ftp_mode {
-        FtpMode::Passive => {
-
-            info!("Seding {} in passive mode to be stored as {} ",
-                  lpath,
-                  rpath);
-            write_command(&mut stream, "PASV \r\n", debug);
-            response = read_message(&mut stream, verbose);
-            let addr = get_pasv_address(&response);
-            match sunique {
-                true => write_command(&mut stream, &format!("STOR {} \r\n", rpath), debug),
-                false => write_command(&mut stream, &format!("STOU {} \r\n", rpath), debug),
-            }
-
-
-            stor_file(&addr, &lpath, &mut stream, debug);
-
-            response.clear();
-            response = read_message(&mut stream, verbose);
+    info!("Seding {} to be stored as {} ", lpath, rpath);
+    let port = match data_port(&mut stream, ftp_mode, debug, verbose) {
+        Some(port) => port,
+        None => return,
+    };
 
-        }
-        FtpMode::Active(_) => {
+    match sunique {
+        true => write_command(&mut stream, &format!("STOR {} \r\n", rpath), debug),
+        false => write_command(&mut stream, &format!("STOU {} \r\n", rpath), debug),
+    }
 
-            info!("Seding {} in active mode to be stored as {} ", lpath, rpath);
-        }
+    if stor_file(&port, &lpath, &mut stream, ftp_type, transfer_mode, debug, 0) {
+        response = read_message(&mut stream, verbose);
     }
 
 
@@ -296,10 +328,11 @@ pub fn put(mut stream: &mut BufReader<TcpStream>,
 }
 
 //Get a file
-pub fn get(mut stream: &mut BufReader<TcpStream>,
+pub fn get(mut stream: &mut BufReader<FtpStream>,
            args: &str,
            ftp_mode: FtpMode,
            ftp_type: FtpType,
+           transfer_mode: TransferMode,
            debug: bool,
            verbose: bool) {
     let mut response = String::new();
@@ -329,26 +362,15 @@ pub fn get(mut stream: &mut BufReader<TcpStream>,
     response = read_message(&mut stream, verbose);
     response.clear();
 
-    match ftp_mode {
-        FtpMode::Passive => {
-            info!("Retrieving {} in passive mode to be stored as {} ",
-                  rpath,
-                  lpath);
-            write_command(&mut stream, "PASV\r\n", debug);
-            response = read_message(&mut stream, verbose);
-
-            let addr = get_pasv_address(&response);
-            write_command(&mut stream, &format!("RETR {}\r\n", rpath), debug);
-            get_file(&addr, &lpath, &mut stream, verbose);
-            response.clear();
-            response = read_message(&mut stream, verbose);
-        }
-        FtpMode::Active(_) => {
+    info!("Retrieving {} to be stored as {} ", rpath, lpath);
+    let port = match data_port(&mut stream, ftp_mode, debug, verbose) {
+        Some(port) => port,
+        None => return,
+    };
 
-            info!("Retrieving {} in active mode to be stored as {} ",
-                  rpath,
-                  lpath);
-        }
+    write_command(&mut stream, &format!("RETR {}\r\n", rpath), debug);
+    if get_file(&port, &lpath, &mut stream, ftp_type, transfer_mode, verbose, 0) {
+        response = read_message(&mut stream, verbose);
     }
 
 
@@ -356,9 +378,10 @@ pub fn get(mut stream: &mut BufReader<TcpStream>,
 }
 
 //List Command
-pub fn list(mut stream: &mut BufReader<TcpStream>,
+pub fn list(mut stream: &mut BufReader<FtpStream>,
             args: &str,
             ftp_mode: FtpMode,
+            transfer_mode: TransferMode,
             debug: bool,
             verbose: bool) {
 
@@ -368,31 +391,23 @@ pub fn list(mut stream: &mut BufReader<TcpStream>,
     response = read_message(&mut stream, verbose);
     response.clear();
 
-    match ftp_mode {
-        FtpMode::Passive => {
-            info!("Retrieving LIST command in passive mode");
-
-            write_command(&mut stream, "PASV \r\n", debug);
-            response = read_message(&mut stream, verbose);
-            let addr = get_pasv_address(&response);
-            write_command(&mut stream, &format!("LIST {}\r\n", args), debug);
-            println!("args: {}", args);
-
-            list_file(&addr, args, &mut stream, verbose);
-            response.clear();
-            response = read_message(&mut stream, verbose);
+    info!("Retrieving LIST command");
+    let port = match data_port(&mut stream, ftp_mode, debug, verbose) {
+        Some(port) => port,
+        None => return,
+    };
 
-        }
-        FtpMode::Active(_) => {
+    write_command(&mut stream, &format!("LIST {}\r\n", args), debug);
+    println!("args: {}", args);
 
-            info!("Retrieving LIST command in passive mode");
-        }
+    if list_file(&port, args, &mut stream, transfer_mode, verbose) {
+        response = read_message(&mut stream, verbose);
     }
 
 }
 
 //mdele for deleting multiple files on the server
-pub fn mdele(mut stream: &mut BufReader<TcpStream>, args: &str, debug: bool, verbose: bool) {
+pub fn mdele(mut stream: &mut BufReader<FtpStream>, args: &str, debug: bool, verbose: bool) {
     let arg_list: Vec<&str> = args.split(' ').collect();
 
     info!("Deleting multiple files {}", args);
@@ -406,124 +421,121 @@ pub fn mdele(mut stream: &mut BufReader<TcpStream>, args: &str, debug: bool, ver
 }
 
 //mget for retrieving multiple files at once
-pub fn mget(mut stream: &mut BufReader<TcpStream>,
+pub fn mget(mut stream: &mut BufReader<FtpStream>,
             args: &str,
             ftp_mode: FtpMode,
             ftp_type: FtpType,
+            transfer_mode: TransferMode,
             debug: bool,
             verbose: bool) {
-    let mut threads = vec![];
-
     let arg_list: Vec<&str> = args.split(' ').collect();
 
     set_type(&mut stream, ftp_type, debug);
-
-    //Creating a clone of the stream that will be protected in a mutex
-    let mut temp_stream = stream.get_mut().try_clone().expect("Could not clone stream");
-    let mut shared_stream = Arc::new(Mutex::new(BufReader::new(temp_stream)));
-
     read_message(&mut stream, verbose);
     info!("retrieving multiple files {}", args);
 
-    for file in arg_list {
-        let arg = format!("{}", file);
-        let mut_stream = shared_stream.clone();
+    //A TLS stream can't be cloned, so the threads share the main stream
+    //through a mutex instead
+    let shared_stream = Mutex::new(stream);
 
-        let mut response = String::new();
-        let t_debug = debug.clone();
-        let t_verbose = verbose.clone();
+    thread::scope(|scope| {
+        let mut threads = vec![];
 
-        let thread = thread::spawn(move || match ftp_mode {
-            FtpMode::Passive => {
+        for file in arg_list {
+            let arg = format!("{}", file);
+            let mut_stream = &shared_stream;
 
+            let mut response = String::new();
+            let t_debug = debug.clone();
+            let t_verbose = verbose.clone();
+
+            let thread = scope.spawn(move || {
                 let mut buf_stream = mut_stream.lock().expect("could not lock main streamm");
-                write_command(&mut buf_stream, "PASV\r\n", t_debug);
-                response = read_message(&mut buf_stream, t_verbose);
-                let addr = get_pasv_address(&response);
+                let port = match data_port(&mut buf_stream, ftp_mode, t_debug, t_verbose) {
+                    Some(port) => port,
+                    None => return,
+                };
                 write_command(&mut buf_stream, &format!("RETR {}\r\n", arg), t_debug);
-                get_file(&addr, &arg, &mut buf_stream, t_verbose);
-                response.clear();
-                response = read_message(&mut buf_stream, t_verbose);
-            }
-            FtpMode::Active(_) => {}
-        });
+                if get_file(&port, &arg, &mut buf_stream, ftp_type, transfer_mode, t_verbose, 0) {
+                    response = read_message(&mut buf_stream, t_verbose);
+                }
+            });
 
-        threads.push(thread);
-    }
+            threads.push(thread);
+        }
 
-    for t in threads {
-        info!("Joining all threads");
-        let _ = t.join().unwrap();
-    }
+        for t in threads {
+            info!("Joining all threads");
+            let _ = t.join().unwrap();
+        }
+    });
 
 
 
 }
 
 //mput for storing multiple files
-pub fn mput(mut stream: &mut BufReader<TcpStream>,
+pub fn mput(mut stream: &mut BufReader<FtpStream>,
             args: &str,
             ftp_mode: FtpMode,
             ftp_type: FtpType,
+            transfer_mode: TransferMode,
             debug: bool,
             verbose: bool) {
-    let mut threads = vec![];
-
     let arg_list: Vec<&str> = args.split(' ').collect();
     let mut response = String::new();
 
     info!("storing multiple files {}", args);
     set_type(&mut stream, ftp_type, debug);
-
-    //Creating a clone of the stream that will be protected in a mutex
-    let mut temp_stream = stream.get_mut().try_clone().expect("Could not clone stream");
-
-    //Creating a new BufReader of the main stream protected inside a mutex
-    let mut shared_stream = Arc::new(Mutex::new(BufReader::new(temp_stream)));
-
     let _ = read_message(&mut stream, verbose);
 
-    for file in arg_list {
-        let arg = format!("{}", file);
-        //cloning the mutex inside of each thread
-        let mut_stream = shared_stream.clone();
+    //A TLS stream can't be cloned, so the threads share the main stream
+    //through a mutex instead
+    let shared_stream = Mutex::new(stream);
+
+    thread::scope(|scope| {
+        let mut threads = vec![];
 
-        let mut response = String::new();
-        let t_debug = debug.clone();
-        let t_verbose = verbose.clone();
+        for file in arg_list {
+            let arg = format!("{}", file);
+            //borrowing the mutex inside of each thread
+            let mut_stream = &shared_stream;
 
-        let thread = thread::spawn(move || match ftp_mode {
-            FtpMode::Passive => {
+            let mut response = String::new();
+            let t_debug = debug.clone();
+            let t_verbose = verbose.clone();
 
+            let thread = scope.spawn(move || {
                 let mut buf_stream = mut_stream.lock().expect("could not lock main streamm");
-                write_command(&mut buf_stream, "PASV\r\n", t_debug);
-                response = read_message(&mut buf_stream, t_verbose);
-                let addr = get_pasv_address(&response);
+                let port = match data_port(&mut buf_stream, ftp_mode, t_debug, t_verbose) {
+                    Some(port) => port,
+                    None => return,
+                };
                 write_command(&mut buf_stream, &format!("STOR {}\r\n", arg), t_debug);
-                stor_file(&addr, &arg, &mut buf_stream, t_debug);
-                response.clear();
-                response = read_message(&mut buf_stream, t_verbose);
-            }
-            FtpMode::Active(addr) => {}
-        });
+                if stor_file(&port, &arg, &mut buf_stream, ftp_type, transfer_mode, t_debug, 0) {
+                    response = read_message(&mut buf_stream, t_verbose);
+                }
+            });
 
-        threads.push(thread);
-    }
+            threads.push(thread);
+        }
 
-    //Joining threads before end of function
-    for t in threads {
-        info!("Joining all threads");
-        let _ = t.join().unwrap();
-    }
+        //Joining threads before end of function
+        for t in threads {
+            info!("Joining all threads");
+            let _ = t.join().unwrap();
+        }
+    });
 
 
 
 }
 
 //mlist Command for listing multiple directories
-pub fn mlist(mut stream: &mut BufReader<TcpStream>,
+pub fn mlist(mut stream: &mut BufReader<FtpStream>,
              args: &str,
              ftp_mode: FtpMode,
+             transfer_mode: TransferMode,
              debug: bool,
              verbose: bool) {
 
@@ -547,31 +559,23 @@ pub fn mlist(mut stream: &mut BufReader<TcpStream>,
                 response = read_message(&mut stream, verbose);
                 response.clear();
 
-                match ftp_mode {
-                    FtpMode::Passive => {
-
-                        write_command(&mut stream, "PASV \r\n", debug);
-                        response = read_message(&mut stream, verbose);
-                        let addr = get_pasv_address(&response);
-                        write_command(&mut stream, &format!("LIST {}\r\n", file), debug);
-                        println!("args: {}", file);
-
-                        let mut stream2 = TcpStream::connect(addr)
-                            .expect("could not read connect address");
-
-                        response = read_message(&mut stream, verbose);
-                        let mut buf: Vec<u8> = Vec::new();
-                        stream2.read_to_end(&mut buf).expect("Could not read second stream");
-                        let text = (String::from_utf8(buf))
-                            .expect("Could not read text from streamm");
-                        stream2.shutdown(Shutdown::Both).expect("Failed to close data stream");
-                        write!(local_file, "{}", text);
-                        response.clear();
-                        response = read_message(&mut stream, verbose);
-
-                    }
-                    FtpMode::Active(_) => {}
-                }
+                let port = match data_port(&mut stream, ftp_mode, debug, verbose) {
+                    Some(port) => port,
+                    None => return,
+                };
+                write_command(&mut stream, &format!("LIST {}\r\n", file), debug);
+                println!("args: {}", file);
+
+                let mut stream2 = match open_data(&mut stream, &port, verbose) {
+                    Some(stream2) => stream2,
+                    None => continue,
+                };
+
+                let text = read_listing(&mut stream2, transfer_mode);
+                stream2.shutdown().expect("Failed to close data stream");
+                write!(local_file, "{}", text);
+                response.clear();
+                response = read_message(&mut stream, verbose);
             }
         }
         _ => {
@@ -582,7 +586,7 @@ pub fn mlist(mut stream: &mut BufReader<TcpStream>,
 
 }
 
-pub fn rstatus(mut stream: &mut BufReader<TcpStream>, args: &str, debug: bool, verbose: bool) {
+pub fn rstatus(mut stream: &mut BufReader<FtpStream>, args: &str, debug: bool, verbose: bool) {
     let cmd = format!("STAT {}\r\n", args);
     info!("Sending STAT command to server");
 
@@ -590,10 +594,21 @@ pub fn rstatus(mut stream: &mut BufReader<TcpStream>, args: &str, debug: bool, v
     let _ = read_multi_message(&mut stream);
 }
 
+//Sends a SITE command, e.g. SITE QUOTA, and prints the reply
+pub fn site(mut stream: &mut BufReader<FtpStream>, args: &str, debug: bool, verbose: bool) {
+    let cmd = format!("SITE {}\r\n", args);
+    info!("Sending SITE {} to server", args);
+
+    write_command(&mut stream, &cmd, debug);
+    let _ = read_multi_message(&mut stream);
+}
 
-pub fn appe(mut stream: &mut BufReader<TcpStream>,
+
+pub fn appe(mut stream: &mut BufReader<FtpStream>,
             args: &str,
             ftp_mode: FtpMode,
+            ftp_type: FtpType,
+            transfer_mode: TransferMode,
             debug: bool,
             verbose: bool) {
 
@@ -612,36 +627,28 @@ pub fn appe(mut stream: &mut BufReader<TcpStream>,
     }
 
     let mut response = String::new();
-    set_type(&mut stream, FtpType::ASCII, debug);
+    set_type(&mut stream, ftp_type, debug);
     response = read_message(&mut stream, verbose);
     response.clear();
 
-    match ftp_mode {
-        FtpMode::Passive => {
-
-            info!("Appending to file {} in passive mode", args);
-            write_command(&mut stream, "PASV \r\n", debug);
-            response = read_message(&mut stream, verbose);
-            let addr = get_pasv_address(&response);
-            write_command(&mut stream, &format!("APPE {} \r\n", rpath), debug);
-            stor_file(&addr, &lpath, &mut stream, verbose);
-
-            response.clear();
-            response = read_message(&mut stream, verbose);
-        }
-
-        FtpMode::Active(_) => {
+    info!("Appending to file {}", args);
+    let port = match data_port(&mut stream, ftp_mode, debug, verbose) {
+        Some(port) => port,
+        None => return,
+    };
 
-            info!("Appending to file {} in active mode", args);
-        }
+    write_command(&mut stream, &format!("APPE {} \r\n", rpath), debug);
+    if stor_file(&port, &lpath, &mut stream, ftp_type, transfer_mode, verbose, 0) {
+        response = read_message(&mut stream, verbose);
     }
 }
 
 
-pub fn get_u(mut stream: &mut BufReader<TcpStream>,
+pub fn get_u(mut stream: &mut BufReader<FtpStream>,
              args: &str,
              ftp_mode: FtpMode,
              ftp_type: FtpType,
+             transfer_mode: TransferMode,
              debug: bool,
              verbose: bool) {
 
@@ -665,37 +672,155 @@ pub fn get_u(mut stream: &mut BufReader<TcpStream>,
 
     let mut local = Path::new(&lpath);
 
-    match ftp_mode {
+    set_type(&mut stream, ftp_type, debug);
+    response = read_message(&mut stream, verbose);
+    response.clear();
+
+    let port = match data_port(&mut stream, ftp_mode, debug, verbose) {
+        Some(port) => port,
+        None => return,
+    };
 
-        FtpMode::Passive => {
+    write_command(&mut stream, &format!("RETR {}\r\n", rpath), debug);
+
+    let started = if local.exists() {
+        println!("Local file exits, replacing with {}", s);
+        info!("Local file exits, replacing with {}", s);
+        get_file(&port, &s, &mut stream, ftp_type, transfer_mode, verbose, 0)
+    } else {
+        info!("Storing file {}", rpath);
+        get_file(&port, &lpath, &mut stream, ftp_type, transfer_mode, verbose, 0)
+    };
+    if started {
+        response = read_message(&mut stream, verbose);
+    }
+}
+
+//Resumes a download, starting from the size of the local file
+pub fn reget(mut stream: &mut BufReader<FtpStream>,
+             args: &str,
+             ftp_mode: FtpMode,
+             ftp_type: FtpType,
+             transfer_mode: TransferMode,
+             debug: bool,
+             verbose: bool) {
+    let mut response = String::new();
+    let mut lpath = String::new();
+    let mut rpath = String::new();
 
-            write_command(&mut stream, "PASV \r\n", debug);
-            response = read_message(&mut stream, verbose);
+    match args.find(' ') {
+        Some(pos) => {
+            rpath = args[0..pos].to_string();
+            lpath = args[pos + 1..].to_string();
+        }
+        None => {
+            rpath = args.to_string();
+            lpath = args.to_string();
+        }
+    }
 
-            let addr = get_pasv_address(&response);
+    //Under TYPE A the server counts what it sent with CRLF line ends
+    let offset = match (File::open(&lpath), ftp_type) {
+        (Ok(file), FtpType::ASCII) => {
+            io::copy(&mut ToCrlf::new(file), &mut io::sink()).unwrap_or(0)
+        }
+        (Ok(file), FtpType::Binary) => file.metadata().map(|meta| meta.len()).unwrap_or(0),
+        (Err(_), _) => 0,
+    };
 
-            write_command(&mut stream, &format!("RETR {}\r\n", rpath), debug);
+    set_type(&mut stream, ftp_type, debug);
+    response = read_message(&mut stream, verbose);
+    response.clear();
 
-            if local.exists() {
-                println!("Local file exits, replacing with {}", s);
-                info!("Local file exits, replacing with {}", s);
-                get_file(&addr, &s, &mut stream, verbose);
-            } else {
-                info!("Storing file {}", rpath);
-                get_file(&addr, &lpath, &mut stream, verbose);
-            }
-            response.clear();
-            response = read_message(&mut stream, verbose);
+    info!("Resuming {} at byte {} to be stored as {}", rpath, offset, lpath);
+    let port = match data_port(&mut stream, ftp_mode, debug, verbose) {
+        Some(port) => port,
+        None => return,
+    };
+
+    if !send_rest(&mut stream, offset, debug, verbose) {
+        return;
+    }
+
+    write_command(&mut stream, &format!("RETR {}\r\n", rpath), debug);
+    if get_file(&port, &lpath, &mut stream, ftp_type, transfer_mode, verbose, offset) {
+        response = read_message(&mut stream, verbose);
+    }
+}
+
+//Resumes an upload, starting from the size of the remote file
+pub fn restart(mut stream: &mut BufReader<FtpStream>,
+               args: &str,
+               ftp_mode: FtpMode,
+               ftp_type: FtpType,
+               transfer_mode: TransferMode,
+               debug: bool,
+               verbose: bool) {
+    let mut response = String::new();
+    let mut lpath = String::new();
+    let mut rpath = String::new();
 
+    match args.find(' ') {
+        Some(pos) => {
+            lpath = args[0..pos].to_string();
+            rpath = args[pos + 1..].to_string();
         }
+        None => {
+            lpath = args.to_string();
+            rpath = args.to_string();
+        }
+    }
 
-        FtpMode::Active(_) => {}
+    set_type(&mut stream, ftp_type, debug);
+    response = read_message(&mut stream, verbose);
+    response.clear();
+
+    write_command(&mut stream, &format!("SIZE {}\r\n", rpath), debug);
+    response = read_message(&mut stream, verbose);
+    let offset = match get_code_from_respone(&response) {
+        Ok(213) => response[4..].trim().parse::<u64>().unwrap_or(0),
+        _ => 0,
+    };
+    response.clear();
+
+    info!("Resuming upload of {} at byte {} to {}", lpath, offset, rpath);
+    let port = match data_port(&mut stream, ftp_mode, debug, verbose) {
+        Some(port) => port,
+        None => return,
+    };
+
+    if !send_rest(&mut stream, offset, debug, verbose) {
+        return;
+    }
+
+    write_command(&mut stream, &format!("STOR {}\r\n", rpath), debug);
+    if stor_file(&port, &lpath, &mut stream, ftp_type, transfer_mode, verbose, offset) {
+        response = read_message(&mut stream, verbose);
+    }
+}
+
+//Sends REST and tells whether the server accepted the restart offset
+fn send_rest(mut stream: &mut BufReader<FtpStream>,
+             offset: u64,
+             debug: bool,
+             verbose: bool)
+             -> bool {
+    write_command(&mut stream, &format!("REST {}\r\n", offset), debug);
+    let response = read_message(&mut stream, verbose);
+
+    match get_code_from_respone(&response) {
+        Ok(350) => true,
+        _ => {
+            println!("Server does not support restarting transfers");
+            info!("REST {} was refused", offset);
+            false
+        }
     }
 }
 
 //Retrieves the size of a file
 
-pub fn size(mut stream: &mut BufReader<TcpStream>, args: &str, debug: bool, verbose: bool) {
+pub fn size(mut stream: &mut BufReader<FtpStream>, args: &str, debug: bool, verbose: bool) {
     let cmd = format!("SIZE {}\r\n", args);
     info!("Sending SIZE command to server");
     let mut response = String::new();
@@ -710,17 +835,20 @@ pub fn size(mut stream: &mut BufReader<TcpStream>, args: &str, debug: bool, verb
 }
 
 // Status of local staus
-pub fn status(mut stream: &mut BufReader<TcpStream>,
+pub fn status(mut stream: &mut BufReader<FtpStream>,
               debug: bool,
               verbose: bool,
               ftp_type: FtpType,
               ftp_mode: FtpMode,
+              transfer_mode: TransferMode,
               sunique: bool,
               runique: bool) {
 
     let mode = match ftp_mode {
         FtpMode::Passive => "Passive Mode",
         FtpMode::Active(_) => "Active Mode",
+        FtpMode::ExtendedPassive => "Extended Passive Mode",
+        FtpMode::ExtendedActive(_) => "Extended Active Mode",
     };
 
     let t_type = match ftp_type {
@@ -737,13 +865,19 @@ pub fn status(mut stream: &mut BufReader<TcpStream>,
     info!("Mode is set  to {}", mode);
     println!("Transfer Type is set  to {}", t_type);
     info!("Transfer Type is set  to {}", t_type);
+    println!("Compression is set  to {}", transfer_mode == TransferMode::Deflate);
+    info!("Compression is set  to {}", transfer_mode == TransferMode::Deflate);
+    println!("Block mode is set  to {}", transfer_mode == TransferMode::Block);
+    info!("Block mode is set  to {}", transfer_mode == TransferMode::Block);
     println!("Debug is set  to {}", debug);
     info!("Debug is set  to {}", debug);
     println!("Verbose is set  to {}", verbose);
     info!("Verbose is set  to {}", verbose);
+    println!("TLS is set  to {}", stream.get_ref().is_tls());
+    info!("TLS is set  to {}", stream.get_ref().is_tls());
 }
 
-pub fn rename(mut stream: &mut BufReader<TcpStream>, args: &str, debug: bool, verbose: bool) {
+pub fn rename(mut stream: &mut BufReader<FtpStream>, args: &str, debug: bool, verbose: bool) {
     info!("SENDING RNFR command");
     let arg_list: Vec<&str> = args.split(' ').collect();
     let from = arg_list[0];
@@ -776,7 +910,7 @@ pub fn rename(mut stream: &mut BufReader<TcpStream>, args: &str, debug: bool, ve
 
 
 // System call of remote
-pub fn system(mut stream: &mut BufReader<TcpStream>, args: &str, debug: bool, verbose: bool) {
+pub fn system(mut stream: &mut BufReader<FtpStream>, args: &str, debug: bool, verbose: bool) {
     let cmd = format!("SYST {}\r\n", args);
     let mut response = String::new();
 
@@ -785,12 +919,194 @@ pub fn system(mut stream: &mut BufReader<TcpStream>, args: &str, debug: bool, ve
     println!("{}", response);
 }
 
+/// Negotiates TLS on the control connection with AUTH TLS, then asks for
+/// protected data connections. Returns `Ok(false)` when the server refuses,
+/// an error means the handshake failed and the connection is unusable
+pub fn auth_tls(mut stream: &mut BufReader<FtpStream>,
+                host: &str,
+                options: &TlsOptions,
+                debug: bool,
+                verbose: bool)
+                -> io::Result<bool> {
+    if stream.get_ref().is_tls() {
+        println!("Already using TLS");
+        return Ok(true);
+    }
+
+    let context = try!(TlsContext::new(host, options));
+
+    info!("Sending AUTH TLS");
+    write_command(&mut stream, "AUTH TLS\r\n", debug);
+    let response = read_message(&mut stream, verbose);
+    match get_code_from_respone(&response) {
+        Ok(234) => {}
+        _ => {
+            println!("Server refused AUTH TLS");
+            return Ok(false);
+        }
+    }
+
+    try!(stream.get_mut().upgrade(&context));
+    println!("TLS connection established");
+    info!("TLS connection established");
+
+    //PBSZ is always 0 over TLS, PROT P protects the data connections as well,
+    //so a plain one MODE B left open goes
+    close_kept_data();
+    write_command(&mut stream, "PBSZ 0\r\n", debug);
+    let _ = read_message(&mut stream, verbose);
+    write_command(&mut stream, "PROT P\r\n", debug);
+    let response = read_message(&mut stream, verbose);
+    match get_code_from_respone(&response) {
+        Ok(200) => {}
+        _ => println!("Server refused PROT P, data connections are not encrypted"),
+    }
+
+    Ok(true)
+}
+
 //helper function to turn server port into valid tcp_stream port
 fn to_ftp_port(b1: u16, b2: u16) -> u16 {
     b1 * 256 + b2
 }
 
-fn write_to_stream(file: &mut File, stream: &mut TcpStream) {
+//Sends a local file from `offset` on, with CRLF line ends in ASCII mode,
+//deflated after MODE Z and in blocks with restart markers after MODE B
+fn write_to_stream(file: &mut File,
+                   stream: &mut FtpStream,
+                   ftp_type: FtpType,
+                   transfer_mode: TransferMode,
+                   offset: u64) {
+    match transfer_mode {
+        TransferMode::Stream => send_file(file, stream, ftp_type),
+        TransferMode::Deflate => {
+            let mut encoder = ZlibEncoder::new(stream, Compression::default());
+            send_file(file, &mut encoder, ftp_type);
+            encoder.finish().expect("Could not write to remote locatio");
+        }
+        TransferMode::Block => {
+            let mut blocks = BlockWriter::with_markers(stream, offset);
+            send_file(file, &mut blocks, ftp_type);
+            blocks.finish().expect("Could not write to remote locatio");
+        }
+    }
+}
+
+//Stores a remote file, inflating it after MODE Z and turning CRLF back into
+//LF in ASCII mode. Returns false when a MODE B transfer broke off before the
+//end of the file, in the other modes that can't be told
+fn write_to_file(file: &mut File,
+                 stream: &mut FtpStream,
+                 ftp_type: FtpType,
+                 transfer_mode: TransferMode)
+                 -> bool {
+    match transfer_mode {
+        TransferMode::Stream => receive_file(file, stream, ftp_type),
+        TransferMode::Deflate => receive_file(file, &mut ZlibDecoder::new(stream), ftp_type),
+        TransferMode::Block => {
+            let mut blocks = BlockReader::new(stream);
+            receive_file(file, &mut blocks, ftp_type);
+            if !blocks.eof() {
+                match blocks.marker() {
+                    Some(marker) => {
+                        println!("Transfer cut off after restart marker {}, reget resumes it",
+                                 marker)
+                    }
+                    None => println!("Transfer cut off, reget resumes it"),
+                }
+                return false;
+            }
+        }
+    }
+    true
+}
+
+//The file data out of MODE B blocks, read off the connection up to the EOF
+//block and no further. A connection that closes before it gives an
+//UnexpectedEof error
+struct BlockReader<R: Read> {
+    inner: R,
+    decoder: BlockDecoder,
+    //The last restart marker the server sent
+    marker: Option<String>,
+}
+
+impl<R: Read> BlockReader<R> {
+    fn new(inner: R) -> BlockReader<R> {
+        BlockReader {
+            inner: inner,
+            decoder: BlockDecoder::new(),
+            marker: None,
+        }
+    }
+
+    //Whether the whole file came in, up to the EOF block
+    fn eof(&self) -> bool {
+        self.decoder.wanted() == 0
+    }
+
+    fn marker(&self) -> Option<&str> {
+        self.marker.as_ref().map(|marker| marker.as_str())
+    }
+}
+
+impl<R: Read> Read for BlockReader<R> {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let want = self.decoder.wanted().min(out.len());
+            if want == 0 {
+                return Ok(0);
+            }
+
+            let n = try!(self.inner.read(&mut out[..want]));
+            if n == 0 {
+                return Err(io::Error::new(io::ErrorKind::UnexpectedEof,
+                                          "Data connection closed inside a block"));
+            }
+            //Data comes back in place, headers and markers are taken out
+            let data = self.decoder.decode(&out[..n]).len();
+            if let Some((marker, _)) = self.decoder.take_marker() {
+                info!("Restart marker {}", marker);
+                self.marker = Some(marker);
+            }
+            if data > 0 {
+                return Ok(data);
+            }
+        }
+    }
+}
+
+fn send_file<W: Write>(file: &mut File, stream: &mut W, ftp_type: FtpType) {
+    match ftp_type {
+        FtpType::Binary => copy_to_stream(file, stream),
+        FtpType::ASCII => copy_to_stream(&mut ToCrlf::new(file), stream),
+    }
+}
+
+fn receive_file<R: Read>(file: &mut File, stream: &mut R, ftp_type: FtpType) {
+    match ftp_type {
+        FtpType::Binary => copy_from_stream(file, stream),
+        FtpType::ASCII => {
+            let mut file = FromCrlf::new(file);
+            copy_from_stream(&mut file, stream);
+            file.finish().expect("Could not write to local locatio");
+        }
+    }
+}
+
+//Reads a whole directory listing off the data connection
+fn read_listing(stream: &mut FtpStream, transfer_mode: TransferMode) -> String {
+    let mut buf: Vec<u8> = Vec::new();
+    match transfer_mode {
+        TransferMode::Stream => stream.read_to_end(&mut buf),
+        TransferMode::Deflate => ZlibDecoder::new(stream).read_to_end(&mut buf),
+        TransferMode::Block => BlockReader::new(stream).read_to_end(&mut buf),
+    }
+    .expect("Could not read second stream");
+    String::from_utf8(buf).expect("Could not read text from streamm")
+}
+
+fn copy_to_stream<R: Read, W: Write>(file: &mut R, stream: &mut W) {
     let mut buf = vec![0; 4096];
     let mut done = false;
     while !done {
@@ -803,11 +1119,15 @@ fn write_to_stream(file: &mut File, stream: &mut TcpStream) {
     }
 }
 
-fn write_to_file(file: &mut File, stream: &mut TcpStream) {
+fn copy_from_stream<W: Write, R: Read>(file: &mut W, stream: &mut R) {
     let mut buf = vec![0; 4096];
     let mut done = false;
     while !done {
-        let n = stream.read(&mut buf).expect("Could not read remote file");
+        //Some servers close a TLS data connection without a close_notify
+        let n = match stream.read(&mut buf) {
+            Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => 0,
+            n => n.expect("Could not read remote file"),
+        };
         if n > 0 {
             file.write_all(&buf[..n]).expect("Could not write to local locatio");
         } else {
@@ -816,7 +1136,7 @@ fn write_to_file(file: &mut File, stream: &mut TcpStream) {
     }
 }
 
-fn set_type(mut stream: &mut BufReader<TcpStream>, ftp_type: FtpType, debug: bool) {
+fn set_type(mut stream: &mut BufReader<FtpStream>, ftp_type: FtpType, debug: bool) {
     match ftp_type {
         FtpType::Binary => {
             let mut cmd = "Type I\r\n".to_string();
@@ -833,73 +1153,352 @@ fn set_type(mut stream: &mut BufReader<TcpStream>, ftp_type: FtpType, debug: boo
     }
 }
 
-fn get_pasv_address(response: &str) -> SocketAddrV4 {
-    let start_pos = response.rfind('(').expect("Could not read response from server") + 1;
-    let end_pos = response.rfind(')').expect("could not read response form server");
+/// Switches the server to MODE Z, MODE B or back to MODE S, returns whether
+/// it agreed. A data connection MODE B left open is closed first
+pub fn set_mode(mut stream: &mut BufReader<FtpStream>,
+                transfer_mode: TransferMode,
+                debug: bool,
+                verbose: bool)
+                -> bool {
+    close_kept_data();
+    let cmd = match transfer_mode {
+        TransferMode::Stream => "MODE S\r\n",
+        TransferMode::Deflate => "MODE Z\r\n",
+        TransferMode::Block => "MODE B\r\n",
+    };
+    write_command(&mut stream, cmd, debug);
+    let response = read_message(&mut stream, verbose);
+
+    match get_code_from_respone(&response) {
+        Ok(200) => true,
+        _ => false,
+    }
+}
+
+fn get_pasv_address(response: &str) -> Option<SocketAddrV4> {
+    let start_pos = match response.rfind('(') {
+        Some(pos) => pos + 1,
+        None => return None,
+    };
+    let end_pos = match response.rfind(')') {
+        Some(pos) if pos >= start_pos => pos,
+        _ => return None,
+    };
     let substr = response[start_pos..end_pos].to_string();
-    let nums: Vec<u8> = substr.split(',').map(|x| x.parse::<u8>().unwrap()).collect();
+    let nums: Vec<u8> = substr.split(',').filter_map(|x| x.trim().parse::<u8>().ok()).collect();
+    if nums.len() != 6 {
+        return None;
+    }
     let ip = Ipv4Addr::new(nums[0], nums[1], nums[2], nums[3]);
     let port = to_ftp_port(nums[4] as u16, nums[5] as u16);
     let addr = SocketAddrV4::new(ip, port);
-    addr
+    Some(addr)
+
+}
 
+//Reads the port out of an EPSV reply such as 229 Entering Extended Passive Mode (|||6446|)
+fn get_epsv_port(response: &str) -> Option<u16> {
+    let start_pos = match response.rfind('(') {
+        Some(pos) => pos + 1,
+        None => return None,
+    };
+    let end_pos = match response.rfind(')') {
+        Some(pos) if pos >= start_pos => pos,
+        _ => return None,
+    };
+    let substr = &response[start_pos..end_pos];
+    let delim = match substr.chars().next() {
+        Some(delim) => delim,
+        None => return None,
+    };
+    let fields: Vec<&str> = substr.split(delim).collect();
+    if fields.len() != 5 {
+        return None;
+    }
+    fields[3].parse::<u16>().ok()
 }
 
-fn stor_file(addr: &SocketAddrV4,
+//Arguments of PORT, the address and port as six comma separated bytes
+fn port_args(addr: &SocketAddrV4) -> String {
+    let ip = format!("{}", addr.ip()).replace(".", ",");
+    format!("{},{},{}", ip, addr.port() / 256, addr.port() % 256)
+}
+
+//Arguments of EPRT, |1| for IPv4 and |2| for IPv6 followed by address and port
+fn eprt_args(addr: &SocketAddr) -> String {
+    let proto = match *addr {
+        SocketAddr::V4(_) => 1,
+        SocketAddr::V6(_) => 2,
+    };
+    format!("|{}|{}|{}|", proto, addr.ip(), addr.port())
+}
+
+/// Sets up the data connection of the next transfer. Passive modes ask the
+/// server for a port with PASV or EPSV, active modes open a local port and
+/// announce it with PORT or EPRT. Over IPv6 only the extended commands work.
+/// A connection MODE B left open is used as it is
+pub fn data_port(mut stream: &mut BufReader<FtpStream>,
+                 ftp_mode: FtpMode,
+                 debug: bool,
+                 verbose: bool)
+                 -> Option<DataPort> {
+    if kept_data_alive() {
+        info!("Reusing the open data connection");
+        return Some(DataPort::Open);
+    }
+
+    let server = stream.get_ref().peer_addr().expect("Could not read server address");
+
+    match ftp_mode {
+        FtpMode::Passive | FtpMode::ExtendedPassive => {
+            let extended = match ftp_mode {
+                FtpMode::ExtendedPassive => true,
+                _ => server.is_ipv6(),
+            };
+
+            if extended {
+                write_command(&mut stream, "EPSV\r\n", debug);
+                let response = read_message(&mut stream, verbose);
+                match (get_code_from_respone(&response), get_epsv_port(&response)) {
+                    (Ok(229), Some(port)) => {
+                        return Some(DataPort::Connect(SocketAddr::new(server.ip(), port)))
+                    }
+                    _ if server.is_ipv6() => {
+                        println!("Server refused EPSV");
+                        return None;
+                    }
+                    _ => info!("EPSV refused, falling back to PASV"),
+                }
+            }
+
+            write_command(&mut stream, "PASV\r\n", debug);
+            let response = read_message(&mut stream, verbose);
+            match (get_code_from_respone(&response), get_pasv_address(&response)) {
+                (Ok(227), Some(addr)) => Some(DataPort::Connect(SocketAddr::V4(addr))),
+                _ => {
+                    println!("Server refused PASV");
+                    None
+                }
+            }
+        }
+        FtpMode::Active(addr) | FtpMode::ExtendedActive(addr) => {
+            let listener = TcpListener::bind(addr).expect("Could not open data port");
+            let local = listener.local_addr().expect("Could not read data port");
+
+            let cmd = match (ftp_mode, local) {
+                (FtpMode::Active(_), SocketAddr::V4(ref v4)) => format!("PORT {}\r\n", port_args(v4)),
+                _ => format!("EPRT {}\r\n", eprt_args(&local)),
+            };
+            write_command(&mut stream, &cmd, debug);
+            let response = read_message(&mut stream, verbose);
+            match get_code_from_respone(&response) {
+                Ok(200) => Some(DataPort::Listen(listener)),
+                _ => {
+                    println!("Server refused {}", cmd.trim());
+                    None
+                }
+            }
+        }
+    }
+}
+
+//Waits for the server to connect back to our data port
+fn accept_data(listener: &TcpListener) -> TcpStream {
+    listener.set_nonblocking(true).expect("Could not poll data port");
+
+    for _ in 0..ACCEPT_TIMEOUT * 10 {
+        match listener.accept() {
+            Ok((sock, _)) => {
+                sock.set_nonblocking(false).expect("Could not set data stream blocking");
+                return sock;
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                thread::sleep(Duration::from_millis(100));
+            }
+            Err(e) => panic!("Could not accept data connection: {}", e),
+        }
+    }
+
+    panic!("Server did not connect to the data port");
+}
+
+//Opens the data connection for a transfer command that was just sent,
+//wrapped in TLS when the control connection is. The server may refuse the
+//transfer (550 and the like) instead of answering 150, then there is None
+fn open_data(stream: &mut BufReader<FtpStream>,
+             port: &DataPort,
+             verbose: bool)
+             -> Option<FtpStream> {
+    //In passive mode the server waits for us before it replies, in active
+    //mode it only connects once it has accepted the command
+    let sock = match *port {
+        DataPort::Connect(addr) => {
+            Some(TcpStream::connect(addr).expect("could not read connect address"))
+        }
+        DataPort::Listen(_) | DataPort::Open => None,
+    };
+
+    let response = read_message(stream, verbose);
+    if !response.starts_with('1') {
+        if !verbose {
+            println!("{}", response.trim());
+        }
+        if let Some(sock) = sock {
+            let _ = sock.shutdown(Shutdown::Both);
+        }
+        close_kept_data();
+        return None;
+    }
+
+    let sock = match (sock, port) {
+        (Some(sock), _) => sock,
+        (None, &DataPort::Listen(ref listener)) => accept_data(listener),
+        (None, &DataPort::Open) => return KEPT_DATA.lock().unwrap().take(),
+        (None, &DataPort::Connect(_)) => unreachable!(),
+    };
+    Some(stream.get_ref().data_stream(sock).expect("could not set up data connection"))
+}
+
+//Whether a data connection MODE B left open can take the next transfer. The
+//server closes it when a transfer fails, anything to read on it while idle
+//means it is gone
+fn kept_data_alive() -> bool {
+    let mut kept = KEPT_DATA.lock().unwrap();
+    let alive = match *kept {
+        Some(ref mut data) => {
+            data.tcp().set_nonblocking(true).expect("Could not poll data stream");
+            let alive = match data.read(&mut [0; 1]) {
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => true,
+                _ => false,
+            };
+            data.tcp().set_nonblocking(false).expect("Could not set data stream blocking");
+            alive
+        }
+        None => return false,
+    };
+
+    if !alive {
+        info!("The open data connection was closed");
+        kept.take();
+    }
+    alive
+}
+
+//Closes the data connection MODE B left open, if there is one
+fn close_kept_data() {
+    if let Some(mut data) = KEPT_DATA.lock().unwrap().take() {
+        let _ = data.shutdown();
+    }
+}
+
+//Done with the data connection of a download. MODE B keeps it open for the
+//next transfer once the whole file came in
+fn close_data(mut data: FtpStream, transfer_mode: TransferMode, done: bool) {
+    if transfer_mode == TransferMode::Block && done {
+        *KEPT_DATA.lock().unwrap() = Some(data);
+        return;
+    }
+    data.shutdown().expect("Failed to close data stream");
+}
+
+//The data transfer functions return false when the server refused the
+//transfer, no completion reply follows then
+fn stor_file(port: &DataPort,
              lpath: &str,
-             mut stream: &mut BufReader<TcpStream>,
-             verbose: bool) {
+             mut stream: &mut BufReader<FtpStream>,
+             ftp_type: FtpType,
+             transfer_mode: TransferMode,
+             verbose: bool,
+             offset: u64)
+             -> bool {
 
     //TODO Spawn a therad here
-    let mut stream2 = TcpStream::connect(addr).expect("could not read connect address");
-    let response = read_message(&mut stream, verbose);
+    let mut stream2 = match open_data(&mut stream, port, verbose) {
+        Some(stream2) => stream2,
+        None => return false,
+    };
 
     let mut file = match File::open(lpath) {
         Ok(file) => file,
         Err(_) => {
             println!("Error opening file on local");
-            stream2.shutdown(Shutdown::Both).expect("Failed to close data stream");
-            return;
+            stream2.shutdown().expect("Failed to close data stream");
+            return true;
         }
     };
-    write_to_stream(&mut file, &mut stream2);
-    stream2.shutdown(Shutdown::Both).expect("Failed to close data stream");
+    let at = local_offset(&mut file, ftp_type, offset);
+    file.seek(SeekFrom::Start(at)).expect("Could not seek to restart offset");
+    write_to_stream(&mut file, &mut stream2, ftp_type, transfer_mode, offset);
+    match transfer_mode {
+        TransferMode::Block => close_data(stream2, transfer_mode, true),
+        _ => stream2.finish_upload().expect("Failed to close data stream"),
+    }
+    true
 }
 
 
-fn get_file(addr: &SocketAddrV4,
+fn get_file(port: &DataPort,
             rpath: &str,
-            mut stream: &mut BufReader<TcpStream>,
-            verbose: bool) {
+            mut stream: &mut BufReader<FtpStream>,
+            ftp_type: FtpType,
+            transfer_mode: TransferMode,
+            verbose: bool,
+            offset: u64)
+            -> bool {
 
     //TODO Spawn a therad here
-    let mut stream2 = TcpStream::connect(addr).expect("could not read connect address");
-    let response = read_message(&mut stream, verbose);
+    let mut stream2 = match open_data(&mut stream, port, verbose) {
+        Some(stream2) => stream2,
+        None => return false,
+    };
 
-    let mut file = match File::create(rpath) {
+    //A resumed download keeps the bytes we already have
+    let mut file = match OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(offset == 0)
+        .open(rpath) {
         Ok(file) => file,
         Err(_) => {
             println!("Error opening file on local");
-            stream2.shutdown(Shutdown::Both).expect("Failed to close data stream");
-            return;
+            stream2.shutdown().expect("Failed to close data stream");
+            return true;
         }
     };
-    write_to_file(&mut file, &mut stream2);
-    stream2.shutdown(Shutdown::Both).expect("Failed to close data stream");
+    let at = local_offset(&mut file, ftp_type, offset);
+    file.seek(SeekFrom::Start(at)).expect("Could not seek to restart offset");
+    let done = write_to_file(&mut file, &mut stream2, ftp_type, transfer_mode);
+    close_data(stream2, transfer_mode, done);
+    true
 }
 
-fn list_file(addr: &SocketAddrV4,
+//Where a restart offset lands in the local file, under TYPE A the server
+//counts it in CRLF line ends
+fn local_offset(file: &mut File, ftp_type: FtpType, offset: u64) -> u64 {
+    match ftp_type {
+        FtpType::ASCII if offset > 0 => {
+            ascii::local_offset(file, offset).expect("Could not read local file")
+        }
+        _ => offset,
+    }
+}
+
+fn list_file(port: &DataPort,
              rpath: &str,
-             mut stream: &mut BufReader<TcpStream>,
-             verbose: bool) {
+             mut stream: &mut BufReader<FtpStream>,
+             transfer_mode: TransferMode,
+             verbose: bool)
+             -> bool {
 
     //TODO Spawn a therad here
-    let mut stream2 = TcpStream::connect(addr).expect("could not read connect address");
-    let response = read_message(&mut stream, verbose);
+    let mut stream2 = match open_data(&mut stream, port, verbose) {
+        Some(stream2) => stream2,
+        None => return false,
+    };
 
-    let mut buf: Vec<u8> = Vec::new();
-    stream2.read_to_end(&mut buf).expect("Could not read second stream");
-    let text = (String::from_utf8(buf)).expect("Could not read text from streamm");
-    stream2.shutdown(Shutdown::Both).expect("Failed to close data stream");
+    let text = read_listing(&mut stream2, transfer_mode);
+    close_data(stream2, transfer_mode, true);
     println!("{}", text);
+    true
 }
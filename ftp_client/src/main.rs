@@ -3,6 +3,11 @@ extern crate argparse; //argument parsing such as -h -d etc..
 extern crate rpassword; //hidden passwords
 extern crate ini;
 extern crate rand;
+extern crate rustls;
+extern crate rustls_native_certs;
+extern crate rustls_pemfile;
+extern crate sha2;
+extern crate flate2;
 
 //Reading from config files
 use ini::Ini;
@@ -18,7 +23,7 @@ use std::io::prelude::*; //the standard io functions that come with rust
 use std::process;
 use std::path::Path;
 use std::io::BufReader; //the standard io functions that come with rust
-use std::net::{SocketAddrV4, Ipv4Addr, TcpStream};
+use std::net::{SocketAddr, TcpStream};
 use std::io;
 
 use std::fs::OpenOptions;
@@ -30,12 +35,21 @@ use argparse::{ArgumentParser, Print, Store, StoreOption, StoreTrue, StoreFalse}
 use slog::DrainExt;
 
 //helper files for client functions
+//TYPE A and MODE B are coded the same way on both ends, so the files are
+//the server's
+#[path = "../../ftp_server/src/ascii.rs"]
+mod ascii;
+#[path = "../../ftp_server/src/block.rs"]
+mod block;
 mod client;
+mod stream;
 mod utils;
 
 
 use client::FtpMode;
 use client::FtpType;
+use client::TransferMode;
+use stream::{FtpStream, TlsOptions};
 
 
 //This section here defines the arguements that the ftp_client will
@@ -57,6 +71,10 @@ struct Arguements {
     l_all: String,
     l_only: String,
     log_file: String,
+    tls: bool,
+    implicit_tls: bool,
+    ca_file: Option<String>,
+    fingerprint: Option<String>,
 }
 
 //These are the defaults incase no arguements are provided
@@ -78,6 +96,10 @@ impl Arguements {
             l_all: "".to_string(),
             l_only: "logs/ftpclient.log".to_string(),
             log_file: "logs/ftpclient.log".to_string(),
+            tls: false,
+            implicit_tls: false,
+            ca_file: None,
+            fingerprint: None,
         }
     }
 }
@@ -156,6 +178,24 @@ fn main() {
         ap.refer(&mut arguements.l_only)
             .add_option(&["--LONLY"], Store, "Location to store all log output");
 
+        ap.refer(&mut arguements.tls)
+            .add_option(&["--tls"], StoreTrue, "Use AUTH TLS before logging in");
+
+        ap.refer(&mut arguements.implicit_tls)
+            .add_option(&["--implicit-tls"],
+                        StoreTrue,
+                        "Start TLS as soon as the connection opens");
+
+        ap.refer(&mut arguements.ca_file)
+            .add_option(&["--ca-file"],
+                        StoreOption,
+                        "PEM file of CAs to trust instead of the system store");
+
+        ap.refer(&mut arguements.fingerprint)
+            .add_option(&["--fingerprint"],
+                        StoreOption,
+                        "SHA-256 fingerprint of a server certificate to trust");
+
         ap.parse_args_or_exit();
     }
     arguements.passive = passive;
@@ -166,7 +206,7 @@ fn main() {
     start_ftp_client(&mut arguements);
 }
 
-fn start_ftp_client(mut arguements: &mut Arguements) -> BufReader<TcpStream> {
+fn start_ftp_client(mut arguements: &mut Arguements) -> BufReader<FtpStream> {
 
     let temp_path = format!("{}", arguements.log_file);
     let log_path = Path::new(&temp_path);
@@ -181,8 +221,6 @@ fn start_ftp_client(mut arguements: &mut Arguements) -> BufReader<TcpStream> {
     slog_stdlog::set_logger(logger).unwrap();
 
     info!("Global File Logger for FTP CLIENT");
-    //this will serve as a holder
-    let mut myclient: TcpStream;
 
     /*
      * Here is the loop for starting the program
@@ -194,20 +232,18 @@ fn start_ftp_client(mut arguements: &mut Arguements) -> BufReader<TcpStream> {
     loop {
 
 
-        //TODO: put the connection into a function to reduce repetition of code
         if !arguements.hostname.is_empty() {
-            let server = format!("{}:{}", arguements.hostname, arguements.ftp_port);
-            match TcpStream::connect(server.as_str()) {
-                Ok(stream) => {
+            let host = arguements.hostname.clone();
+            let port = arguements.ftp_port.clone();
+            match connect(&host, &port, &arguements) {
+                Ok(mut stream) => {
                     info!("Success Connecting to server {}",
-                          stream.peer_addr().unwrap().ip());
+                          stream.get_ref().peer_addr().unwrap().ip());
                     arguements.hostname = "".to_string();
                     arguements.ftp_port = "".to_string();
-                    myclient = stream;
-                    let mut stream = BufReader::new(myclient);
                     println!("Success Connecting to server");
                     let response = client::read_message(&mut stream, arguements.verbose);
-                    cmd_loop(&mut stream, &mut arguements);
+                    cmd_loop(&mut stream, &mut arguements, &host);
                 }
                 Err(_) => {
                     arguements.hostname = "".to_string();
@@ -227,16 +263,13 @@ fn start_ftp_client(mut arguements: &mut Arguements) -> BufReader<TcpStream> {
                         None => (args.as_ref(), "21".as_ref()),
                     };
 
-                    let server = format!("{}:{}", host, port);
-                    match TcpStream::connect(server.as_str()) {
-                        Ok(stream) => {
+                    match connect(host, port, &arguements) {
+                        Ok(mut stream) => {
                             arguements.hostname = "".to_string();
                             arguements.ftp_port = "".to_string();
-                            myclient = stream;
-                            let mut stream = BufReader::new(myclient);
                             println!("Success Connecting to server");
                             let response = client::read_message(&mut stream, arguements.verbose);
-                            cmd_loop(&mut stream, &mut arguements);
+                            cmd_loop(&mut stream, &mut arguements, host);
                         }
                         Err(_) => {
                             println!("Could not connect to host");
@@ -268,8 +301,45 @@ fn start_ftp_client(mut arguements: &mut Arguements) -> BufReader<TcpStream> {
 
 }
 
+//Opens the control connection, with TLS from the start for implicit FTPS
+fn connect(host: &str, port: &str, arguements: &Arguements) -> io::Result<BufReader<FtpStream>> {
+    //IPv6 literals need brackets around them once a port is added
+    let server = match host.contains(':') && !host.starts_with('[') {
+        true => format!("[{}]:{}", host, port),
+        false => format!("{}:{}", host, port),
+    };
+    let mut stream = FtpStream::Plain(try!(TcpStream::connect(server.as_str())));
 
-fn login(mut client: &mut BufReader<TcpStream>, arguements: &Arguements) -> bool {
+    if arguements.implicit_tls {
+        let context = try!(stream::TlsContext::new(host, &tls_options(arguements)));
+        try!(stream.upgrade(&context));
+        info!("TLS connection established");
+    }
+
+    Ok(BufReader::new(stream))
+}
+
+fn tls_options(arguements: &Arguements) -> TlsOptions {
+    TlsOptions {
+        ca_file: arguements.ca_file.clone(),
+        fingerprint: arguements.fingerprint.clone(),
+    }
+}
+
+//Runs AUTH TLS, returns false when the connection has to be dropped
+fn auth_tls(mut client: &mut BufReader<FtpStream>, arguements: &Arguements, host: &str) -> bool {
+    let (debug, verbose) = (arguements.debug, arguements.verbose);
+    match client::auth_tls(&mut client, host, &tls_options(arguements), debug, verbose) {
+        Ok(_) => true,
+        Err(e) => {
+            println!("TLS negotiation failed: {}", e);
+            info!("TLS negotiation failed: {}", e);
+            false
+        }
+    }
+}
+
+fn login(mut client: &mut BufReader<FtpStream>, arguements: &Arguements) -> bool {
     let mut logged_in: bool = false;
     let os_user = std::env::var("USER").unwrap_or(String::new());
 
@@ -346,10 +416,14 @@ fn login(mut client: &mut BufReader<TcpStream>, arguements: &Arguements) -> bool
 
 
 
-fn cmd_loop(mut client: &mut BufReader<TcpStream>, mut arguements: &mut Arguements) {
+fn cmd_loop(mut client: &mut BufReader<FtpStream>, mut arguements: &mut Arguements, host: &str) {
 
-    let mut actv_socket_addr = SocketAddrV4::new(Ipv4Addr::new(127, 0, 0, 1), 27598);
+    //In active mode the server connects back to the address we reached it from
+    let local_ip = client.get_ref().local_addr().expect("Could not read local address").ip();
+    let actv_socket_addr = SocketAddr::new(local_ip, 0);
+    let mut epsv4 = false;
     let mut ftp_type = FtpType::Binary;
+    let mut transfer_mode = TransferMode::Stream;
 
     let mut ftp_mode = match arguements.passive {
         true => {
@@ -361,6 +435,14 @@ fn cmd_loop(mut client: &mut BufReader<TcpStream>, mut arguements: &mut Arguemen
             FtpMode::Active(actv_socket_addr)
         }
     };
+    //Never send the password in the clear when TLS was asked for
+    if arguements.tls && !arguements.implicit_tls {
+        if !auth_tls(&mut client, &arguements, host) || !client.get_ref().is_tls() {
+            println!("Closing connection");
+            return;
+        }
+    }
+
     let mut logged_in = login(&mut client, &arguements);
     let auth_mesg = "You need to be logged in";
     let mut runique = false;
@@ -371,7 +453,34 @@ fn cmd_loop(mut client: &mut BufReader<TcpStream>, mut arguements: &mut Arguemen
         let (debug, verbose) = (arguements.debug, arguements.verbose);
         if logged_in {
             match cmd.to_lowercase().as_ref() {
-                "appe" | "append" => client::appe(&mut client, &args, ftp_mode, debug, verbose),
+                "appe" | "append" => {
+                    client::appe(&mut client, &args, ftp_mode, ftp_type, transfer_mode, debug, verbose)
+                }
+                "auth" => {
+                    if !auth_tls(&mut client, &arguements, host) {
+                        break;
+                    }
+                }
+                "epsv4" => {
+                    epsv4 = !epsv4;
+                    ftp_mode = extended_mode(ftp_mode, epsv4);
+                    println!("EPSV/EPRT on IPv4= {}", epsv4);
+                    info!("EPSV/EPRT on IPv4= {}", epsv4);
+                }
+                "passive" => {
+                    ftp_mode = match ftp_mode {
+                        FtpMode::Passive | FtpMode::ExtendedPassive => {
+                            println!("Passive mode off");
+                            FtpMode::Active(actv_socket_addr)
+                        }
+                        _ => {
+                            println!("Passive mode on");
+                            FtpMode::Passive
+                        }
+                    };
+                    ftp_mode = extended_mode(ftp_mode, epsv4);
+                    info!("Mode set to {:?}", ftp_mode);
+                }
                 "ascii" => {
                     ftp_type = FtpType::ASCII;
                     println!("Type set to A- Ascii");
@@ -382,6 +491,28 @@ fn cmd_loop(mut client: &mut BufReader<TcpStream>, mut arguements: &mut Arguemen
                     println!("Type set Binary");
                     info!("Type set Binary");
                 }
+                "compress" => {
+                    let mode = match transfer_mode {
+                        TransferMode::Deflate => TransferMode::Stream,
+                        _ => TransferMode::Deflate,
+                    };
+                    if client::set_mode(&mut client, mode, debug, verbose) {
+                        transfer_mode = mode;
+                    }
+                    println!("Compression= {}", transfer_mode == TransferMode::Deflate);
+                    info!("Compression= {}", transfer_mode == TransferMode::Deflate);
+                }
+                "block" => {
+                    let mode = match transfer_mode {
+                        TransferMode::Block => TransferMode::Stream,
+                        _ => TransferMode::Block,
+                    };
+                    if client::set_mode(&mut client, mode, debug, verbose) {
+                        transfer_mode = mode;
+                    }
+                    println!("Block mode= {}", transfer_mode == TransferMode::Block);
+                    info!("Block mode= {}", transfer_mode == TransferMode::Block);
+                }
                 "close" | "disconnect" => {
                     println!("Closing connection");
                     info!("Closing connection");
@@ -393,27 +524,29 @@ fn cmd_loop(mut client: &mut BufReader<TcpStream>, mut arguements: &mut Arguemen
                 "get" | "retr| recv" => {
                     match runique {
                         true => {
-                            client::get_u(&mut client, &args, ftp_mode, ftp_type, debug, verbose)
+                            client::get_u(&mut client, &args, ftp_mode, ftp_type, transfer_mode, debug, verbose)
                         }
                         false => {
-                            client::get(&mut client, &args, ftp_mode, ftp_type, debug, verbose)
+                            client::get(&mut client, &args, ftp_mode, ftp_type, transfer_mode, debug, verbose)
                         }
                     }
                 }
-                "ls" | "list" | "dir" => client::list(&mut client, &args, ftp_mode, debug, verbose),
+                "ls" | "list" | "dir" => {
+                    client::list(&mut client, &args, ftp_mode, transfer_mode, debug, verbose)
+                },
                 "lls" | "llist" | "ldir" => client::list_local(&args),
                 "lpwd" => client::print_locoal_dir(),
                 "lcd" | "lcwd" => client::change_local_dir(&args),
                 "mkdir" | "mkd" => client::make_dir(&mut client, &args, debug, verbose),
                 "mdele" | "mdel" => client::mdele(&mut client, &args, debug, verbose),
                 "mlist" | "mls" | "mdir" => {
-                    client::mlist(&mut client, &args, ftp_mode, debug, verbose)
+                    client::mlist(&mut client, &args, ftp_mode, transfer_mode, debug, verbose)
                 }
                 "mget" | "mretr| mrecv" => {
-                    client::mget(&mut client, &args, ftp_mode, ftp_type, debug, verbose)
+                    client::mget(&mut client, &args, ftp_mode, ftp_type, transfer_mode, debug, verbose)
                 }
                 "mput" | "mstor" => {
-                    client::mput(&mut client, &args, ftp_mode, ftp_type, debug, verbose)
+                    client::mput(&mut client, &args, ftp_mode, ftp_type, transfer_mode, debug, verbose)
                 }
                 "pwd" => client::print_working_dir(&mut client, debug, verbose),
                 "put" | "stor" => {
@@ -421,12 +554,20 @@ fn cmd_loop(mut client: &mut BufReader<TcpStream>, mut arguements: &mut Arguemen
                                 &args,
                                 ftp_mode,
                                 ftp_type,
+                                transfer_mode,
                                 debug,
                                 verbose,
                                 sunique)
                 }
                 "rm" | "rmd" | "rmdir" => client::remove_dir(&mut client, &args, debug, verbose),
+                "reget" => {
+                    client::reget(&mut client, &args, ftp_mode, ftp_type, transfer_mode, debug, verbose)
+                }
+                "restart" => {
+                    client::restart(&mut client, &args, ftp_mode, ftp_type, transfer_mode, debug, verbose)
+                }
                 "rstatus" => client::rstatus(&mut client, &args, debug, verbose),
+                "site" => client::site(&mut client, &args, debug, verbose),
                 "reset" => continue,
                 "rename" | "rename" => client::rename(&mut client, &args, debug, verbose),
                 "rhelp" => client::r_help(&mut client, debug, verbose),
@@ -446,6 +587,7 @@ fn cmd_loop(mut client: &mut BufReader<TcpStream>, mut arguements: &mut Arguemen
                                    verbose,
                                    ftp_type,
                                    ftp_mode,
+                                   transfer_mode,
                                    runique,
                                    sunique)
                 }
@@ -490,6 +632,11 @@ fn cmd_loop(mut client: &mut BufReader<TcpStream>, mut arguements: &mut Arguemen
                 }
                 "help" | "?" | "usage" => utils::print_help(&args),
                 "user" => logged_in = login(&mut client, &arguements),
+                "auth" => {
+                    if !auth_tls(&mut client, &arguements, host) {
+                        break;
+                    }
+                }
                 "open" | "ftp" => {
                     println!("Already connected, use close to end connection");
                 }
@@ -516,6 +663,17 @@ fn cmd_loop(mut client: &mut BufReader<TcpStream>, mut arguements: &mut Arguemen
 
 }
 
+//Picks the EPSV/EPRT flavour of a mode when epsv4 is on
+fn extended_mode(ftp_mode: FtpMode, extended: bool) -> FtpMode {
+    match (ftp_mode, extended) {
+        (FtpMode::Passive, true) => FtpMode::ExtendedPassive,
+        (FtpMode::ExtendedPassive, false) => FtpMode::Passive,
+        (FtpMode::Active(addr), true) => FtpMode::ExtendedActive(addr),
+        (FtpMode::ExtendedActive(addr), false) => FtpMode::Active(addr),
+        (mode, _) => mode,
+    }
+}
+
 fn get_commands() -> (String, String) {
 
     print!("ftp> ");
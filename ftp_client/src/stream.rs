@@ -0,0 +1,332 @@
+//! Control and data connections that may be wrapped in TLS (RFC 4217)
+
+use std::convert::TryFrom;
+use std::fs::File;
+use std::io::prelude::*;
+use std::io::{self, BufReader, ErrorKind, IsTerminal};
+use std::net::{Shutdown, SocketAddr, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::client::WebPkiServerVerifier;
+use rustls::crypto::{self, CryptoProvider};
+use rustls::pki_types::{CertificateDer, ServerName, UnixTime};
+use rustls::{ClientConfig, ClientConnection, DigitallySignedStruct, RootCertStore,
+             SignatureScheme, StreamOwned};
+use sha2::{Digest, Sha256};
+
+/// How the server certificate gets trusted
+#[derive(Debug, Clone)]
+pub struct TlsOptions {
+    /// PEM file with the CAs to trust instead of the system store
+    pub ca_file: Option<String>,
+    /// SHA-256 fingerprint of a server certificate to accept as is
+    pub fingerprint: Option<String>,
+}
+
+/// What a TLS connection needs, kept so data connections can reuse it
+pub struct TlsContext {
+    config: Arc<ClientConfig>,
+    server_name: ServerName<'static>,
+}
+
+impl TlsContext {
+    pub fn new(host: &str, options: &TlsOptions) -> io::Result<Arc<TlsContext>> {
+        let server_name = try!(ServerName::try_from(host.to_string())
+            .map_err(|e| io::Error::new(ErrorKind::InvalidInput, e)));
+        let verifier = try!(FingerprintVerifier::new(options));
+
+        let config = ClientConfig::builder()
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(verifier))
+            .with_no_client_auth();
+
+        Ok(Arc::new(TlsContext {
+            config: Arc::new(config),
+            server_name: server_name,
+        }))
+    }
+}
+
+/// A connection to the server, plain until AUTH TLS upgrades it
+pub enum FtpStream {
+    Plain(TcpStream),
+    Tls(Box<StreamOwned<ClientConnection, TcpStream>>, Arc<TlsContext>),
+}
+
+impl FtpStream {
+    /// The socket underneath, whether or not it is encrypted
+    pub fn tcp(&self) -> &TcpStream {
+        match *self {
+            FtpStream::Plain(ref stream) => stream,
+            FtpStream::Tls(ref stream, _) => &stream.sock,
+        }
+    }
+
+    pub fn is_tls(&self) -> bool {
+        match *self {
+            FtpStream::Plain(_) => false,
+            FtpStream::Tls(..) => true,
+        }
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.tcp().local_addr()
+    }
+
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.tcp().peer_addr()
+    }
+
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.tcp().set_read_timeout(timeout)
+    }
+
+    /// Runs the client side of a TLS handshake over this connection
+    pub fn upgrade(&mut self, context: &Arc<TlsContext>) -> io::Result<()> {
+        let sock = match *self {
+            FtpStream::Plain(ref stream) => try!(stream.try_clone()),
+            FtpStream::Tls(..) => return Err(io::Error::new(ErrorKind::Other, "already TLS")),
+        };
+
+        let mut tls = try!(connect_tls(sock, context));
+        while tls.conn.is_handshaking() {
+            try!(tls.conn.complete_io(&mut tls.sock));
+        }
+
+        *self = FtpStream::Tls(Box::new(tls), context.clone());
+        Ok(())
+    }
+
+    /// Wraps a new data connection the same way as this control connection
+    pub fn data_stream(&self, sock: TcpStream) -> io::Result<FtpStream> {
+        match *self {
+            FtpStream::Plain(_) => Ok(FtpStream::Plain(sock)),
+            FtpStream::Tls(_, ref context) => {
+                let tls = try!(connect_tls(sock, context));
+                Ok(FtpStream::Tls(Box::new(tls), context.clone()))
+            }
+        }
+    }
+
+    /// Closes the connection, sending a TLS close_notify first when encrypted
+    pub fn shutdown(&mut self) -> io::Result<()> {
+        if let FtpStream::Tls(ref mut stream, _) = *self {
+            stream.conn.send_close_notify();
+            let _ = stream.flush();
+        }
+        match self.tcp().shutdown(Shutdown::Both) {
+            //The server may well have closed its end first
+            Err(ref e) if e.kind() == ErrorKind::NotConnected => Ok(()),
+            result => result,
+        }
+    }
+
+    /// Ends an upload. Only our side is closed and we wait for the server to
+    /// close its end, dropping the socket with data still coming in (like TLS
+    /// session tickets) would reset it before the server has read everything
+    pub fn finish_upload(&mut self) -> io::Result<()> {
+        if let FtpStream::Tls(ref mut stream, _) = *self {
+            stream.conn.send_close_notify();
+            let _ = stream.flush();
+        }
+        match self.tcp().shutdown(Shutdown::Write) {
+            Err(ref e) if e.kind() == ErrorKind::NotConnected => return Ok(()),
+            Err(e) => return Err(e),
+            Ok(_) => {}
+        }
+        let _ = io::copy(&mut self.tcp(), &mut io::sink());
+        Ok(())
+    }
+}
+
+impl Read for FtpStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match *self {
+            FtpStream::Plain(ref mut stream) => stream.read(buf),
+            FtpStream::Tls(ref mut stream, _) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for FtpStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match *self {
+            FtpStream::Plain(ref mut stream) => stream.write(buf),
+            FtpStream::Tls(ref mut stream, _) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match *self {
+            FtpStream::Plain(ref mut stream) => stream.flush(),
+            FtpStream::Tls(ref mut stream, _) => stream.flush(),
+        }
+    }
+}
+
+fn connect_tls(sock: TcpStream,
+               context: &Arc<TlsContext>)
+               -> io::Result<StreamOwned<ClientConnection, TcpStream>> {
+    let conn = try!(ClientConnection::new(context.config.clone(), context.server_name.clone())
+        .map_err(|e| io::Error::new(ErrorKind::Other, e)));
+    Ok(StreamOwned::new(conn, sock))
+}
+
+/// SHA-256 fingerprint of a certificate as colon separated hex
+pub fn fingerprint(cert: &[u8]) -> String {
+    let digest = Sha256::digest(cert);
+    let hex: Vec<String> = digest.iter().map(|b| format!("{:02X}", b)).collect();
+    hex.join(":")
+}
+
+//Compares fingerprints ignoring case and separators
+fn same_fingerprint(a: &str, b: &str) -> bool {
+    let clean = |s: &str| -> String {
+        s.chars().filter(|c| c.is_digit(16)).collect::<String>().to_uppercase()
+    };
+    clean(a) == clean(b)
+}
+
+/// Trusts only a server whose certificate matches the pinned fingerprint when
+/// there is one. Without a pin it checks the CA store, and when that fails
+/// the user is shown the fingerprint and asked whether to accept it for the
+/// rest of the session. Nobody is asked when stdin is not a terminal
+#[derive(Debug)]
+struct FingerprintVerifier {
+    //None when there are no CAs to check against
+    webpki: Option<Arc<WebPkiServerVerifier>>,
+    //From --fingerprint, nothing else is trusted then
+    pinned: Option<String>,
+    //What the user accepted at the prompt
+    accepted: Mutex<Option<String>>,
+    provider: Arc<CryptoProvider>,
+}
+
+impl FingerprintVerifier {
+    fn new(options: &TlsOptions) -> io::Result<FingerprintVerifier> {
+        let mut roots = RootCertStore::empty();
+
+        match options.ca_file {
+            Some(ref path) => {
+                let mut reader = BufReader::new(try!(File::open(path)));
+                for cert in rustls_pemfile::certs(&mut reader) {
+                    try!(roots.add(try!(cert))
+                        .map_err(|e| io::Error::new(ErrorKind::InvalidData, e)));
+                }
+            }
+            None => {
+                for cert in rustls_native_certs::load_native_certs().certs {
+                    let _ = roots.add(cert);
+                }
+            }
+        }
+
+        let provider = Arc::new(crypto::ring::default_provider());
+        let webpki = match roots.is_empty() {
+            true => None,
+            false => {
+                Some(try!(WebPkiServerVerifier::builder_with_provider(Arc::new(roots),
+                                                                      provider.clone())
+                    .build()
+                    .map_err(|e| io::Error::new(ErrorKind::InvalidData, e))))
+            }
+        };
+
+        Ok(FingerprintVerifier {
+            webpki: webpki,
+            pinned: options.fingerprint.clone(),
+            accepted: Mutex::new(None),
+            provider: provider,
+        })
+    }
+}
+
+impl ServerCertVerifier for FingerprintVerifier {
+    fn verify_server_cert(&self,
+                          end_entity: &CertificateDer,
+                          intermediates: &[CertificateDer],
+                          server_name: &ServerName,
+                          ocsp_response: &[u8],
+                          now: UnixTime)
+                          -> Result<ServerCertVerified, rustls::Error> {
+        let seen = fingerprint(end_entity);
+
+        if let Some(ref pinned) = self.pinned {
+            if same_fingerprint(pinned, &seen) {
+                return Ok(ServerCertVerified::assertion());
+            }
+            println!("The server certificate {} is not the pinned one", seen);
+            info!("Refused server certificate {}, {} is pinned", seen, pinned);
+            let failure = rustls::CertificateError::ApplicationVerificationFailure;
+            return Err(rustls::Error::InvalidCertificate(failure));
+        }
+
+        if let Some(ref accepted) = *self.accepted.lock().unwrap() {
+            if same_fingerprint(accepted, &seen) {
+                return Ok(ServerCertVerified::assertion());
+            }
+        }
+
+        let err = match self.webpki {
+            Some(ref webpki) => {
+                match webpki.verify_server_cert(end_entity,
+                                                intermediates,
+                                                server_name,
+                                                ocsp_response,
+                                                now) {
+                    Ok(verified) => return Ok(verified),
+                    Err(err) => err,
+                }
+            }
+            None => rustls::Error::InvalidCertificate(rustls::CertificateError::UnknownIssuer),
+        };
+
+        println!("Could not verify the server certificate: {}", err);
+        println!("SHA-256 fingerprint: {}", seen);
+        if !io::stdin().is_terminal() {
+            println!("Not asking whether to accept it without a terminal, use --fingerprint");
+            return Err(err);
+        }
+        print!("Accept this fingerprint? (y/n) ");
+        io::stdout().flush().expect("Something went wrong flushing");
+
+        let mut answer = String::new();
+        let _ = io::stdin().read_line(&mut answer);
+        match answer.trim().to_lowercase().as_ref() {
+            "y" | "yes" => {
+                info!("Accepted server certificate {}", seen);
+                *self.accepted.lock().unwrap() = Some(seen);
+                Ok(ServerCertVerified::assertion())
+            }
+            _ => Err(err),
+        }
+    }
+
+    fn verify_tls12_signature(&self,
+                              message: &[u8],
+                              cert: &CertificateDer,
+                              dss: &DigitallySignedStruct)
+                              -> Result<HandshakeSignatureValid, rustls::Error> {
+        crypto::verify_tls12_signature(message,
+                                       cert,
+                                       dss,
+                                       &self.provider.signature_verification_algorithms)
+    }
+
+    fn verify_tls13_signature(&self,
+                              message: &[u8],
+                              cert: &CertificateDer,
+                              dss: &DigitallySignedStruct)
+                              -> Result<HandshakeSignatureValid, rustls::Error> {
+        crypto::verify_tls13_signature(message,
+                                       cert,
+                                       dss,
+                                       &self.provider.signature_verification_algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider.signature_verification_algorithms.supported_schemes()
+    }
+}
@@ -9,7 +9,8 @@ bye		help		mput		rename		type
 cd		image		nlist		reset		user
 cdup		lcd		open		restart		verbose
 close		lpwd		passive		rmdir		?
-delete		ls		put		runique	
+reget		auth		site		compress
+delete		ls		put		runique		block
 debug		mdelete		pwd		send	
         ";
 
@@ -19,9 +20,14 @@ pub fn print_help(args: &str) {
         "!" | "bye" | "quit" | "exit" => println!("bye - closes application"),
         "append" => println!("append[local] [remote] - Appends a file to exising file in remote"),
         "ascii" => println!("ascii- Sets transfer mode to ascii"),
+        "auth" => println!("auth- Secures the connection with TLS (AUTH TLS, PBSZ, PROT P)"),
         "binary" | "image" => println!("binary- Sets transfer mode to binary"),
         "cd" | "dir" => println!("cd [path]- Changes current remote directory"),
         "cdup" => println!("cdup - Changes current remote directory one directory up"),
+        "compress" => println!("compress- Toggles MODE Z, transfers are deflated on the way"),
+        "block" => {
+            println!("block- Toggles MODE B, the data connection stays open between transfers")
+        }
         "close" | "disconnect" => println!("close - Closes current connection"),
         "dele" | "del" => println!("dele [file]- Deletes a file on remote connection"),
         "debug" => println!("debug- Toggles debug mode"),
@@ -49,12 +55,20 @@ pub fn print_help(args: &str) {
         "put" | "send" => println!("get[local] [remote] - endss a local file to remote path"),
         "nls" | "nlist" => println!("nlist [path]- List simple names on remote connection"),
         "open" | "ftp" => println!("open [host] [port]- opens a remote connection"),
-        "passive" => println!("passive- Sets transfer mode to passive"),
+        "passive" => println!("passive- Toggles between passive and active mode"),
+        "epsv4" => println!("epsv4- Toggles EPSV/EPRT over IPv4, IPv6 always uses them"),
         "pwd" => println!("pwd- Prints remote current working directory"),
         "size" => println!("size [file]- Prints size of remote file"),
         "rhelp" => println!("rhelp- Retrieves remote server help file"),
+        "reget" => {
+            println!("reget [remote] [local]- Resumes a download from the size of the local file")
+        }
         "reset" => println!("reset- Resets current connection"),
+        "restart" => {
+            println!("restart [local] [remote]- Resumes an upload from the size of the remote file")
+        }
         "rstatus" => println!("rstatus- Retrieves remote server status"),
+        "site" => println!("site [command]- Sends a SITE command, site quota shows your disk quota"),
         "rmdir" | "rmd" => println!("rmdir [path]- deletes a remote directory"),
         "runique" => println!("runique- Toggles receive unique to not overwrite existing files"),
         "rename" => println!("rename [from] [to]- Renames a remote file"),
@@ -0,0 +1,297 @@
+//! The service port. Admins log in with USER and PASS like on the FTP port
+//! and manage the server with one line commands
+//!
+//! - `SESSIONS` lists the clients with their user, address, directory and
+//!   running transfer
+//! - `STATS [id]` shows what every session, or just one, has transferred
+//! - `KICK <id>` aborts the session's transfer and disconnects it
+//! - `PAUSE` and `RESUME` stop and restart taking new logins
+//! - `RELOAD` reads fsys.cfg and the User DB again
+//! - `STOP` turns new clients away, waits up to SHUTDOWN_GRACE seconds for
+//!   running transfers and exits
+//!
+//! The old `server_stop`, `server_pause` and `server_start` still work.
+//! `AUTH TLS` encrypts the connection, which logins need unless the port
+//! only listens on the loopback interface. After MAX_ATTEMPTS failed logins
+//! the connection is closed
+
+use std::sync::Arc;
+
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+use crate::server;
+use crate::sessions::{Hangup, Session};
+use crate::stream::FtpStream;
+use crate::{drain, reload, Shared};
+
+const ADMIN_HELP: &'static str = "214-Service port commands\r
+ AUTH TLS        encrypt this connection\r
+ SESSIONS        list the connected clients\r
+ STATS [id]      bytes and transfers per session\r
+ KICK <id>       disconnect a session\r
+ PAUSE, RESUME   stop or restart taking new logins\r
+ RELOAD          read fsys.cfg and the User DB again\r
+ STOP            wait for running transfers and exit\r
+ QUIT            close this connection\r
+214 End\r\n";
+
+/// Accepts admin connections on the service port, each one is served as a
+/// task of its own so several admins can be connected at once
+pub async fn accept_admins(listener: tokio::net::TcpListener, shared: Arc<Shared>) {
+    loop {
+        let stream = match listener.accept().await {
+            Ok((stream, _)) => stream,
+            Err(e) => {
+                info!("Could not accept admin: {}", e);
+                continue;
+            }
+        };
+
+        let shared = shared.clone();
+
+        tokio::spawn(async move {
+            let mut client = BufReader::new(FtpStream::Plain(stream));
+            handle_admin(&mut client, &shared).await;
+        });
+    }
+}
+
+/// # handle_admin
+///
+/// Runs one admin connection until QUIT or a hang up
+///
+/// # Arguments
+///
+/// - client
+/// - shared
+pub async fn handle_admin(client: &mut BufReader<FtpStream>, shared: &Arc<Shared>) {
+    server::write_response(client,
+                           &format!("{} Service port, log in with an admin user using USER \
+                                     and PASS\r\n",
+                                    server::LOGGED_EXPECTED)).await;
+    let mut logged_in = false;
+    let settings = shared.settings.read().unwrap().clone();
+    let mut limit = settings.max_attempts.parse::<i32>().unwrap_or(3);
+    //Off the loopback interface passwords would go over the network
+    let local = client.get_ref().local_addr().map(|addr| addr.ip().is_loopback());
+    let tls_required = !local.unwrap_or(false);
+
+    loop {
+        let mut response = String::new();
+        match client.read_line(&mut response).await {
+            Ok(0) | Err(_) => break,
+            Ok(_) => {}
+        }
+
+        let line = response.trim();
+        let (cmd, args) = match line.find(' ') {
+            Some(pos) => (&line[0..pos], line[pos + 1..].trim()),
+            None => (line, ""),
+        };
+        info!("ADMIN: {}", cmd);
+
+        if !logged_in {
+            match cmd.to_lowercase().as_ref() {
+                "auth" => {
+                    if !server::handle_auth(client, args, &shared.tls).await {
+                        break;
+                    }
+                }
+                "user" if tls_required && !client.get_ref().is_tls() => {
+                    server::write_response(client,
+                                           &format!("{} TLS required, use AUTH TLS first\r\n",
+                                                    server::AUTHENTICATION_FAILED)).await;
+                }
+                "user" => {
+                    logged_in = login(client, shared, args).await;
+                    if !logged_in {
+                        limit -= 1;
+                        if limit <= 0 {
+                            info!("Too many failed logins on the service port");
+                            server::write_response(client,
+                                                   &format!("{} Too many failed logins\r\n",
+                                                            server::SERVICE_NOT_AVAILABLE))
+                                .await;
+                            break;
+                        }
+                    }
+                }
+                "quit" => break,
+                _ => {
+                    server::write_response(client,
+                                           &format!("{} Not Logged In\r\n",
+                                                    server::AUTHENTICATION_FAILED)).await
+                }
+            }
+            continue;
+        }
+
+        match cmd.to_lowercase().as_ref() {
+            "sessions" => list_sessions(client, shared).await,
+            "stats" => stats(client, shared, args).await,
+            "kick" => kick(client, shared, args).await,
+            "pause" | "server_pause" => {
+                shared.sessions.set_paused(true);
+                info!("Logins paused");
+                server::write_response(client,
+                                       &format!("{} New logins are paused\r\n",
+                                                server::OPERATION_SUCCESS)).await;
+            }
+            "resume" | "server_start" => {
+                shared.sessions.set_paused(false);
+                info!("Logins resumed");
+                server::write_response(client,
+                                       &format!("{} Taking new logins\r\n",
+                                                server::OPERATION_SUCCESS)).await;
+            }
+            "reload" => {
+                //Reading the User DB scans every user's files for the quota
+                let reloading = shared.clone();
+                let reloaded = tokio::task::spawn_blocking(move || reload(&reloading))
+                    .await
+                    .unwrap_or_else(|_| Err("The reload failed".to_string()));
+                match reloaded {
+                    Ok(count) => {
+                        server::write_response(client,
+                                               &format!("{} Reloaded the settings and {} \
+                                                         users\r\n",
+                                                        server::OPERATION_SUCCESS,
+                                                        count)).await
+                    }
+                    Err(e) => {
+                        server::write_response(client,
+                                               &format!("{} {}, nothing was changed\r\n",
+                                                        server::LOCAL_ERROR,
+                                                        e)).await
+                    }
+                }
+            }
+            "stop" | "server_stop" => {
+                stop(client, shared).await;
+                break;
+            }
+            "help" => server::write_response(client, ADMIN_HELP).await,
+            "quit" => {
+                server::write_response(client, &format!("{} GOODBYE\r\n", server::GOODBYE)).await;
+                break;
+            }
+            _ => {
+                server::write_response(client,
+                                       &format!("{} {} not understood\r\n",
+                                                server::NOT_UNDERSTOOD,
+                                                cmd)).await
+            }
+        }
+    }
+
+    let _ = client.get_mut().shutdown().await;
+}
+
+//Only users with the admin role get in
+async fn login(client: &mut BufReader<FtpStream>, shared: &Shared, name: &str) -> bool {
+    let users = shared.users.read().unwrap().clone();
+
+    match users.get(name) {
+        Some(user) if user.role == "admin" => {
+            server::handle_user(client, name, &users, &Hangup::new()).await.is_ok()
+        }
+        _ => {
+            info!("{} tried the service port", name);
+            server::write_response(client,
+                                   &format!("{} Only admins can use the service port\r\n",
+                                            server::AUTHENTICATION_FAILED)).await;
+            false
+        }
+    }
+}
+
+async fn list_sessions(client: &mut BufReader<FtpStream>, shared: &Shared) {
+    let sessions = shared.sessions.list();
+    let mut msg = format!("{}-{} sessions\r\n", server::SYSTEM_STATUS, sessions.len());
+
+    for session in sessions {
+        let transfer = match session.transfer() {
+            Some((command, bytes)) => format!("{} ({} bytes)", command, bytes),
+            None => "idle".to_string(),
+        };
+        msg.push_str(&format!(" {} {} {} {} {}\r\n",
+                              session.id,
+                              or_dash(session.user()),
+                              session.peer,
+                              or_dash(session.cwd()),
+                              transfer));
+    }
+
+    msg.push_str(&format!("{} End\r\n", server::SYSTEM_STATUS));
+    server::write_response(client, &msg).await;
+}
+
+async fn stats(client: &mut BufReader<FtpStream>, shared: &Shared, args: &str) {
+    let sessions = match args {
+        "" => shared.sessions.list(),
+        id => {
+            match find_session(client, shared, id).await {
+                Some(session) => vec![session],
+                None => return,
+            }
+        }
+    };
+
+    let mut msg = format!("{}-Transfer stats\r\n", server::SYSTEM_STATUS);
+    for session in sessions {
+        let stats = session.stats();
+        msg.push_str(&format!(" {} {} transfers {} uploaded {} downloaded {}\r\n",
+                              session.id,
+                              or_dash(session.user()),
+                              stats.transfers,
+                              stats.uploaded,
+                              stats.downloaded));
+    }
+
+    msg.push_str(&format!("{} End\r\n", server::SYSTEM_STATUS));
+    server::write_response(client, &msg).await;
+}
+
+async fn kick(client: &mut BufReader<FtpStream>, shared: &Shared, args: &str) {
+    if let Some(session) = find_session(client, shared, args).await {
+        info!("Kicking session {} of {}", session.id, session.user());
+        session.kick("Disconnected by the administrator");
+        server::write_response(client,
+                               &format!("{} Session {} disconnected\r\n",
+                                        server::OPERATION_SUCCESS,
+                                        session.id)).await;
+    }
+}
+
+//Drains the sessions before the server goes down, so the admin sees when
+//it is over
+async fn stop(client: &mut BufReader<FtpStream>, shared: &Shared) {
+    info!("Stopping the server from the service port");
+    server::write_response(client,
+                           &format!("{} Stopping, waiting for {} sessions\r\n",
+                                    server::OPERATION_SUCCESS,
+                                    shared.sessions.len())).await;
+
+    drain(shared).await;
+    server::write_response(client, &format!("{} Server stopped\r\n", server::GOODBYE)).await;
+    shared.stop.notify_one();
+}
+
+async fn find_session(client: &mut BufReader<FtpStream>,
+                      shared: &Shared,
+                      id: &str)
+                      -> Option<Arc<Session>> {
+    let session = id.parse::<u64>().ok().and_then(|id| shared.sessions.get(id));
+    if session.is_none() {
+        server::write_response(client,
+                               &format!("{} No session {}\r\n", server::BAD_SEQUENCE, id)).await;
+    }
+    session
+}
+
+fn or_dash(value: String) -> String {
+    match value.is_empty() {
+        true => "-".to_string(),
+        false => value,
+    }
+}
@@ -0,0 +1,139 @@
+//! TYPE A line endings. Files are stored with the local LF line ends and
+//! sent as CRLF, the NVT-ASCII form RFC 959 puts on the wire. A LF that
+//! already follows a CR goes out as it is, the same rule SIZE counts by
+
+use std::io;
+use std::io::prelude::*;
+use std::io::BufReader;
+
+/// Reads a file as it goes out in ASCII mode, every bare LF becomes CRLF
+pub struct ToCrlf<R: Read> {
+    inner: R,
+    buf: Vec<u8>,
+    last: u8,
+    //A LF whose CR filled up the last read
+    pending_lf: bool,
+}
+
+impl<R: Read> ToCrlf<R> {
+    pub fn new(inner: R) -> ToCrlf<R> {
+        ToCrlf {
+            inner: inner,
+            buf: Vec::new(),
+            last: 0,
+            pending_lf: false,
+        }
+    }
+}
+
+impl<R: Read> Read for ToCrlf<R> {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        if out.is_empty() {
+            return Ok(0);
+        }
+
+        let mut n = 0;
+        if self.pending_lf {
+            out[0] = b'\n';
+            self.pending_lf = false;
+            self.last = b'\n';
+            n = 1;
+            if n == out.len() {
+                return Ok(n);
+            }
+        }
+
+        //Each byte read can turn into two
+        let want = ((out.len() - n) / 2).max(1);
+        self.buf.resize(want, 0);
+        let got = self.inner.read(&mut self.buf[..want])?;
+
+        for &byte in &self.buf[..got] {
+            if byte == b'\n' && self.last != b'\r' {
+                out[n] = b'\r';
+                n += 1;
+                if n == out.len() {
+                    self.pending_lf = true;
+                    self.last = b'\r';
+                    break;
+                }
+            }
+            out[n] = byte;
+            n += 1;
+            self.last = byte;
+        }
+
+        Ok(n)
+    }
+}
+
+/// Writes what came in in ASCII mode, CRLF is stored as LF. A CR at the end
+/// of a write is held back until the next one shows what follows it, so
+/// `finish` has to be called once the data is all in
+pub struct FromCrlf<W: Write> {
+    inner: W,
+    cr: bool,
+}
+
+impl<W: Write> FromCrlf<W> {
+    pub fn new(inner: W) -> FromCrlf<W> {
+        FromCrlf {
+            inner: inner,
+            cr: false,
+        }
+    }
+
+    /// Writes a CR still held back, it ended the data
+    pub fn finish(&mut self) -> io::Result<()> {
+        if self.cr {
+            self.cr = false;
+            self.inner.write_all(b"\r")?;
+        }
+        self.inner.flush()
+    }
+}
+
+impl<W: Write> Write for FromCrlf<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut out = Vec::with_capacity(buf.len() + 1);
+
+        for &byte in buf {
+            if self.cr && byte != b'\n' {
+                out.push(b'\r');
+            }
+            self.cr = byte == b'\r';
+            if !self.cr {
+                out.push(byte);
+            }
+        }
+
+        self.inner.write_all(&out)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Where a REST offset the client counted in what went out in ASCII mode
+/// lands in the local file. One that falls between a CR that was added and
+/// its LF lands on the LF
+pub fn local_offset<R: Read>(file: R, wire: u64) -> io::Result<u64> {
+    let (mut local, mut sent, mut last) = (0, 0, 0);
+
+    for byte in BufReader::new(file).bytes() {
+        let byte = byte?;
+        sent += match byte == b'\n' && last != b'\r' {
+            true => 2,
+            false => 1,
+        };
+        if sent > wire {
+            break;
+        }
+        local += 1;
+        last = byte;
+    }
+
+    Ok(local)
+}
@@ -0,0 +1,185 @@
+//! The audit log, one JSON object per line for everything a session does:
+//! connecting, logging in or failing to and why, every command with the
+//! code it was answered with, and disconnecting
+//!
+//! ```text
+//! {"time":"2026-10-18T10:31:02Z","session":3,"peer":"127.0.0.1","event":"login","user":"user2","success":false,"reason":"blocked"}
+//! ```
+//!
+//! Passwords never make it in, PASS is only seen as part of a login
+
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::prelude::*;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::server;
+
+/// The log file, shared by every session and appended to
+#[derive(Debug)]
+pub struct AuditLog {
+    file: Option<Mutex<File>>,
+}
+
+impl AuditLog {
+    /// Opens `path` for appending, an empty path turns the log off
+    pub fn open(path: &str) -> io::Result<AuditLog> {
+        if path.is_empty() {
+            return Ok(AuditLog { file: None });
+        }
+
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(AuditLog { file: Some(Mutex::new(file)) })
+    }
+
+    fn write(&self, record: &Record) {
+        if let Some(ref file) = self.file {
+            let line = format!("{}\n", record.finish());
+            if let Err(e) = file.lock().unwrap().write_all(line.as_bytes()) {
+                info!("Could not write to the audit log: {}", e);
+            }
+        }
+    }
+}
+
+/// A session's handle on the log, every record carries its id and address
+#[derive(Debug, Clone)]
+pub struct SessionAudit {
+    log: Arc<AuditLog>,
+    session: u64,
+    peer: IpAddr,
+}
+
+impl SessionAudit {
+    pub fn new(log: Arc<AuditLog>, session: u64, peer: IpAddr) -> SessionAudit {
+        SessionAudit {
+            log: log,
+            session: session,
+            peer: peer,
+        }
+    }
+
+    pub fn connect(&self, reply: Option<u32>) {
+        let record = self.record("connect").code("reply", reply);
+        self.log.write(&record);
+    }
+
+    /// `result` holds why the login failed, like "blocked" or "bad password"
+    pub fn login(&self, user: &str, result: Result<(), &str>) {
+        let record = self.record("login").string("user", user).boolean("success", result.is_ok());
+        let record = match result {
+            Ok(_) => record,
+            Err(reason) => record.string("reason", reason),
+        };
+        self.log.write(&record);
+    }
+
+    /// A command and the last reply it got. PASS arguments are left out
+    pub fn command(&self, user: &str, command: &str, args: &str, reply: Option<u32>) {
+        let command = command.to_uppercase();
+        let args = match command.as_ref() {
+            "PASS" => "",
+            _ => args,
+        };
+
+        let record = self.record("command")
+            .string("user", user)
+            .string("command", &command)
+            .string("args", args)
+            .code("reply", reply);
+        self.log.write(&record);
+    }
+
+    pub fn disconnect(&self, user: &str, reason: &str) {
+        let record = self.record("disconnect").string("user", user).string("reason", reason);
+        self.log.write(&record);
+    }
+
+    fn record(&self, event: &str) -> Record {
+        Record::new()
+            .string("time", &timestamp(SystemTime::now()))
+            .number("session", self.session)
+            .string("peer", &self.peer.to_string())
+            .string("event", event)
+    }
+}
+
+/// A JSON object built up one field at a time
+#[derive(Debug)]
+pub struct Record {
+    fields: Vec<String>,
+}
+
+impl Record {
+    pub fn new() -> Record {
+        Record { fields: Vec::new() }
+    }
+
+    pub fn string(mut self, key: &str, value: &str) -> Record {
+        self.fields.push(format!("{}:{}", quote(key), quote(value)));
+        self
+    }
+
+    pub fn number(mut self, key: &str, value: u64) -> Record {
+        self.fields.push(format!("{}:{}", quote(key), value));
+        self
+    }
+
+    pub fn boolean(mut self, key: &str, value: bool) -> Record {
+        self.fields.push(format!("{}:{}", quote(key), value));
+        self
+    }
+
+    /// A reply code, `null` when there was none
+    pub fn code(mut self, key: &str, value: Option<u32>) -> Record {
+        let value = match value {
+            Some(code) => code.to_string(),
+            None => "null".to_string(),
+        };
+        self.fields.push(format!("{}:{}", quote(key), value));
+        self
+    }
+
+    pub fn finish(&self) -> String {
+        format!("{{{}}}", self.fields.join(","))
+    }
+}
+
+/// Makes a JSON string, escaping quotes, backslashes and control characters
+pub fn quote(value: &str) -> String {
+    let mut quoted = String::from("\"");
+    for c in value.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c if (c as u32) < 0x20 => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+//Formats a time as an RFC 3339 UTC timestamp, 2026-10-18T10:31:02Z
+fn timestamp(time: SystemTime) -> String {
+    let secs = match time.duration_since(UNIX_EPOCH) {
+        Ok(elapsed) => elapsed.as_secs(),
+        Err(_) => 0,
+    };
+
+    let (year, month, day) = server::civil_from_days((secs / 86400) as i64);
+    let rem = secs % 86400;
+
+    format!("{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+            year,
+            month,
+            day,
+            rem / 3600,
+            (rem % 3600) / 60,
+            rem % 60)
+}
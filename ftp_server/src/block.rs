@@ -0,0 +1,187 @@
+//! MODE B, the block mode of RFC 959. Data goes in blocks of up to 65535
+//! bytes, each behind a three byte header: a descriptor and the byte count.
+//! The descriptor flags the last block of a file (EOF) and restart markers,
+//! whose data is the marker instead of file data. Since the end of a file is
+//! marked in band, the data connection can stay open for the next transfer
+//!
+//! Markers here are the decimal offset in the file of the data that follows
+//! them, which is what REST takes to restart a transfer from one
+
+use std::io;
+use std::io::prelude::*;
+
+//Descriptor bits, end of record and suspect data don't matter to files
+pub const EOF: u8 = 64;
+pub const MARKER: u8 = 16;
+
+//Bytes of file data between the restart markers a sender puts in
+pub const MARKER_INTERVAL: u64 = 1 << 20;
+
+const MAX_BLOCK: usize = 65535;
+
+/// Sends what is written to it as data blocks. `finish` sends the EOF block,
+/// without it the receiver takes the file as cut off
+pub struct BlockWriter<W: Write> {
+    inner: W,
+    //Where in the file the data written so far ends
+    offset: u64,
+    //Offset of the next restart marker, if markers are sent
+    next_marker: Option<u64>,
+}
+
+impl<W: Write> BlockWriter<W> {
+    pub fn new(inner: W) -> BlockWriter<W> {
+        BlockWriter {
+            inner: inner,
+            offset: 0,
+            next_marker: None,
+        }
+    }
+
+    /// A writer for a file sent from `offset` on, with a restart marker
+    /// every MARKER_INTERVAL bytes
+    pub fn with_markers(inner: W, offset: u64) -> BlockWriter<W> {
+        BlockWriter {
+            inner: inner,
+            offset: offset,
+            next_marker: Some(offset + MARKER_INTERVAL),
+        }
+    }
+
+    /// Where the blocks go
+    pub fn get_mut(&mut self) -> &mut W {
+        &mut self.inner
+    }
+
+    pub fn finish(&mut self) -> io::Result<()> {
+        self.block(EOF, &[])?;
+        self.inner.flush()
+    }
+
+    fn block(&mut self, descriptor: u8, data: &[u8]) -> io::Result<()> {
+        let mut block = Vec::with_capacity(data.len() + 3);
+        block.push(descriptor);
+        block.push((data.len() >> 8) as u8);
+        block.push(data.len() as u8);
+        block.extend_from_slice(data);
+        self.inner.write_all(&block)
+    }
+}
+
+impl<W: Write> Write for BlockWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        let n = buf.len().min(MAX_BLOCK);
+        self.block(0, &buf[..n])?;
+        self.offset += n as u64;
+
+        if let Some(next) = self.next_marker {
+            if self.offset >= next {
+                let marker = self.offset.to_string();
+                self.block(MARKER, marker.as_bytes())?;
+                self.next_marker = Some(self.offset + MARKER_INTERVAL);
+            }
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Takes the blocks of a file apart as they come in, up to the EOF block.
+/// It is fed no more than `wanted` bytes at a time, so nothing past the EOF
+/// block is ever read off the connection. Restart markers are kept for the
+/// caller along with how much data came before them
+pub struct BlockDecoder {
+    header: Vec<u8>,
+    //Bytes left in the current block, data or marker
+    left: usize,
+    //The current block holds a restart marker
+    in_marker: bool,
+    //The current block is the last one
+    last: bool,
+    eof: bool,
+    read: u64,
+    marker: Vec<u8>,
+    marker_at: Option<(String, u64)>,
+}
+
+impl BlockDecoder {
+    pub fn new() -> BlockDecoder {
+        BlockDecoder {
+            header: Vec::with_capacity(3),
+            left: 0,
+            in_marker: false,
+            last: false,
+            eof: false,
+            read: 0,
+            marker: Vec::new(),
+            marker_at: None,
+        }
+    }
+
+    /// How many bytes to read next at most, 0 once the EOF block is in
+    pub fn wanted(&self) -> usize {
+        match (self.eof, self.left) {
+            (true, _) => 0,
+            (false, 0) => 3 - self.header.len(),
+            (false, left) => left,
+        }
+    }
+
+    /// Takes bytes read off the connection, no more than `wanted`, and
+    /// returns the file data among them
+    pub fn decode<'a>(&mut self, buf: &'a [u8]) -> &'a [u8] {
+        let buf = &buf[..buf.len().min(self.wanted())];
+
+        if self.left == 0 {
+            self.header.extend_from_slice(buf);
+            if self.header.len() == 3 {
+                let count = (self.header[1] as usize) << 8 | self.header[2] as usize;
+                self.in_marker = self.header[0] & MARKER != 0;
+                self.last = self.header[0] & EOF != 0;
+                self.left = count;
+                self.header.clear();
+                self.end_block();
+            }
+            return &[];
+        }
+
+        self.left -= buf.len();
+        if self.in_marker {
+            self.marker.extend_from_slice(buf);
+            self.end_block();
+            return &[];
+        }
+
+        self.read += buf.len() as u64;
+        self.end_block();
+        buf
+    }
+
+    /// The last restart marker since this was called, and the bytes of data
+    /// read before it
+    pub fn take_marker(&mut self) -> Option<(String, u64)> {
+        self.marker_at.take()
+    }
+
+    //Wraps up the current block once all of it is in
+    fn end_block(&mut self) {
+        if self.left > 0 {
+            return;
+        }
+
+        if self.in_marker {
+            let marker = String::from_utf8_lossy(&self.marker).trim().to_string();
+            self.marker_at = Some((marker, self.read));
+            self.marker.clear();
+            self.in_marker = false;
+        }
+        self.eof = self.last;
+    }
+}
@@ -4,6 +4,13 @@
 extern crate argparse; //argument parsing such as -h -d etc..
 extern crate rand; // unique string names to handle collisions
 extern crate ini; // configuration file parser
+extern crate rustls; // FTPS support
+extern crate rustls_pemfile;
+extern crate argon2; // password hashes in the user database
+extern crate pwhash;
+extern crate constant_time_eq;
+extern crate tokio; // runs the listeners and sessions
+extern crate flate2; // MODE Z
 
 // External logging library for pretty logging
 #[macro_use]
@@ -16,13 +23,11 @@ extern crate log;
 use ini::Ini;
 
 use std::io::prelude::*; //the standard io functions that come with rust
-use std::io::{Write, BufReader};
+use std::io::Write;
 use std::io;
-use std::thread::spawn; //For threads
-use std::thread; //For threads
 
 use std::string::String;
-use std::net::{Ipv4Addr, TcpStream, TcpListener, Shutdown, SocketAddrV4};
+use std::net::{IpAddr, Ipv4Addr, TcpStream, TcpListener, Shutdown, SocketAddr, SocketAddrV4};
 
 use std::path::Path;
 use std::fs;
@@ -33,9 +38,17 @@ use std::process;
 use std::env;
 use std::iter::Iterator;
 use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+use std::future::Future;
+use std::time::{Duration, Instant};
 
 use argparse::{ArgumentParser, Print, Store, StoreTrue, StoreFalse};
 use slog::DrainExt;
+use rustls::ServerConfig;
+use tokio::io::{AsyncWriteExt, BufReader};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::mpsc::UnboundedSender;
+use tokio::sync::{Notify, Semaphore};
 
 
 
@@ -44,16 +57,45 @@ mod server;
 mod tests;
 mod user;
 mod main_commands;
+mod metrics;
+mod password;
+mod ports;
+mod quota;
+mod sessions;
+mod admin;
+mod ascii;
+mod audit;
+mod block;
+mod throttle;
+mod stream;
+mod storage;
+mod transfer;
+mod xferlog;
 
 use user::User;
-use server::FtpMode;
+use server::{FtpMode, TransferMode};
+use stream::FtpStream;
+use storage::{LocalDisk, MemoryStorage, Storage};
+use ports::{PassivePort, PortPool};
+use sessions::{Session, Sessions};
+use xferlog::{TransferLog, XferLog};
+use audit::{AuditLog, SessionAudit};
+use metrics::METRICS;
 use main_commands as mc;
 
+//Port of METRICS_LISTEN addresses that don't give one
+const METRICS_PORT: &'static str = "2117";
+
+//Read at startup, and again on SIGHUP or RELOAD from the service port
+const FSYS_CONFIG: &'static str = "conf/fsys.cfg";
+
 #[derive(Debug, Clone)]
 struct Settings {
     ftp_port: String,
     ftp_mode: String,
     service_port: String,
+    listen: String,
+    service_listen: String,
     ftp_root: String,
     users_path: String,
     welcome: String,
@@ -66,6 +108,22 @@ struct Settings {
     log_file: String,
     max_users: String,
     max_attempts: String,
+    tls_cert: String,
+    tls_key: String,
+    tls_required: bool,
+    //Bandwidth limits in bytes per second, 0 is unlimited
+    upload_rate: u64,
+    download_rate: u64,
+    session_upload_rate: u64,
+    session_download_rate: u64,
+    //Seconds running transfers get to finish when the server stops
+    shutdown_grace: u64,
+    //The xferlog, empty turns it off
+    xfer_log: String,
+    //JSON lines of every session event, empty turns it off
+    audit_log: String,
+    //Where Prometheus scrapes, empty turns it off
+    metrics_listen: String,
 }
 
 //These are the defaults incase no arguements are provided
@@ -86,6 +144,8 @@ impl Settings {
             ftp_root: "ftproot".to_string(),
             ftp_mode: "PASSIVE".to_string(),
             service_port: "2185".to_string(),
+            listen: "127.0.0.1".to_string(),
+            service_listen: "127.0.0.1".to_string(),
             users_path: "conf/users.cfg".to_string(),
             welcome: "Welcome To Pachev's FTP".to_string(),
             passive: true,
@@ -97,22 +157,42 @@ impl Settings {
             log_file: "logs/fserver.log".to_string(),
             max_users: "200".to_string(),
             max_attempts: "3".to_string(),
+            tls_cert: "".to_string(),
+            tls_key: "".to_string(),
+            tls_required: false,
+            upload_rate: 0,
+            download_rate: 0,
+            session_upload_rate: 0,
+            session_download_rate: 0,
+            shutdown_grace: 30,
+            xfer_log: "logs/xferlog".to_string(),
+            audit_log: "logs/audit.log".to_string(),
+            metrics_listen: "127.0.0.1".to_string(),
         }
     }
 }
 
 fn main() {
+    //Making a password hash needs no configuration, so it comes first
+    if env::args().any(|arg| arg == "--hash-password") {
+        print_password_hash();
+        return;
+    }
+
     let mut settings = Settings::new();
-    let conf = Ini::load_from_file("conf/fsys.cfg").unwrap();
+    let conf = Ini::load_from_file(FSYS_CONFIG).unwrap();
 
     //Loading default setting from conf file
-    load_defaults(&mut settings, &conf);
+    if let Err(e) = load_defaults(&mut settings, &conf) {
+        panic!("{}", e);
+    }
 
     /*This is due to borrowing issue I'm setting a default mode of true
      * but use the argparser to allow the user to set the transfer mode
      */
 
     let mut passive = true;
+    let mut hash_password = false; //only here for --help, see the top of main
 
     {
 
@@ -159,95 +239,26 @@ fn main() {
         ap.refer(&mut settings.users_path)
             .add_option(&["-u", "--userdb"], Store, "location of User DB file");
 
+        ap.refer(&mut hash_password)
+            .add_option(&["--hash-password"],
+                        StoreTrue,
+                        "Reads a password from stdin and prints its hash for the User DB");
+
         ap.parse_args_or_exit();
     }
     settings.passive = passive;
 
 
     //Creating the database of users
-    let mut users: HashMap<String, user::User> = HashMap::new();
-    users = get_user_list(&settings);
-
-    let service_port = format!("{}", settings.service_port);
-    let mut map = users.clone(); //Cloning users for service port usage
-    let mut serv_settings = settings.clone();
-
-    // # This is the service port for the FTP server
-    // It will be in the background stoping everything and starting everything
-    let thread = thread::spawn(move || {
-
-        let server = format!("127.0.0.1:{}", service_port);
-        let listener = TcpListener::bind(server.as_str()).expect("Could not bind to main port");
-        let (stream, _) = listener.accept().expect("Could not connect to service prot");
-        let mut serv_client = BufReader::new(stream);
-        let mut logged_in = false;
-        let mut started = true;
-        server::write_response(&mut serv_client,
-                               "Welcome to Sevice Port please login with admin user 
-                               using 'USER [username] Followed by 'PASS [password]'\r\n");
-
-
-        loop {
-
-            let mut response = String::new();
-            serv_client.read_line(&mut response).expect("Could not read stream message");
-
-            let line = response.trim();
-
-            let (cmd, args) = match line.find(' ') {
-                Some(pos) => (&line[0..pos], &line[pos + 1..]),
-                None => (line, "".as_ref()),
-            };
-
-            if logged_in {
-
-                match cmd.to_lowercase().as_ref() {
-                    "server_stop" => {
-                        process::exit(1);
-                    }
-                    "server_start" => {
-                        match started {
-                            true => {
-                                server::write_response(&mut serv_client,
-                                                       "Server is already running \r\n")
-                            }
-                            false => {
-                                start_server(&mut serv_settings, &map);
-                                server::write_response(&mut serv_client, "Server has started\r\n");
-                            }
-
-                        }
-                    }
-                    "server_pause" => {
-                        started = pause_server();
-                    }
-                    _ => {
-                        println!("Bad Command");
-                    }
-                }
-            } else {
-                logged_in = server::handle_user(&mut serv_client, &args, &map);
-            }
-        }
-
-    });
+    let users = match get_user_list(&settings) {
+        Ok(users) => users,
+        Err(e) => panic!("{}", e),
+    };
 
     start_server(&mut settings, &users);
-
-    thread.join().expect("Could not join service thread");
-
-}
-fn pause_server() -> bool {
-    false
 }
 
 fn start_server(settings: &mut Settings, users: &HashMap<String, user::User>) {
-    use std::collections::HashSet;
-    use std::collections::HashMap;
-    use std::sync::{Arc, Mutex};
-
-    let mut threads = HashMap::new();
-
     let log_path = Path::new(&settings.log_file);
     let log_file = OpenOptions::new()
         .create(true)
@@ -265,66 +276,228 @@ fn start_server(settings: &mut Settings, users: &HashMap<String, user::User>) {
     //Sets FTP ROOT
     create_root(&settings);
 
-    let server = format!("127.0.0.1:{}", settings.ftp_port);
-    let listener = TcpListener::bind(server.as_str()).expect("Could not bind to main port");
-    let data_port_range = get_data_ports(format!("{}", settings.data_port_range));
-
-    let hash_set: HashSet<i32> = HashSet::new();
-    let hash_set_done: HashSet<i32> = HashSet::new();
+    let tls = load_tls(&settings);
+    if settings.tls_required && tls.is_none() {
+        panic!("TLS_REQUIRED is set but no TLS certificate could be loaded");
+    }
 
-    let mut used_ports = Arc::new(Mutex::new(hash_set));
-    let mut used_ports_done = Arc::new(Mutex::new(hash_set_done));
+    let listen = match listen_addrs(&settings.listen, &settings.ftp_port) {
+        Ok(addrs) => addrs,
+        Err(e) => panic!("{}", e),
+    };
+    let service_addr = match listen_addrs(&settings.service_listen, &settings.service_port) {
+        Ok(ref addrs) if addrs.len() == 1 => addrs[0],
+        Ok(_) => panic!("SERVICE_LISTEN takes a single address"),
+        Err(e) => panic!("{}", e),
+    };
+    //Admins would have to log in over the network in the clear
+    if !service_addr.ip().is_loopback() && tls.is_none() {
+        panic!("SERVICE_LISTEN {} is not a loopback address, that needs a TLS certificate",
+               service_addr.ip());
+    }
+    let metrics_addr = match settings.metrics_listen.trim().is_empty() {
+        true => None,
+        false => {
+            match listen_addrs(&settings.metrics_listen, METRICS_PORT) {
+                Ok(ref addrs) if addrs.len() == 1 => Some(addrs[0]),
+                Ok(_) => panic!("METRICS_LISTEN takes a single address"),
+                Err(e) => panic!("{}", e),
+            }
+        }
+    };
+    let max_users = settings.max_users.parse::<usize>().unwrap_or(200);
 
-    let mut port_count = 0;
+    let data_ports = get_data_ports(format!("{}", settings.data_port_range));
+    let xferlog = match XferLog::open(&settings.xfer_log) {
+        Ok(xferlog) => xferlog,
+        Err(e) => panic!("Could not open the transfer log {}: {}", settings.xfer_log, e),
+    };
+    let audit = match AuditLog::open(&settings.audit_log) {
+        Ok(audit) => audit,
+        Err(e) => panic!("Could not open the audit log {}: {}", settings.audit_log, e),
+    };
 
+    let shared = Arc::new(Shared {
+        settings: RwLock::new(Arc::new(settings.clone())),
+        users: RwLock::new(Arc::new(users.clone())),
+        tls: tls,
+        //Shared by every session, so this one caps the whole server
+        limits: RwLock::new(throttle::Limits::new(settings.upload_rate, settings.download_rate)),
+        slots: Arc::new(Semaphore::new(max_users)),
+        sessions: Sessions::new(),
+        ports: PortPool::new(&data_ports),
+        xferlog: Arc::new(xferlog),
+        audit: Arc::new(audit),
+        stop: Notify::new(),
+    });
 
     println!("Welcome to Pachev's Famous Rusty FTP Server");
 
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .expect("Could not start the runtime");
 
-    for stream in listener.incoming() {
-        port_count = 0;
-        while used_ports.lock().unwrap().contains(&data_port_range[port_count]) && port_count < 200 {
-            port_count += 1;
-        }
-        if port_count >= 200 {
-            info!("Reached client threshold");
-            continue;
-        }
-	println!("Handling client number {}", port_count);
-        let data_port = data_port_range[port_count];
-        let stream = stream.expect("Could not create TCP Stream");
-
-        if (used_ports_done.lock().unwrap().contains(&data_port)) {
-          let t: std::thread::JoinHandle<_> = threads.remove(&data_port).unwrap();
-          let _ = t.join();
-          used_ports_done.lock().unwrap().remove(&data_port);
-          println!("Cleaned up the data from previous run...");
-        }
-        used_ports.lock().unwrap().insert(data_port);
+    runtime.block_on(serve(listen, service_addr, metrics_addr, shared));
+
+    //The sessions are gone by now, this joins the runtime's threads
+    runtime.shutdown_timeout(Duration::from_secs(1));
+    info!("Server stopped");
+}
 
+//What sessions and the admin port share
+struct Shared {
+    //Replaced on reload, sessions keep the ones they started with
+    settings: RwLock<Arc<Settings>>,
+    //Replaced as a whole on reload, sessions keep the one they logged in with
+    users: RwLock<Arc<HashMap<String, user::User>>>,
+    tls: Option<Arc<ServerConfig>>,
+    limits: RwLock<throttle::Limits>,
+    //One permit per connected client, MAX_USERS of them
+    slots: Arc<Semaphore>,
+    sessions: Sessions,
+    //Passive ports, taken by PASV and EPSV for one transfer
+    ports: PortPool,
+    xferlog: Arc<XferLog>,
+    audit: Arc<AuditLog>,
+    //Wakes serve up to stop the server
+    stop: Notify,
+}
+
+//Accepts clients on every LISTEN address and admins on the service port
+//until SIGTERM, SIGINT or the admin port stops the server
+async fn serve(listen: Vec<SocketAddr>,
+               service_addr: SocketAddr,
+               metrics_addr: Option<SocketAddr>,
+               shared: Arc<Shared>) {
+    let service = tokio::net::TcpListener::bind(service_addr)
+        .await
+        .expect("Could not bind to service port");
+    println!("Service port on {}", service_addr);
+    info!("Service port on {}", service_addr);
+    let mut listeners = vec![tokio::spawn(admin::accept_admins(service, shared.clone()))];
+
+    if let Some(addr) = metrics_addr {
+        let metrics = tokio::net::TcpListener::bind(addr)
+            .await
+            .expect("Could not bind to metrics port");
+        println!("Metrics on http://{}/metrics", addr);
+        info!("Metrics on http://{}/metrics", addr);
+        listeners.push(tokio::spawn(metrics::accept_scrapers(metrics, shared.clone())));
+    }
 
-        debug!("client {} has started and given data port {}",
-               stream.peer_addr().unwrap().ip(),
-               data_port);
+    for addr in listen {
+        let listener = tokio::net::TcpListener::bind(addr)
+            .await
+            .expect("Could not bind to main port");
+        println!("Listening on {}", addr);
+        info!("Listening on {}", addr);
 
-        let mut map = users.clone();
-        let settings = settings.clone();
-        let mut used_ports_client_copy = used_ports.clone();
-	let mut used_ports_done_client_copy = used_ports_done.clone();
+        listeners.push(tokio::spawn(accept_clients(listener, shared.clone())));
+    }
+
+    for kind in vec![SignalKind::terminate(), SignalKind::interrupt()] {
+        let mut signals = signal(kind).expect("Could not listen for signals");
+        let shared = shared.clone();
 
-        threads.insert(data_port, spawn(move || {
-            let mut b_stream = BufReader::new(stream);
-            handle_client(&mut b_stream, &data_port, &settings, &mut map);
-            used_ports_client_copy.lock().unwrap().remove(&data_port);
-	    used_ports_done_client_copy.lock().unwrap().insert(data_port);
-        }));
+        tokio::spawn(async move {
+            if signals.recv().await.is_some() {
+                info!("Got a signal to stop");
+                shared.stop.notify_one();
+            }
+        });
     }
 
-    for (p, t) in threads {
-        info!("Stopping all threads");
-        let _ = t.join();
+    let mut hangups = signal(SignalKind::hangup()).expect("Could not listen for signals");
+    let reloading = shared.clone();
+    tokio::spawn(async move {
+        while hangups.recv().await.is_some() {
+            info!("Got SIGHUP, reloading the configuration");
+            let shared = reloading.clone();
+            let reloaded = tokio::task::spawn_blocking(move || reload(&shared))
+                .await
+                .unwrap_or_else(|_| Err("The reload failed".to_string()));
+            if let Err(e) = reloaded {
+                info!("{}, nothing was changed", e);
+            }
+        }
+    });
+
+    //The listeners keep turning clients away while the sessions drain
+    shared.stop.notified().await;
+    drain(&shared).await;
+
+    for listener in listeners {
+        listener.abort();
     }
+}
 
+//How often a stopping server checks whether its sessions are gone
+const DRAIN_INTERVAL: u64 = 100;
+
+//Turns new clients away and has every session end with a 421 once its
+//transfer is done. Transfers still running after SHUTDOWN_GRACE seconds
+//are aborted
+async fn drain(shared: &Shared) {
+    let grace = Duration::from_secs(shared.settings.read().unwrap().shutdown_grace);
+    let started = Instant::now();
+    let mut aborted = false;
+
+    shared.sessions.stop();
+    info!("Stopping, waiting for {} sessions", shared.sessions.len());
+
+    while shared.sessions.len() > 0 {
+        if !aborted && started.elapsed() >= grace {
+            info!("Grace period is over, aborting the transfers of {} sessions",
+                  shared.sessions.len());
+            shared.sessions.abort_transfers();
+            aborted = true;
+        }
+        tokio::time::sleep(Duration::from_millis(DRAIN_INTERVAL)).await;
+    }
+}
+
+async fn accept_clients(listener: tokio::net::TcpListener, shared: Arc<Shared>) {
+    loop {
+        let (stream, peer) = match listener.accept().await {
+            Ok(client) => client,
+            Err(e) => {
+                info!("Could not accept client: {}", e);
+                continue;
+            }
+        };
+
+        if shared.sessions.stopping() {
+            refuse(stream, "Server is shutting down").await;
+            continue;
+        }
+        let permit = match shared.slots.clone().try_acquire_owned() {
+            Ok(permit) => permit,
+            Err(_) => {
+                info!("Reached client threshold");
+                refuse(stream, "Too many users, try again later").await;
+                continue;
+            }
+        };
+        debug!("client {} has started", peer.ip());
+
+        let shared = shared.clone();
+
+        tokio::spawn(async move {
+            let registration = shared.sessions.register(peer);
+            let mut b_stream = BufReader::new(FtpStream::Plain(stream));
+            let session = handle_client(&mut b_stream, &shared, &registration.session);
+            server::track_replies(session).await;
+            drop(permit);
+        });
+    }
+}
+
+//Turns a client away before its session starts
+async fn refuse(mut stream: tokio::net::TcpStream, reason: &str) {
+    let msg = format!("{} {}\r\n", server::SERVICE_NOT_AVAILABLE, reason);
+    let _ = stream.write_all(msg.as_bytes()).await;
+    let _ = stream.shutdown().await;
 }
 
 /// # handle_client
@@ -334,20 +507,24 @@ fn start_server(settings: &mut Settings, users: &HashMap<String, user::User>) {
 /// # Arguments
 ///
 /// - client
-/// - data_port
-/// - map
-fn handle_client(mut client: &mut BufReader<TcpStream>,
-                 data_port: &i32,
-                 settings: &Settings,
-                 map: &HashMap<String, user::User>) {
+/// - shared
+/// - session
+async fn handle_client(mut client: &mut BufReader<FtpStream>,
+                       shared: &Shared,
+                       session: &Session) {
 
-    let data_server = format!("{}:{}",
-                              client.get_mut().local_addr().unwrap().ip(),
-                              data_port);
+    let settings = shared.settings.read().unwrap().clone();
+    let limits = shared.limits.read().unwrap().clone();
+    let (tls, ports) = (&shared.tls, &shared.ports);
+    let peer = session.peer;
+    let xferlog = TransferLog::new(shared.xferlog.clone(), peer.ip());
+    let audit = SessionAudit::new(shared.audit.clone(), session.id, peer.ip());
 
-    let mut actv_socket_addr = SocketAddrV4::new(Ipv4Addr::new(127, 0, 0, 1), 27598);
+    let mut actv_socket_addr = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(127, 0, 0, 1),
+                                                                27598));
 
-    let data_listener = TcpListener::bind(data_server.as_str()).expect("Could not open data serve");
+    //Taken by PASV or EPSV, the next transfer uses it and gives it back
+    let mut passive: Option<PassivePort> = None;
 
     let mut ftp_mode = match settings.passive {
         true => {
@@ -361,8 +538,24 @@ fn handle_client(mut client: &mut BufReader<TcpStream>,
     };
 
     let mut logged_in = false;
+    let mut ftp_type = "BINARY".to_string();
+    let mut transfer_mode = TransferMode::Stream;
+    let mut deflate_level = server::DEFAULT_DEFLATE_LEVEL;
+    let mut restart_offset: u64 = 0;
+    let mut transfer: Option<transfer::Transfer> = None;
+    let kept = transfer::KeptConnection::new();
+    let mut prot_private = false;
+    //After EPSV ALL the client only uses EPSV for data connections
+    let mut epsv_all = false;
+    //Set by RNFR for the RNTO right after it
+    let mut rename_from: Option<String> = None;
     let mut limit = settings.max_attempts.parse::<i32>().unwrap_or(3);
     let mut user = User::new();
+    //Nothing is reachable until logging in swaps in the user's root
+    let mut storage = Storage::new(Arc::new(MemoryStorage::new()));
+    let session_limits = throttle::Limits::new(settings.session_upload_rate,
+                                               settings.session_download_rate);
+    let mut throttle = throttle::Throttle::new(vec![session_limits.clone(), limits.clone()]);
 
     let msg = format!("{} {} {}\r\n",
                       server::LOGGED_EXPECTED,
@@ -370,12 +563,32 @@ fn handle_client(mut client: &mut BufReader<TcpStream>,
                       client.get_mut().local_addr().unwrap().ip());
 
 
-    server::write_response(&mut client, &msg);
+    server::write_response(&mut client, &msg).await;
+    let reply = server::take_last_reply();
+    audit.connect(reply);
+    count_reply(reply);
 
-    loop {
+    //The last command, it goes in the audit log once all its replies are out
+    let mut pending: Option<(String, String, String)> = None;
+    let mut reason = "hangup";
 
-        let mut response = String::new();
-        client.read_line(&mut response).expect("Could not read stream message");
+    loop {
+        session.update(&user.name, &user.cur_dir);
+
+        let command = transfer::read_command(&mut client,
+                                             &mut transfer,
+                                             &mut passive,
+                                             &session.hangup)
+            .await;
+        let response = match command {
+            Some(response) => response,
+            None => break,
+        };
+        if let Some((name, cmd, args)) = pending.take() {
+            let reply = server::take_last_reply();
+            audit.command(&name, &cmd, &args, reply);
+            count_reply(reply);
+        }
 
         let line = response.trim();
 
@@ -384,126 +597,348 @@ fn handle_client(mut client: &mut BufReader<TcpStream>,
             None => (line, "".as_ref()),
         };
 
-        println!("CLIENT: {} {}", cmd, args);
-        info!("CLIENT: {} {}", cmd, args);
+        pending = Some((user.name.clone(), cmd.to_string(), args.to_string()));
+        let renaming = rename_from.take();
+        METRICS.command(cmd);
+        let shown = match cmd.to_lowercase().as_ref() {
+            "pass" => "****",
+            _ => args,
+        };
+        println!("CLIENT: {} {}", cmd, shown);
+        info!("CLIENT: {} {}", cmd, shown);
+
+        //Only ABOR, STAT and NOOP are answered while a transfer is running,
+        //everything else waits for it to finish
+        match cmd.to_lowercase().as_ref() {
+            "abor" | "stat" | "noop" => {}
+            _ => transfer::finish(&mut client, &mut transfer).await,
+        }
 
+        //Security commands are valid both before and after logging in
+        match cmd.to_lowercase().as_ref() {
+            "auth" => {
+                if !server::handle_auth(&mut client, &args, tls).await {
+                    reason = "tls failed";
+                    break;
+                }
+                continue;
+            }
+            "pbsz" => {
+                server::handle_pbsz(&mut client, &args).await;
+                continue;
+            }
+            "prot" => {
+                if let Some(private) = server::handle_prot(&mut client, &args).await {
+                    prot_private = private;
+                    kept.clear().await;
+                }
+                continue;
+            }
+            _ => {}
+        }
 
         if logged_in {
             match cmd.to_lowercase().as_ref() {
+                "abor" => {
+                    transfer::abort(&mut client, &mut transfer).await;
+                }
                 "appe" => {
-                    mc::stor(&mut client, &user, ftp_mode, &args, &data_listener);
-
+                    let (user, storage, args) = (user.clone(), storage.clone(), args.to_string());
+                    let data = data_channel(ftp_mode,
+                                            peer.ip(),
+                                            &mut passive,
+                                            tls,
+                                            prot_private,
+                                            &throttle,
+                                            &xferlog,
+                                            ftp_type == "ASCII",
+                                            transfer_mode,
+                                            &kept);
+                    let offset = restart_offset;
+                    transfer = Some(start_transfer(session, line, move |replies, state| async move {
+                        mc::appe(&replies, &user, &storage, &data, &args, offset, &state).await
+                    }));
+                    restart_offset = 0;
                 }
                 "cdup" => {
-                    server::cdup(&mut client, &mut user);
+                    server::cdup(&mut client, &mut user).await;
                 }
 
                 "cwd" | "cd" => {
-                    server::cwd(&mut client, &args, &mut user);
+                    server::cwd(&mut client, &args, &mut user, &storage).await;
                 }
                 "dele" => {
-                    mc::dele(&mut client, &user, &args);
+                    mc::dele(&mut client, &user, &storage, &args).await;
                 }
                 "list" => {
-                    mc::list(&mut client,
-                             &user,
-                             ftp_mode,
-                             &args,
-                             &data_port,
-                             &data_listener);
+                    let data = data_channel(ftp_mode,
+                                            peer.ip(),
+                                            &mut passive,
+                                            tls,
+                                            prot_private,
+                                            &throttle,
+                                            &xferlog,
+                                            ftp_type == "ASCII",
+                                            transfer_mode,
+                                            &kept);
+                    mc::list(&mut client, &user, &storage, &data, &args).await;
+                }
+                "mdtm" => {
+                    mc::mdtm(&mut client, &user, &storage, &args).await;
+                }
+                "mlsd" => {
+                    let data = data_channel(ftp_mode,
+                                            peer.ip(),
+                                            &mut passive,
+                                            tls,
+                                            prot_private,
+                                            &throttle,
+                                            &xferlog,
+                                            ftp_type == "ASCII",
+                                            transfer_mode,
+                                            &kept);
+                    mc::mlsd(&mut client, &user, &storage, &data, &args).await;
+                }
+                "mlst" => {
+                    mc::mlst(&mut client, &user, &storage, &args).await;
+                }
+                "mode" => {
+                    let mode = server::handle_mode(&mut client, &args, deflate_level).await;
+                    if let Some(mode) = mode {
+                        //A MODE B connection left open is no use in another mode
+                        kept.clear().await;
+                        transfer_mode = mode;
+                    }
                 }
                 "mkd" | "mkdir" => {
-                    server::mkd(&mut client, &args, &mut user);
+                    server::mkd(&mut client, &args, &mut user, &storage).await;
+                }
+                "site" => {
+                    server::site(&mut client, &args, &user).await;
                 }
                 "noop" => {
                     server::write_response(&mut client,
                                            &format!("{} NOOP successfull\r\n",
-                                                    server::OPERATION_SUCCESS));
+                                                    server::OPERATION_SUCCESS)).await;
+                }
+                "opts" => {
+                    if let Some(level) = server::handle_opts(&mut client, &args).await {
+                        deflate_level = level;
+                        if let TransferMode::Deflate(_) = transfer_mode {
+                            transfer_mode = TransferMode::Deflate(level);
+                        }
+                    }
+                }
+                "pasv" | "port" | "eprt" if epsv_all => {
+                    server::write_response(&mut client,
+                                           &format!("{} Only EPSV is allowed after EPSV ALL\r\n",
+                                                    server::OUT_OF_SEQUENCE)).await;
                 }
                 "pasv" => {
-                    ftp_mode = FtpMode::Passive;
-                    server::handle_mode(&mut client, ftp_mode, &data_port);
-
+                    //A port from an earlier PASV nobody used goes back first,
+                    //along with a connection MODE B left open
+                    passive = None;
+                    kept.clear().await;
+                    if let Some(port) = server::passive_port(&mut client, ports).await {
+                        if server::handle_pasv(&mut client, port.port()).await {
+                            ftp_mode = FtpMode::Passive;
+                            passive = Some(port);
+                        }
+                    }
                 }
                 "port" => {
-                    actv_socket_addr = port_addr(args);
-                    ftp_mode = FtpMode::Active(actv_socket_addr);
-
-                    server::handle_mode(&mut client, ftp_mode, &data_port);
+                    if let Some(addr) = server::handle_port(&mut client, args, peer.ip()).await {
+                        actv_socket_addr = addr;
+                        ftp_mode = FtpMode::Active(actv_socket_addr);
+                        passive = None;
+                        kept.clear().await;
+                    }
+                }
+                "epsv" => {
+                    if args.eq_ignore_ascii_case("all") {
+                        epsv_all = true;
+                        server::write_response(&mut client,
+                                               &format!("{} EPSV ALL command successful\r\n",
+                                                        server::OPERATION_SUCCESS)).await;
+                    } else {
+                        passive = None;
+                        kept.clear().await;
+                        if let Some(port) = server::passive_port(&mut client, ports).await {
+                            if server::handle_epsv(&mut client, args, port.port()).await {
+                                ftp_mode = FtpMode::Passive;
+                                passive = Some(port);
+                            }
+                        }
+                    }
+                }
+                "eprt" => {
+                    if let Some(addr) = server::handle_eprt(&mut client, args, peer.ip()).await {
+                        actv_socket_addr = addr;
+                        ftp_mode = FtpMode::Active(actv_socket_addr);
+                        passive = None;
+                        kept.clear().await;
+                    }
                 }
                 "pwd" => {
-                    let shortpath = format!("{}", user.cur_dir);
-                    let pos = shortpath.find("ftproot").unwrap(); //Possible improvement here(error checking)
                     server::write_response(&mut client,
                                            &format!("{} {} is the current directory\r\n",
                                                     server::PATHNAME_AVAILABLE,
-                                                    &shortpath[pos + 7..]));
+                                                    user.cur_dir)).await;
 
                 }
+                "rest" => {
+                    restart_offset = server::handle_rest(&mut client, &args).await;
+                }
                 "retr" => {
-                    mc::retr(&mut client, &user, ftp_mode, &args, &data_listener);
+                    let (user, storage, args) = (user.clone(), storage.clone(), args.to_string());
+                    let data = data_channel(ftp_mode,
+                                            peer.ip(),
+                                            &mut passive,
+                                            tls,
+                                            prot_private,
+                                            &throttle,
+                                            &xferlog,
+                                            ftp_type == "ASCII",
+                                            transfer_mode,
+                                            &kept);
+                    let offset = restart_offset;
+                    transfer = Some(start_transfer(session, line, move |replies, state| async move {
+                        mc::retr(&replies, &user, &storage, &data, &args, offset, &state).await
+                    }));
+                    restart_offset = 0;
                 }
                 "rmd" => {
-                    mc::rmd(&mut client, &user, &args);
+                    mc::rmd(&mut client, &user, &storage, &args).await;
                 }
                 "rnfr" => {
-                    mc::rnfr(&mut client, &user, &args);
+                    rename_from = mc::rnfr(&mut client, &user, &storage, &args).await;
+                }
+                "rnto" => {
+                    mc::rnto(&mut client, &user, &storage, renaming, &args).await;
+                }
+                "size" => {
+                    mc::size(&mut client, &user, &storage, &args, &ftp_type).await;
+                }
+                "stat" => {
+                    server::stat(&mut client, &user, &ftp_type, &transfer).await;
                 }
                 "stor" => {
-                    mc::stor(&mut client, &user, ftp_mode, &args, &data_listener);
+                    let (user, storage, args) = (user.clone(), storage.clone(), args.to_string());
+                    let data = data_channel(ftp_mode,
+                                            peer.ip(),
+                                            &mut passive,
+                                            tls,
+                                            prot_private,
+                                            &throttle,
+                                            &xferlog,
+                                            ftp_type == "ASCII",
+                                            transfer_mode,
+                                            &kept);
+                    let offset = restart_offset;
+                    transfer = Some(start_transfer(session, line, move |replies, state| async move {
+                        mc::stor(&replies, &user, &storage, &data, &args, offset, &state).await
+                    }));
+                    restart_offset = 0;
                 }
                 "stou" => {
-                    mc::stou(&mut client, &user, ftp_mode, &args, &data_listener);
+                    let (user, storage, args) = (user.clone(), storage.clone(), args.to_string());
+                    let data = data_channel(ftp_mode,
+                                            peer.ip(),
+                                            &mut passive,
+                                            tls,
+                                            prot_private,
+                                            &throttle,
+                                            &xferlog,
+                                            ftp_type == "ASCII",
+                                            transfer_mode,
+                                            &kept);
+                    transfer = Some(start_transfer(session, line, move |replies, state| async move {
+                        mc::stou(&replies, &user, &storage, &data, &args, &state).await
+                    }));
+                    restart_offset = 0;
                 }
                 "type" => {
-                    server::handle_type(&mut client, &args);
+                    let new_type = server::handle_type(&mut client, &args).await;
+                    if !new_type.is_empty() {
+                        ftp_type = new_type;
+                    }
                 }
                 "quit" | "exit" | "logout" => {
                     server::write_response(&mut client,
-                                           &format!("{} GOODBYE\r\n", server::GOODBYE));
+                                           &format!("{} GOODBYE\r\n", server::GOODBYE)).await;
+                    reason = "quit";
                     break;
                 }
                 "syst" => {
                     server::write_response(&mut client,
                                            &format!("{} UNIX Type: L8\r\n",
-                                                    server::SYSTEM_RECEIVED));
+                                                    server::SYSTEM_RECEIVED)).await;
+                }
+                "feat" => {
+                    server::feat(&mut client, tls.is_some()).await;
                 }
                 "help" | "?" => {
-                    write!(client.get_mut(), "{}\r\n", COMMANDS_HELP)
+                    client.get_mut()
+                        .write_all(format!("{}\r\n", COMMANDS_HELP).as_bytes())
+                        .await
                         .expect("Could not write to client");
                 }
                 "user" => {
                     server::write_response(client,
                                            &format!("{} Badd sequence of commands\r\n",
-                                                    server::NOT_UNDERSTOOD));
+                                                    server::NOT_UNDERSTOOD)).await;
 
                 }
-                _ => server::write_response(&mut client, &format!("500 Invalid Command\r\n")),
+                _ => server::write_response(&mut client, &format!("500 Invalid Command\r\n")).await,
             }
 
         } else {
 
             match cmd.to_lowercase().as_ref() {
+                "user" if settings.tls_required && !client.get_ref().is_tls() => {
+                    server::write_response(&mut client,
+                                           &format!("{} TLS required, use AUTH TLS first\r\n",
+                                                    server::AUTHENTICATION_FAILED)).await;
+                }
+                "user" if shared.sessions.paused() => {
+                    server::write_response(&mut client,
+                                           &format!("{} Logins are paused, try again later\r\n",
+                                                    server::SERVICE_NOT_AVAILABLE)).await;
+                }
                 "user" => {
-                    match server::handle_user(&mut client, &args, &map) {
-                        true => {
+                    //Whatever the User DB was when they log in
+                    let map = shared.users.read().unwrap().clone();
+                    let result = server::handle_user(&mut client, &args, &map, &session.hangup)
+                        .await;
+                    audit.login(args, result);
+                    METRICS.login(result);
+                    match result {
+                        Ok(_) => {
                             logged_in = true;
                             user = map.get(args).unwrap().clone();
+                            storage = Storage::new(Arc::new(LocalDisk::new(&user.path)));
+                            throttle = throttle::Throttle::new(vec![session_limits.clone(),
+                                                                    user.limits.clone(),
+                                                                    limits.clone()]);
                         }
-                        false => {
+                        Err(_) => {
                             logged_in = false;
                             limit -= 1;
                             if limit <= 0 {
                                 info!("{} reached logged limit", args);
+                                reason = "too many failed logins";
                                 break;
                             }
                         }
                     }
                 }
+                "feat" => {
+                    server::feat(&mut client, tls.is_some()).await;
+                }
                 _ => {
                     server::write_response(&mut client,
                                            &format!("{} Not Logged In\r\n",
-                                                    server::AUTHENTICATION_FAILED))
+                                                    server::AUTHENTICATION_FAILED)).await
                 }
 
             }
@@ -511,11 +946,150 @@ fn handle_client(mut client: &mut BufReader<TcpStream>,
 
     }
 
-    client.get_mut().shutdown(Shutdown::Both).expect("couldn't close server");
-    println!("Client {} has closed connection", data_port - 27500);
-    info!("Client {} has closed connection", data_port - 27500);
+    transfer::finish(&mut client, &mut transfer).await;
+    kept.clear().await;
+    if let Some((name, cmd, args)) = pending.take() {
+        let reply = server::take_last_reply();
+        audit.command(&name, &cmd, &args, reply);
+        count_reply(reply);
+    }
+
+    if let Some(kicked) = session.kicked() {
+        server::write_response(&mut client,
+                               &format!("{} {}\r\n", server::SERVICE_NOT_AVAILABLE, kicked)).await;
+        reason = "kicked";
+    } else if shared.sessions.stopping() {
+        server::write_response(&mut client,
+                               &format!("{} Server is shutting down\r\n",
+                                        server::SERVICE_NOT_AVAILABLE)).await;
+        reason = "shutdown";
+    }
+    audit.disconnect(&user.name, reason);
+    let _ = client.get_mut().shutdown().await;
+    println!("Client {} has closed connection", peer);
+    info!("Client {} has closed connection", peer);
+}
+
+fn count_reply(reply: Option<u32>) {
+    if let Some(code) = reply {
+        METRICS.reply(code);
+    }
 }
 
+/// Reads fsys.cfg and the User DB again. Sessions that start afterwards get
+/// the new welcome message, login attempts, TLS requirement, rates and grace
+/// period; addresses, ports, MAX_USERS and FTP_ROOT need a restart. Sessions
+/// of users that are now blocked or gone are closed. Returns how many users
+/// were loaded, on errors nothing changes
+fn reload(shared: &Shared) -> Result<usize, String> {
+    let conf = Ini::load_from_file(FSYS_CONFIG)
+        .map_err(|e| format!("Could not read {}: {}", FSYS_CONFIG, e))?;
+    let mut fresh = Settings::new();
+    load_defaults(&mut fresh, &conf)?;
+
+    //What was given on the command line stays as it is
+    let mut settings = (**shared.settings.read().unwrap()).clone();
+    settings.welcome = fresh.welcome;
+    settings.max_attempts = fresh.max_attempts;
+    settings.tls_required = fresh.tls_required;
+    settings.session_upload_rate = fresh.session_upload_rate;
+    settings.session_download_rate = fresh.session_download_rate;
+    settings.shutdown_grace = fresh.shutdown_grace;
+
+    let mut users = get_user_list(&settings)?;
+    let count = users.len();
+    carry_over(&mut users, &shared.users.read().unwrap());
+
+    //New buckets would let the old and new sessions have twice the rate,
+    //so the server wide limits are only replaced when they change
+    if (settings.upload_rate, settings.download_rate) !=
+       (fresh.upload_rate, fresh.download_rate) {
+        settings.upload_rate = fresh.upload_rate;
+        settings.download_rate = fresh.download_rate;
+        *shared.limits.write().unwrap() = throttle::Limits::new(settings.upload_rate,
+                                                                 settings.download_rate);
+    }
+
+    *shared.settings.write().unwrap() = Arc::new(settings);
+    *shared.users.write().unwrap() = Arc::new(users.clone());
+    info!("Reloaded {} and {} users", FSYS_CONFIG, count);
+
+    for session in shared.sessions.list() {
+        let name = session.user();
+        if name.is_empty() {
+            continue;
+        }
+
+        let allowed = match users.get(&name) {
+            Some(user) => user.role != "blocked" && user.role != "notallowed",
+            None => false,
+        };
+        if !allowed {
+            info!("Closing session {}, {} is no longer allowed in", session.id, name);
+            session.kick("Your account has been disabled");
+        }
+    }
+
+    Ok(count)
+}
+
+//Runs a transfer as a task of its own and lets the admin port know about it
+fn start_transfer<F, R>(session: &Session, command: &str, work: F) -> transfer::Transfer
+    where F: FnOnce(UnboundedSender<String>, transfer::TransferState) -> R,
+          R: Future<Output = ()> + Send + 'static
+{
+    let running = transfer::start(command, work);
+    session.start_transfer(command, &running.state);
+    running
+}
+
+//Builds the data connection settings for the next transfer
+fn data_channel(mode: FtpMode,
+                peer: IpAddr,
+                passive: &mut Option<PassivePort>,
+                tls: &Option<Arc<ServerConfig>>,
+                prot_private: bool,
+                throttle: &throttle::Throttle,
+                xferlog: &TransferLog,
+                ascii: bool,
+                transfer_mode: TransferMode,
+                kept: &transfer::KeptConnection)
+                -> transfer::DataChannel {
+    transfer::DataChannel {
+        mode: mode,
+        peer: peer,
+        //A passive port serves a single transfer
+        passive: passive.take(),
+        tls: match prot_private {
+            true => tls.clone(),
+            false => None,
+        },
+        throttle: throttle.clone(),
+        xferlog: xferlog.clone(),
+        ascii: ascii,
+        transfer_mode: transfer_mode,
+        kept: kept.clone(),
+    }
+}
+
+//Loads the FTPS certificate if one is configured
+fn load_tls(settings: &Settings) -> Option<Arc<ServerConfig>> {
+    if settings.tls_cert.is_empty() || settings.tls_key.is_empty() {
+        return None;
+    }
+
+    match stream::tls_config(&settings.tls_cert, &settings.tls_key) {
+        Ok(config) => {
+            info!("Loaded TLS certificate {}", settings.tls_cert);
+            Some(config)
+        }
+        Err(e) => {
+            println!("Could not load TLS certificate {}: {}", settings.tls_cert, e);
+            info!("Could not load TLS certificate {}: {}", settings.tls_cert, e);
+            None
+        }
+    }
+}
 
 /// # Users are initialized here
 ///
@@ -567,12 +1141,44 @@ fn initialize_user(name: &str, pass: &str, role: &str, root: &str) -> User {
     user.name = format!("{}", name).to_string();
     user.pass = format!("{}", pass).to_string();
     user.role = format!("{}", role).to_string();
-    user.cur_dir = format!("{}", user.path).to_string();
+    user.cur_dir = "/".to_string();
 
     return user;
 }
 
 
+/// Parses the LISTEN setting, a list of addresses separated by commas or
+/// spaces. Each one is an IP with an optional port (`0.0.0.0`, `[::]:2121`,
+/// `127.0.0.1:2115`), those without one get `default_port`
+fn listen_addrs(list: &str, default_port: &str) -> Result<Vec<SocketAddr>, String> {
+    let port = default_port.trim()
+        .parse::<u16>()
+        .map_err(|_| format!("Bad port {}", default_port))?;
+    let mut addrs = Vec::new();
+
+    for entry in list.split(|c: char| c == ',' || c.is_whitespace()).filter(|e| !e.is_empty()) {
+        let addr = match (entry.parse::<SocketAddr>(), entry.parse::<IpAddr>()) {
+            (Ok(addr), _) => addr,
+            (_, Ok(ip)) => SocketAddr::new(ip, port),
+            _ => {
+                //[::1] without a port
+                match entry.trim_matches(|c| c == '[' || c == ']').parse::<IpAddr>() {
+                    Ok(ip) => SocketAddr::new(ip, port),
+                    Err(_) => return Err(format!("Bad listen address {}", entry)),
+                }
+            }
+        };
+        if !addrs.contains(&addr) {
+            addrs.push(addr);
+        }
+    }
+
+    match addrs.is_empty() {
+        true => Err("No listen address given".to_string()),
+        false => Ok(addrs),
+    }
+}
+
 //takes the command line argument in the form of 1-5 and returns array of ports
 fn get_data_ports(ports: String) -> Vec<i32> {
     //Split the range in order to have an array of ports to issue
@@ -592,18 +1198,22 @@ fn get_data_ports(ports: String) -> Vec<i32> {
 
 }
 
-fn get_user_list(settings: &Settings) -> HashMap<String, user::User> {
+/// Reads the User DB. Errors say which entry is wrong, so a reload can
+/// keep the users it has
+fn get_user_list(settings: &Settings) -> Result<HashMap<String, user::User>, String> {
 
     let mut map: HashMap<String, user::User> = HashMap::new();
 
     let user_list = format!("{}", settings.users_path);
-    let f = File::open(user_list).unwrap_or(File::open("conf/users.cfg").unwrap());
-    let file = BufReader::new(f);
+    let f = File::open(&user_list)
+        .or_else(|_| File::open("conf/users.cfg"))
+        .map_err(|e| format!("Could not open {}: {}", user_list, e))?;
+    let file = io::BufReader::new(f);
     // let mut users: Vec<&str> = Vec::new(); //May still user as alternative
     let mut user = user::User::new();
 
     for line in file.lines() {
-        let line = line.expect("Could not read line");
+        let line = line.map_err(|e| format!("Could not read {}: {}", user_list, e))?;
         let things = match line.find('#') {
             Some(pos) => (line[0..pos].to_string()),
             None => line,
@@ -615,27 +1225,81 @@ fn get_user_list(settings: &Settings) -> HashMap<String, user::User> {
 
         let split = things.split(' ');
         let tokens: Vec<&str> = split.collect();
+        if tokens.len() < 3 {
+            return Err(format!("{} needs a name, password and role", things));
+        }
         user = initialize_user(&tokens[0].to_string(),
                                &tokens[1].to_string(),
                                &tokens[2].to_string(),
                                &settings.ftp_root);
         let name = tokens[0].to_string();
+
+        //Anything after the role limits what the user can do and store
+        let (quota_specs, other_specs): (Vec<&str>, Vec<&str>) = tokens[3..]
+            .iter()
+            .cloned()
+            .filter(|t| !t.is_empty())
+            .partition(|t| t.starts_with("quota"));
+        let (rate_specs, perm_specs): (Vec<&str>, Vec<&str>) = other_specs.into_iter()
+            .partition(|t| t.starts_with("upload_rate") || t.starts_with("download_rate"));
+        user.perms = match user::Permissions::parse(&perm_specs) {
+            Ok(perms) => perms,
+            Err(e) => return Err(format!("Bad permissions for {}: {}", name, e)),
+        };
+        user.quota = match quota::Quota::parse(&quota_specs) {
+            Ok(quota) => quota,
+            Err(e) => return Err(format!("Bad quota for {}: {}", name, e)),
+        };
+        user.limits = match throttle::Limits::parse(&rate_specs) {
+            Ok(limits) => limits,
+            Err(e) => return Err(format!("Bad rate limit for {}: {}", name, e)),
+        };
+        user.quota.scan(&LocalDisk::new(&user.path));
         info!("name: {}, role {}", name, tokens[2]);
+        if !password::is_hashed(tokens[1]) {
+            info!("{} has a cleartext password, use --hash-password to make a hash", name);
+        }
         map.insert(name, user);
     }
 
-    map
+    Ok(map)
 
 }
 
-//Converts port command arguements into a socket address
-fn port_addr(args: &str) -> SocketAddrV4 {
-    let nums: Vec<u8> = args.split(',').map(|x| x.parse::<u8>().unwrap()).collect();
-    let ip = Ipv4Addr::new(nums[0], nums[1], nums[2], nums[3]);
-    let port = server::to_ftp_port(nums[4] as u16, nums[5] as u16);
-    let addr = SocketAddrV4::new(ip, port);
-    addr
+//Prompts for a password and prints the entry that goes in the User DB
+fn print_password_hash() {
+    //The prompt goes to stderr so only the hash ends up in a redirect
+    eprint!("Password: ");
+    io::stderr().flush().expect("Could not flush stderr");
+
+    let mut pass = String::new();
+    io::stdin().read_line(&mut pass).expect("Could not read password");
+    let pass = pass.trim_right_matches(|c| c == '\r' || c == '\n');
+    if pass.is_empty() {
+        println!("No password given");
+        process::exit(1);
+    }
 
+    println!("{}", password::hash(pass));
+}
+
+/// Hands the quota usage and rate buckets of users that are still there
+/// over to their new entries. Sessions of a user share them, so they must
+/// outlive a reload for uploads and transfers already running to count
+fn carry_over(users: &mut HashMap<String, user::User>, old: &HashMap<String, user::User>) {
+    for (name, user) in users.iter_mut() {
+        let before = match old.get(name) {
+            Some(before) => before,
+            None => continue,
+        };
+
+        if user.path == before.path {
+            user.quota.share_usage(&before.quota);
+        }
+        if user.limits.rates() == before.limits.rates() {
+            user.limits = before.limits.clone();
+        }
+    }
 }
 
 //create ftproot folder if it does not exist
@@ -647,10 +1311,19 @@ fn create_root(settings: &Settings) {
     }
 }
 
-fn load_defaults(settings: &mut Settings, conf: &Ini) {
+//Reads a rate from fsys.cfg, a size per second that takes a K, M or G suffix
+fn rate_setting(value: Option<&String>) -> Result<u64, String> {
+    match value {
+        Some(value) => quota::parse_size(value.trim()),
+        None => Ok(0),
+    }
+}
+
+fn load_defaults(settings: &mut Settings, conf: &Ini) -> Result<(), String> {
 
     info!("Loading defaults from Setting File");
-    let defaults = conf.section(Some("default".to_owned())).unwrap();
+    let defaults = conf.section(Some("default".to_owned()))
+        .ok_or(format!("{} has no [default] section", FSYS_CONFIG))?;
 
     settings.ftp_port = format!("{}",
                                 defaults.get("DATA_PORT_FTP_SERVER")
@@ -660,6 +1333,11 @@ fn load_defaults(settings: &mut Settings, conf: &Ini) {
                                     defaults.get("SERVICE_PORT")
                                         .unwrap_or(&settings.service_port));
 
+    settings.listen = format!("{}", defaults.get("LISTEN").unwrap_or(&settings.listen));
+    settings.service_listen = format!("{}",
+                                      defaults.get("SERVICE_LISTEN")
+                                          .unwrap_or(&settings.service_listen));
+
     settings.ftp_root = format!("{}", defaults.get("FTP_ROOT").unwrap_or(&settings.ftp_root));
     settings.users_path = format!("{}",
                                   defaults.get("USER_DATA_FILE").unwrap_or(&settings.users_path));
@@ -674,6 +1352,12 @@ fn load_defaults(settings: &mut Settings, conf: &Ini) {
                                            .unwrap_or(&"2799".to_string()));
 
     settings.log_file = format!("{}", defaults.get("FTP_LOG").unwrap_or(&settings.log_file));
+    settings.xfer_log = format!("{}", defaults.get("XFER_LOG").unwrap_or(&settings.xfer_log));
+    settings.audit_log = format!("{}",
+                                 defaults.get("AUDIT_LOG").unwrap_or(&settings.audit_log));
+    settings.metrics_listen = format!("{}",
+                                      defaults.get("METRICS_LISTEN")
+                                          .unwrap_or(&settings.metrics_listen));
     settings.max_users = format!("{}",
                                  defaults.get("MAX_USERS").unwrap_or(&settings.max_users));
     settings.max_attempts = format!("{}",
@@ -682,6 +1366,21 @@ fn load_defaults(settings: &mut Settings, conf: &Ini) {
     settings.ftp_mode = format!("{}",
                                 defaults.get("FTP_MODE").unwrap_or(&"PASSIVE".to_string()));
 
+    settings.tls_cert = format!("{}", defaults.get("TLS_CERT_FILE").unwrap_or(&settings.tls_cert));
+    settings.tls_key = format!("{}", defaults.get("TLS_KEY_FILE").unwrap_or(&settings.tls_key));
+    settings.tls_required = match defaults.get("TLS_REQUIRED") {
+        Some(value) => value == "1" || value.to_lowercase() == "true",
+        None => false,
+    };
+
+    settings.upload_rate = rate_setting(defaults.get("UPLOAD_RATE"))?;
+    settings.download_rate = rate_setting(defaults.get("DOWNLOAD_RATE"))?;
+    settings.session_upload_rate = rate_setting(defaults.get("SESSION_UPLOAD_RATE"))?;
+    settings.session_download_rate = rate_setting(defaults.get("SESSION_DOWNLOAD_RATE"))?;
+    settings.shutdown_grace = defaults.get("SHUTDOWN_GRACE")
+        .and_then(|grace| grace.trim().parse::<u64>().ok())
+        .unwrap_or(settings.shutdown_grace);
+
     match settings.ftp_mode.to_lowercase().as_ref() {
         "passive" => {
             settings.passive = true;
@@ -691,6 +1390,7 @@ fn load_defaults(settings: &mut Settings, conf: &Ini) {
         }
     }
 
+    Ok(())
 }
 
 
@@ -711,10 +1411,12 @@ const COMMANDS_HELP: &'static str =
 214-        stor - Stores a file\r\n
 214-        stou - Stores a file uniquely\r\n
 214-        appe - Appends to a file\r\n
+214-        rest - Sets the restart offset of the next transfer\r\n
 214-        type - Stes tranfer type to Active or Passive\r\n
 214-        rnrf - Rename From\r\n
 214-        rnto - Rename To\r\n
 214-        abor - Aborts a transfer\r\n
+214-        stat - Prints server or transfer status\r\n
 214-        dele - Deletes a file\r\n
 214-        rmd - Removes a directory\r\n
 214-        mkd - Makes a directory\r\n
@@ -723,7 +1425,19 @@ const COMMANDS_HELP: &'static str =
 214-        noop - Does nothing\r\n
 214-        help - Prints Help Menu\r\n
 214-        size - Prints size of file\r\n
-214-        nlist - Name list of direcotry\r\n
+214-        mdtm - Prints modification time of file\r\n
+214-        mlsd - Machine readable directory listing\r\n
+214-        mlst - Machine readable file facts\r\n
+214-        feat - Lists supported extensions\r\n
+214-        mode - Sets the transfer mode, S for stream, B for block or Z for deflate\r\n
+214-        opts - OPTS MODE Z LEVEL sets the compression level\r\n
+214-        epsv - Extended passive mode, IPv4 or IPv6\r\n
+214-        eprt - Extended active mode, IPv4 or IPv6\r\n
+214-        auth - Upgrades the connection to TLS\r\n
+214-        pbsz - Sets the protection buffer size\r\n
+214-        prot - Sets the data channel protection level\r\n
+214-        site - SITE QUOTA shows the disk quota\r\n
+        nlist - Name list of direcotry\r\n
 214 \r\n     
 ";
 
@@ -731,12 +1445,12 @@ struct MyFormat;
 
 impl slog_stream::Format for MyFormat {
     fn format(&self,
-              io: &mut io::Write,
+              io: &mut dyn io::Write,
               rinfo: &slog::Record,
               _logger_values: &slog::OwnedKeyValueList)
               -> io::Result<()> {
         let msg = format!("{} - {}\n", rinfo.level(), rinfo.msg());
-        let _ = try!(io.write_all(msg.as_bytes()));
+        let _ = io.write_all(msg.as_bytes())?;
         Ok(())
     }
 }
@@ -1,17 +1,21 @@
 use rand::Rng;
 use rand;
-use std::fs::OpenOptions;
-use std::io::BufReader;
+use std::io::{self, Read};
 use std::string::String;
-use std::net::{TcpStream, TcpListener, Shutdown};
-use std::path::Path;
-use std::fs;
-use std::fs::File;
+use std::time::Instant;
+use tokio::io::BufReader;
+use tokio::sync::mpsc::UnboundedSender;
 
 
-use user::User;
-use server::FtpMode;
-use server;
+use crate::ascii::{self, FromCrlf, ToCrlf};
+use crate::user::{Capability, User};
+use crate::server;
+use crate::stream::FtpStream;
+use crate::storage::{Blocking, Storage};
+use crate::quota;
+use crate::metrics::METRICS;
+use crate::transfer::{self, DataChannel, TransferState};
+use crate::xferlog::Direction;
 
 /// # The FTP List command
 /// This function implements the list command server side
@@ -20,389 +24,832 @@ use server;
 ///
 /// - client
 /// - user
-/// - mode
+/// - storage
+/// - data
 /// - args
-/// - data_port
-/// - listener
-pub fn list(client: &mut BufReader<TcpStream>,
-            user: &User,
-            mode: FtpMode,
-            args: &str,
-            data_port: &i32,
-            listener: &TcpListener) {
-
-
-
-    match mode {
-        FtpMode::Passive => {
+pub async fn list(client: &mut BufReader<FtpStream>,
+                  user: &User,
+                  storage: &Storage,
+                  data: &DataChannel,
+                  args: &str) {
+
+    let path = server::resolve_path(user, args);
+    if !server::permitted(client, user, Capability::List, &path).await {
+        return;
+    }
+    if storage.stat(&path).await.is_err() {
+        server::write_response(client,
+                               &format!("{} No Such File or Dir\r\n", server::NO_ACCESS)).await;
+        return;
+    }
 
-            info!("{} in passive mode requesting LIST command", user.name);
-            //getting a head start here in order to prvent slow connection
-            let (stream, _) = listener.accept().expect("Could not accept connection");
+    info!("{} in {:?} mode requesting LIST command", user.name, data.mode);
+    //getting a head start here in order to prvent slow connection
+    let state = TransferState::new();
+    match data.open(&state).await {
+        Some(mut data_stream) => {
             server::write_response(client,
                                    &format!("{} Openning ASCII mode data for file list\r\n",
-                                            server::OPENNING_DATA_CONNECTION));
-
-            let mut data_stream = stream;
-            server::ftp_ls(&user, &mut data_stream, args);
-            server::write_response(client,
-                                   &format!("{} Transfer Complete\r\n",
-                                            server::CLOSING_DATA_CONNECTION));
-            data_stream.shutdown(Shutdown::Both).expect("Could not shutdownd data stram");
-
+                                            server::OPENNING_DATA_CONNECTION)).await;
+
+            let entries = storage.list(&path).await.unwrap_or_default();
+            let result = server::write_encoded(&mut data_stream, data.transfer_mode, |stream| {
+                server::ftp_ls(&entries, stream, &path);
+                Ok(())
+            }).await;
+            let keep = data.keeps_open(&result, &state);
+            data.close(data_stream, keep, &state).await;
+            listing_complete(client, keep).await;
         }
-
-        FtpMode::Active(addr) => {
-            info!("{} in passive mode requesting LIST command", user.name);
-            server::write_response(client,
-                                   &format!("{} Openning ASCII mode data for file list\r\n",
-                                            server::OPENNING_DATA_CONNECTION));
-            let mut stream = TcpStream::connect(addr).expect("Could not connect to addr");
-
-
-            server::ftp_ls(&user, &mut stream, args);
+        None => {
             server::write_response(client,
-                                   &format!("{} Transfer Complete\r\n",
-                                            server::CLOSING_DATA_CONNECTION));
-
+                                   &format!("{} Can't open data connection\r\n",
+                                            server::CANT_OPEN_DATA)).await;
         }
     }
-
-
 }
 
-
-pub fn stor(mut client: &mut BufReader<TcpStream>,
-            user: &User,
-            mode: FtpMode,
-            args: &str,
-            listener: &TcpListener) {
-
-
-    match mode {
-        FtpMode::Passive => {
-            info!("{} in passive mode requesting STOR command", user.name);
-            let (stream, _) = listener.accept().expect("Could not accept connection");
-            let mut data_stream = stream;
-
-            stor_file(&mut client, user, &mut data_stream, args);
-
-            data_stream.shutdown(Shutdown::Both).expect("Could not shutdownd data stram");
-
+/// # The FTP MLSD command
+/// Sends a machine readable listing of a directory over the data
+/// connection (RFC 3659)
+///
+/// # Arguements
+///
+/// - client
+/// - user
+/// - storage
+/// - data
+/// - args
+pub async fn mlsd(client: &mut BufReader<FtpStream>,
+                  user: &User,
+                  storage: &Storage,
+                  data: &DataChannel,
+                  args: &str) {
+
+    let path = server::resolve_path(user, args);
+    if !server::permitted(client, user, Capability::List, &path).await {
+        return;
+    }
+    match storage.stat(&path).await {
+        Ok(ref meta) if meta.is_dir => {}
+        _ => {
+            server::write_response(client,
+                                   &format!("{} {} is not a directory\r\n",
+                                            server::NO_ACCESS,
+                                            args)).await;
+            return;
         }
+    }
 
-        FtpMode::Active(addr) => {
-
-            info!("{} in active mode requesting STOR command", user.name);
-            let mut data_stream = TcpStream::connect(addr).expect("Could not connect to addr");
-            stor_file(&mut client, user, &mut data_stream, args);
-            data_stream.shutdown(Shutdown::Both).expect("Could not shutdownd data stram");
-
+    info!("{} in {:?} mode requesting MLSD command", user.name, data.mode);
+    let state = TransferState::new();
+    match data.open(&state).await {
+        Some(mut data_stream) => {
+            server::write_response(client,
+                                   &format!("{} Openning ASCII mode data for MLSD\r\n",
+                                            server::OPENNING_DATA_CONNECTION)).await;
+
+            let entries = storage.list(&path).await.unwrap_or_default();
+            let result = server::write_encoded(&mut data_stream, data.transfer_mode, |stream| {
+                server::ftp_mlsd(&entries, user, stream, &path);
+                Ok(())
+            }).await;
+            let keep = data.keeps_open(&result, &state);
+            data.close(data_stream, keep, &state).await;
+            listing_complete(client, keep).await;
+        }
+        None => {
+            server::write_response(client,
+                                   &format!("{} Can't open data connection\r\n",
+                                            server::CANT_OPEN_DATA)).await;
         }
     }
-
 }
 
-pub fn retr(mut client: &mut BufReader<TcpStream>,
-            user: &User,
-            mode: FtpMode,
-            args: &str,
-            listener: &TcpListener) {
-
-    //getting a head start here in order to prvent slow connection
-
-    match mode {
-        FtpMode::Passive => {
-
-            info!("{} in passive mode requesting RETR command", user.name);
-            let (stream, _) = listener.accept().expect("Could not accept connection");
-            let mut data_stream = stream;
-
-            retr_file(&mut client, user, &mut data_stream, args);
-            data_stream.shutdown(Shutdown::Both).expect("Could not shutdownd data stram");
+/// # The FTP MLST command
+/// Replies with the facts of a single file or directory on the control
+/// connection (RFC 3659)
+///
+/// # Arguements
+///
+/// - client
+/// - user
+/// - storage
+/// - args
+pub async fn mlst(client: &mut BufReader<FtpStream>,
+                  user: &User,
+                  storage: &Storage,
+                  args: &str) {
+    let remote = server::resolve_path(user, args);
+    if !server::permitted(client, user, Capability::List, &remote).await {
+        return;
+    }
 
+    match storage.stat(&remote).await {
+        Ok(meta) => {
+            server::write_response(client,
+                                   &format!("{}-Listing {}\r\n {} {}\r\n{} End\r\n",
+                                            server::CWD_CONFIRMED,
+                                            args,
+                                            server::mlst_facts(&meta, user, &remote),
+                                            remote,
+                                            server::CWD_CONFIRMED)).await;
         }
-
-        FtpMode::Active(addr) => {
-            info!("{} in active mode requesting RETR command", user.name);
-            let mut data_stream = TcpStream::connect(addr).expect("Could not connect to addr");
-            retr_file(&mut client, user, &mut data_stream, args);
-            data_stream.shutdown(Shutdown::Both).expect("Could not shutdownd data stram");
-
+        _ => {
+            server::write_response(client,
+                                   &format!("{} No Such File or Dir\r\n", server::NO_ACCESS)).await;
         }
     }
-
-
 }
 
 
-pub fn stou(mut client: &mut BufReader<TcpStream>,
-            user: &User,
-            mode: FtpMode,
-            args: &str,
-            listener: &TcpListener) {
-
-    //This is in case the file name is not unique
-    let mut rng = rand::thread_rng();
-
-    let full_path = format!("{}/{}", user.cur_dir, args);
-    let s = rng.gen_ascii_chars().take(8).collect::<String>();
-
-    let remote = Path::new(&full_path);
-
-    match mode {
-
-        FtpMode::Passive => {
-
-            info!("{} in passive mode requesting STOU command", user.name);
-            let (stream, _) = listener.accept().expect("Could not accept connection");
-
-            let mut data_stream = stream;
-
-            if remote.exists() {
-                stor_file(&mut client, user, &mut data_stream, &s);
-            } else {
-                stor_file(&mut client, user, &mut data_stream, args);
-            }
-
-            data_stream.shutdown(Shutdown::Both).expect("Could not shutdownd data stream");
-
+/// # The FTP STOR command
+/// Runs on the transfer thread, receiving a file over the data connection
+///
+/// # Arguements
+///
+/// - replies
+/// - user
+/// - storage
+/// - data
+/// - args
+/// - offset
+/// - state
+pub async fn stor(replies: &UnboundedSender<String>,
+                  user: &User,
+                  storage: &Storage,
+                  data: &DataChannel,
+                  args: &str,
+                  offset: u64,
+                  state: &TransferState) {
+
+    info!("{} in {:?} mode requesting STOR command", user.name, data.mode);
+    match data.open(state).await {
+        Some(mut data_stream) => {
+            let keep = transfer_permitted(replies, user, Capability::Write, args) &&
+                       stor_file(replies,
+                                 user,
+                                 storage,
+                                 &mut data_stream,
+                                 data,
+                                 args,
+                                 offset,
+                                 state).await;
+            data.close(data_stream, keep, state).await;
         }
+        None => no_data_connection(replies),
+    }
+}
 
-        FtpMode::Active(addr) => {
-
-            info!("{} in active mode requesting STOU command", user.name);
-            let mut data_stream = TcpStream::connect(addr).expect("Could not connect to addr");
-            if remote.exists() {
-                stor_file(&mut client, user, &mut data_stream, &s);
-            } else {
-                stor_file(&mut client, user, &mut data_stream, args);
-            }
-
-            data_stream.shutdown(Shutdown::Both).expect("Could not shutdownd data stream");
-
+/// # The FTP RETR command
+/// Runs on the transfer thread, sending a file over the data connection
+///
+/// # Arguements
+///
+/// - replies
+/// - user
+/// - storage
+/// - data
+/// - args
+/// - offset
+/// - state
+pub async fn retr(replies: &UnboundedSender<String>,
+                  user: &User,
+                  storage: &Storage,
+                  data: &DataChannel,
+                  args: &str,
+                  offset: u64,
+                  state: &TransferState) {
+
+    info!("{} in {:?} mode requesting RETR command", user.name, data.mode);
+    match data.open(state).await {
+        Some(mut data_stream) => {
+            let keep = transfer_permitted(replies, user, Capability::Read, args) &&
+                       retr_file(replies,
+                                 user,
+                                 storage,
+                                 &mut data_stream,
+                                 data,
+                                 args,
+                                 offset,
+                                 state).await;
+            data.close(data_stream, keep, state).await;
         }
+        None => no_data_connection(replies),
     }
-
 }
 
-pub fn appe(client: &mut BufReader<TcpStream>,
-            user: &User,
-            mode: FtpMode,
-            args: &str,
-            listener: &TcpListener) {
-
-
-    match mode {
-        FtpMode::Passive => {
-
-            //Waits for clinet to connect to data port
-            let (stream, _) = listener.accept().expect("Could not accept connection");
-
-            let mut data_stream = stream;
-            let full_path = format!("{}/{}", user.cur_dir, args);
-            let remote = Path::new(&full_path);
-
-
-            if !remote.is_dir() {
-
-                let mut file = match OpenOptions::new().append(true).open(remote) {
-                    Ok(file) => file,
-                    Err(_) => {
-                        let file = File::create(remote)
-                            .expect("Could not create remote file for append");
-                        file
-                    }
-                };
-
-
-                server::write_to_file(&mut file, &mut data_stream);
+/// # The FTP SIZE command
+/// Replies with the number of bytes a RETR of the file would transfer
+/// under the current TYPE (RFC 3659)
+///
+/// # Arguements
+///
+/// - client
+/// - user
+/// - storage
+/// - args
+/// - ftp_type
+pub async fn size(client: &mut BufReader<FtpStream>,
+                  user: &User,
+                  storage: &Storage,
+                  args: &str,
+                  ftp_type: &str) {
+    let local = server::resolve_path(user, args);
+    if !server::permitted(client, user, Capability::List, &local).await {
+        return;
+    }
 
-                //TODO: Add how long it took to transfer file
+    info!("{} requesting SIZE of {}", user.name, args);
+    if storage.stat(&local).await.map(|meta| !meta.is_dir).unwrap_or(false) {
+        let (path, ftp_type) = (local.clone(), ftp_type.to_string());
+        match storage.run(move |backend| server::transfer_size(backend, &path, &ftp_type)).await {
+            Ok(size) => {
                 server::write_response(client,
-                                       &format!("{} Transfer Complete\r\n",
-                                                server::CLOSING_DATA_CONNECTION));
-
-            } else {
+                                       &format!("{} {}\r\n", server::FILE_STATUS, size)).await;
+            }
+            Err(_) => {
                 server::write_response(client,
-                                       &format!("{} No Such File or Dir\r\n", server::NO_ACCESS));
+                                       &format!("{} Could not read file size\r\n",
+                                                server::NO_ACCESS)).await;
             }
+        }
+    } else {
+        server::write_response(client,
+                               &format!("{} No Such File or Dir\r\n", server::NO_ACCESS)).await;
+    }
+}
 
-            data_stream.shutdown(Shutdown::Both).expect("Could not shutdownd data stram");
+/// # The FTP MDTM command
+/// Replies with the last modification time of a file as a YYYYMMDDHHMMSS
+/// UTC timestamp (RFC 3659)
+///
+/// # Arguements
+///
+/// - client
+/// - user
+/// - storage
+/// - args
+pub async fn mdtm(client: &mut BufReader<FtpStream>,
+                  user: &User,
+                  storage: &Storage,
+                  args: &str) {
+    let local = server::resolve_path(user, args);
+    if !server::permitted(client, user, Capability::List, &local).await {
+        return;
+    }
 
+    info!("{} requesting MDTM of {}", user.name, args);
+    match storage.stat(&local).await {
+        Ok(ref meta) if !meta.is_dir => {
+            server::write_response(client,
+                                   &format!("{} {}\r\n",
+                                            server::FILE_STATUS,
+                                            server::mdtm_timestamp(meta.modified))).await;
+        }
+        _ => {
+            server::write_response(client,
+                                   &format!("{} No Such File or Dir\r\n", server::NO_ACCESS)).await;
+        }
+    }
+}
 
 
+/// # The FTP STOU command
+/// Like STOR, but a name that is already taken gets replaced by a random one
+pub async fn stou(replies: &UnboundedSender<String>,
+                  user: &User,
+                  storage: &Storage,
+                  data: &DataChannel,
+                  args: &str,
+                  state: &TransferState) {
 
+    //This is in case the file name is not unique
+    let s = rand::thread_rng().gen_ascii_chars().take(8).collect::<String>();
+
+    let taken = storage.stat(&server::resolve_path(user, args)).await.is_ok();
+    let name = match taken {
+        true => &s,
+        false => args,
+    };
+
+    info!("{} in {:?} mode requesting STOU command", user.name, data.mode);
+    match data.open(state).await {
+        Some(mut data_stream) => {
+            let keep = transfer_permitted(replies, user, Capability::Write, name) &&
+                       stor_file(replies,
+                                 user,
+                                 storage,
+                                 &mut data_stream,
+                                 data,
+                                 name,
+                                 0,
+                                 state).await;
+            data.close(data_stream, keep, state).await;
         }
+        None => no_data_connection(replies),
+    }
+}
 
-        FtpMode::Active(_) => {
-            println!("mode not yet implemented");
-
+/// # The FTP APPE command
+/// Appends the incoming data to a file, creating it when it doesn't exist.
+/// After a REST the data is written from the restart offset instead
+pub async fn appe(replies: &UnboundedSender<String>,
+                  user: &User,
+                  storage: &Storage,
+                  data: &DataChannel,
+                  args: &str,
+                  offset: u64,
+                  state: &TransferState) {
+
+    info!("{} in {:?} mode requesting APPE command", user.name, data.mode);
+    //Writing from a restart offset overwrites, so it needs more than append
+    let cap = match offset > 0 {
+        true => Capability::Write,
+        false => Capability::Append,
+    };
+    match data.open(state).await {
+        Some(mut data_stream) => {
+            let keep = match transfer_permitted(replies, user, cap, args) {
+                true if offset > 0 => {
+                    stor_file(replies,
+                              user,
+                              storage,
+                              &mut data_stream,
+                              data,
+                              args,
+                              offset,
+                              state).await
+                }
+                true => {
+                    appe_file(replies,
+                              user,
+                              storage,
+                              &mut data_stream,
+                              data,
+                              args,
+                              state).await
+                }
+                false => false,
+            };
+            data.close(data_stream, keep, state).await;
         }
+        None => no_data_connection(replies),
     }
-
 }
 
-pub fn rnfr(mut client: &mut BufReader<TcpStream>, user: &User, args: &str) {
-    let full_path = format!("{}/{}", user.cur_dir, args);
-    let remote = Path::new(&full_path);
+/// # The FTP RNFR command
+/// Checks the file or directory to rename exists. Returns its path for the
+/// RNTO that should come next
+///
+/// # Arguements
+///
+/// - client
+/// - user
+/// - storage
+/// - args
+pub async fn rnfr(client: &mut BufReader<FtpStream>,
+                  user: &User,
+                  storage: &Storage,
+                  args: &str)
+                  -> Option<String> {
+    let from = server::resolve_path(user, args);
+    if !server::permitted(client, user, Capability::Rename, &from).await {
+        return None;
+    }
 
-    if remote.exists() {
+    if storage.stat(&from).await.is_ok() {
         server::write_response(client,
                                &format!("{} File or Directory Exists, Ready for Desitination\r\n",
-                                        server::ITEM_EXISTS));
-
-        //REFRACTOR: Consider adding a function that reads a message and parses cmd/args
-        let response = server::read_message(&mut client);
-        let line = response.trim();
-        let (cmd, new_name) = match line.find(' ') {
-            Some(pos) => (&line[0..pos], &line[pos + 1..]),
-            None => (line, "".as_ref()),
-        };
-
-        match cmd.to_lowercase().as_ref() {
-            "rnto" => {
-
-                let from_path = format!("{}/{}", user.cur_dir, args);
-                let to_path = format!("{}/{}", user.cur_dir, new_name);
-                let from = Path::new(&from_path);
-                let to = Path::new(&to_path);
-
-                println!("Curr {}\nTo: {}", from_path, to_path);
-                match fs::rename(from, to) {
-                    Ok(_) => {
-                        server::write_response(client,
-                                               &format!("{} Success Renaming\r\n",
-                                                        server::CWD_CONFIRMED));
+                                        server::ITEM_EXISTS)).await;
+        Some(from)
+    } else {
+        server::write_response(client,
+                               &format!("{} No Such File or Dir\r\n", server::NO_ACCESS)).await;
+        None
+    }
+}
 
-                    }
-                    Err(_) => {
+/// # The FTP RNTO command
+/// Renames what the RNFR right before it named, `from` is `None` without one
+///
+/// # Arguements
+///
+/// - client
+/// - user
+/// - storage
+/// - from
+/// - args
+pub async fn rnto(client: &mut BufReader<FtpStream>,
+                  user: &User,
+                  storage: &Storage,
+                  from: Option<String>,
+                  args: &str) {
+    let from = match from {
+        Some(from) => from,
+        None => {
+            server::write_response(client,
+                                   &format!("{} RNFR has to come first\r\n",
+                                            server::OUT_OF_SEQUENCE)).await;
+            return;
+        }
+    };
 
-                        server::write_response(client,
-                                               &format!("{} Could Not Rename File\r\n",
-                                                        server::BAD_SEQUENCE));
-                    }
+    let to = server::resolve_path(user, args);
+    if !server::permitted(client, user, Capability::Rename, &to).await {
+        return;
+    }
 
-                }
-            }
-            _ => {
-                server::write_response(client,
-                                       &format!("{} {} Bad Sequence of Commands \r\n",
-                                                server::BAD_SEQUENCE,
-                                                cmd));
+    println!("Curr {}\nTo: {}", from, to);
+    let replaced = storage.stat(&to).await.ok().filter(|meta| !meta.is_dir);
+    match storage.rename(&from, &to).await {
+        Ok(_) => {
+            if let Some(meta) = replaced {
+                user.quota.release(meta.len, 1);
             }
+            server::write_response(client,
+                                   &format!("{} Success Renaming\r\n", server::CWD_CONFIRMED))
+                .await;
+        }
+        Err(_) => {
+            server::write_response(client,
+                                   &format!("{} Could Not Rename File\r\n",
+                                            server::BAD_SEQUENCE)).await;
         }
-
-    } else {
-        server::write_response(client,
-                               &format!("{} No Such File or Dir\r\n", server::NO_ACCESS));
     }
-
 }
 
-pub fn dele(mut client: &mut BufReader<TcpStream>, user: &User, args: &str) {
-    let full_path = format!("{}/{}", user.cur_dir, args);
-    let remote = Path::new(&full_path);
+pub async fn dele(mut client: &mut BufReader<FtpStream>,
+                  user: &User,
+                  storage: &Storage,
+                  args: &str) {
+    let remote = server::resolve_path(user, args);
+    if !server::permitted(client, user, Capability::Delete, &remote).await {
+        return;
+    }
 
     info!("{} being deleted form serve", args);
-    if remote.exists() && !remote.is_dir() {
-        match fs::remove_file(remote) {
-            Ok(_) => {
-                server::write_response(client,
-                                       &format!("{} Success Deleting Filer\n",
-                                                server::OPERATION_SUCCESS));
-            }
-            Err(_) => {
+    match storage.stat(&remote).await.ok().filter(|meta| !meta.is_dir) {
+        Some(meta) => {
+            match storage.remove(&remote).await {
+                Ok(_) => {
+                    user.quota.release(meta.len, 1);
+                    server::write_response(client,
+                                           &format!("{} Success Deleting Filer\n",
+                                                    server::OPERATION_SUCCESS)).await;
+                }
+                Err(_) => {
 
-                server::write_response(client,
-                                       &format!("{} File could not be deleted\r\n",
-                                                server::NO_ACCESS));
+                    server::write_response(client,
+                                           &format!("{} File could not be deleted\r\n",
+                                                    server::NO_ACCESS)).await;
+                }
             }
         }
-
-    } else {
-        server::write_response(client,
-                               &format!("{} No Such File or Dir\r\n", server::NO_ACCESS));
+        None => {
+            server::write_response(client,
+                                   &format!("{} No Such File or Dir\r\n", server::NO_ACCESS)).await;
+        }
     }
 }
 
 
-pub fn rmd(mut client: &mut BufReader<TcpStream>, user: &User, args: &str) {
-    let full_path = format!("{}/{}", user.cur_dir, args);
-    let mut remote = Path::new(&full_path);
+pub async fn rmd(mut client: &mut BufReader<FtpStream>,
+                 user: &User,
+                 storage: &Storage,
+                 args: &str) {
+    let remote = server::resolve_path(user, args);
+    if !server::permitted(client, user, Capability::Rmdir, &remote).await {
+        return;
+    }
 
-    if remote.exists() && remote.is_dir() {
-        match fs::remove_dir(remote) {
+    //The root itself can't be removed
+    let is_dir = storage.stat(&remote).await.map(|meta| meta.is_dir).unwrap_or(false);
+    if is_dir && remote != "/" {
+        match storage.rmdir(&remote).await {
             Ok(_) => {
                 server::write_response(client,
                                        &format!("{} Success Deleting Directory\r\n",
-                                                server::CWD_CONFIRMED));
+                                                server::CWD_CONFIRMED)).await;
             }
             Err(_) => {
 
                 server::write_response(client,
                                        &format!("{} Directory is not empty\r\n",
-                                                server::NO_ACCESS));
+                                                server::NO_ACCESS)).await;
             }
         }
 
 
     } else {
         server::write_response(client,
-                               &format!("{} No Such File or Dir\r\n", server::NO_ACCESS));
+                               &format!("{} No Such File or Dir\r\n", server::NO_ACCESS)).await;
     }
 }
 
 
-fn stor_file(client: &mut BufReader<TcpStream>, user: &User, stream: &mut TcpStream, args: &str) {
+//Same as server::permitted for the commands that run on the transfer thread
+fn transfer_permitted(replies: &UnboundedSender<String>,
+                      user: &User,
+                      cap: Capability,
+                      args: &str)
+                      -> bool {
+    let path = server::resolve_path(user, args);
+    if user.can(cap, &path) {
+        return true;
+    }
 
-    server::write_response(client,
-                           &format!("{} Opening binary mode to receive {}\r\n",
-                                    server::OPENNING_DATA_CONNECTION,
-                                    args));
-    let mut data_stream = stream;
-    let full_path = format!("{}/{}", user.cur_dir, args);
+    info!("{} lacks {:?} on {}", user.name, cap, path);
+    transfer::queue_response(replies,
+                             &format!("{} Permission denied\r\n", server::NO_ACCESS));
+    false
+}
 
-    let remote = Path::new(&full_path);
+//Takes room for one more file in the user's quota, replying 552 when full
+fn reserve_file(replies: &UnboundedSender<String>, user: &User) -> bool {
+    if user.quota.reserve(0, 1) {
+        return true;
+    }
 
-    if !remote.is_dir() {
-        let mut file = File::create(remote).expect("Could not create file to store");
-        server::write_to_file(&mut file, &mut data_stream);
-        //TODO: Add how long it took to transfer file
-        server::write_response(client,
-                               &format!("{} Transfer Complete\r\n",
-                                        server::CLOSING_DATA_CONNECTION));
+    info!("{} reached their file quota", user.name);
+    transfer::queue_response(replies,
+                             &format!("{} File quota exceeded\r\n", server::EXCEEDED_STORAGE));
+    false
+}
 
-    } else {
-        server::write_response(client,
-                               &format!("{} No Such File or Dir\r\n", server::NO_ACCESS));
+//Like DataChannel::complete, but an upload stopped by the quota gets a 552
+fn upload_complete(replies: &UnboundedSender<String>,
+                   data: &DataChannel,
+                   result: io::Result<()>,
+                   state: &TransferState)
+                   -> bool {
+    match result {
+        Err(ref e) if quota::is_exceeded(e) => {
+            transfer::queue_response(replies,
+                                     &format!("{} Disk quota exceeded\r\n",
+                                              server::EXCEEDED_STORAGE));
+            false
+        }
+        result => data.complete(replies, result, state),
     }
 }
 
-fn retr_file(client: &mut BufReader<TcpStream>, user: &User, stream: &mut TcpStream, args: &str) {
+//The final reply of LIST and MLSD, 250 when MODE B left the connection open
+async fn listing_complete(client: &mut BufReader<FtpStream>, kept_open: bool) {
+    match kept_open {
+        true => server::write_response(client, &transfer::kept_open_reply()).await,
+        false => {
+            server::write_response(client,
+                                   &format!("{} Transfer Complete\r\n",
+                                            server::CLOSING_DATA_CONNECTION)).await
+        }
+    }
+}
+
+//Writes the transfer log entry of a copy that just ended
+fn log_transfer(data: &DataChannel,
+                user: &User,
+                path: &str,
+                direction: Direction,
+                started: Instant,
+                result: &io::Result<()>,
+                state: &TransferState) {
+    let complete = result.is_ok() && !state.aborted();
+    METRICS.transfer(direction, started.elapsed());
+    data.xferlog.record(&user.name,
+                        path,
+                        direction,
+                        data.ascii,
+                        state.bytes(),
+                        started,
+                        complete);
+}
 
-    server::write_response(client,
-                           &format!("{} Openning binary mode to transfer {}\r\n",
-                                    server::OPENNING_DATA_CONNECTION,
-                                    args));
+fn type_name(data: &DataChannel) -> &'static str {
+    match data.ascii {
+        true => "ASCII",
+        false => "binary",
+    }
+}
+
+//Copies an upload into the file from `offset` on, turning CRLF back into LF
+//under TYPE A
+async fn receive_file<W>(replies: &UnboundedSender<String>,
+                         file: W,
+                         stream: &mut FtpStream,
+                         data: &DataChannel,
+                         offset: u64,
+                         state: &TransferState)
+                         -> io::Result<()>
+    where W: io::Write + Send + 'static
+{
+    if !data.ascii {
+        return server::write_to_file(&mut Blocking::new(file),
+                                     stream,
+                                     data.transfer_mode,
+                                     offset,
+                                     &data.throttle,
+                                     state,
+                                     replies).await;
+    }
 
-    let full_path = format!("{}/{}", user.cur_dir, args);
-    println!("{} requested file", full_path);
+    let mut file = Blocking::new(FromCrlf::new(file));
+    server::write_to_file(&mut file,
+                          stream,
+                          data.transfer_mode,
+                          offset,
+                          &data.throttle,
+                          state,
+                          replies).await?;
+    file.run(|file| file.finish()).await
+}
 
-    let mut data_stream = stream;
-    let local = Path::new(&full_path);
+//Under TYPE A the client counts a restart offset in what went over the
+//wire, so the file is translated from its start and that much skipped
+fn to_crlf_from(file: Box<dyn Read + Send>, offset: u64) -> io::Result<Box<dyn Read + Send>> {
+    let mut file = ToCrlf::new(file);
+    io::copy(&mut (&mut file).take(offset), &mut io::sink())?;
+    Ok(Box::new(file))
+}
 
-    if !local.is_dir() && local.exists() {
-        let mut file = File::open(local).expect("Could not create file to store");
+fn no_data_connection(replies: &UnboundedSender<String>) {
+    transfer::queue_response(replies,
+                             &format!("{} Can't open data connection\r\n",
+                                      server::CANT_OPEN_DATA));
+}
 
-        server::write_to_stream(&mut file, &mut data_stream);
+async fn appe_file(replies: &UnboundedSender<String>,
+                   user: &User,
+                   storage: &Storage,
+                   stream: &mut FtpStream,
+                   data: &DataChannel,
+                   args: &str,
+                   state: &TransferState)
+                   -> bool {
+
+    transfer::queue_response(replies,
+                             &format!("{} Opening {} mode to append to {}\r\n",
+                                      server::OPENNING_DATA_CONNECTION,
+                                      type_name(data),
+                                      args));
+    let remote = server::resolve_path(user, args);
+
+    if !storage.stat(&remote).await.map(|meta| meta.is_dir).unwrap_or(false) {
+        let old_len = storage.stat(&remote).await.ok().map(|meta| meta.len);
+        if old_len.is_none() && !reserve_file(replies, user) {
+            return false;
+        }
 
-        server::write_response(client,
-                               &format!("{} Transfer Complete\r\n",
-                                        server::CLOSING_DATA_CONNECTION));
+        let file = match storage.append(&remote).await {
+            Ok(file) => file,
+            Err(_) => {
+                if old_len.is_none() {
+                    user.quota.release(0, 1);
+                }
+                transfer::queue_response(replies,
+                                         &format!("{} Could not open file for append\r\n",
+                                                  server::NO_ACCESS));
+                return false;
+            }
+        };
 
+        let len = old_len.unwrap_or(0);
+        let file = user.quota.writer(file, len, len);
+        let started = Instant::now();
+        let result = receive_file(replies, file, stream, data, len, state).await;
+        log_transfer(data, user, &remote, Direction::Incoming, started, &result, state);
+        upload_complete(replies, data, result, state)
     } else {
-        server::write_response(client,
-                               &format!("{} No Such File or Dir\r\n", server::NO_ACCESS));
+        transfer::queue_response(replies,
+                                 &format!("{} No Such File or Dir\r\n", server::NO_ACCESS));
+        false
+    }
+}
+
+async fn stor_file(replies: &UnboundedSender<String>,
+                   user: &User,
+                   storage: &Storage,
+                   stream: &mut FtpStream,
+                   data: &DataChannel,
+                   args: &str,
+                   offset: u64,
+                   state: &TransferState)
+                   -> bool {
+
+    transfer::queue_response(replies,
+                             &format!("{} Opening {} mode to receive {}\r\n",
+                                      server::OPENNING_DATA_CONNECTION,
+                                      type_name(data),
+                                      args));
+    let remote = server::resolve_path(user, args);
+
+    if !storage.stat(&remote).await.map(|meta| meta.is_dir).unwrap_or(false) {
+        let old_len = storage.stat(&remote).await.ok().map(|meta| meta.len);
+        if old_len.is_none() && !reserve_file(replies, user) {
+            return false;
+        }
+
+        //A restart offset counted in CRLF lines is further into the stored file
+        //than where it lands
+        let at = match data.ascii && offset > 0 {
+            true => {
+                let path = remote.clone();
+                storage.run(move |backend| {
+                        backend.open_read(&path, 0)
+                            .and_then(|file| ascii::local_offset(file, offset))
+                    })
+                    .await
+                    .unwrap_or(offset)
+            }
+            false => offset,
+        };
+
+        //Only a fresh upload truncates, a restarted one keeps what is already there
+        let file = match storage.open_write(&remote, at).await {
+            Ok(file) => file,
+            Err(_) => {
+                if old_len.is_none() {
+                    user.quota.release(0, 1);
+                }
+                transfer::queue_response(replies,
+                                         &format!("{} Could not create file\r\n",
+                                                  server::NO_ACCESS));
+                return false;
+            }
+        };
+        let len = match (old_len, at) {
+            (Some(len), 0) => {
+                user.quota.release(len, 0);
+                0
+            }
+            (Some(len), _) => len,
+            (None, _) => 0,
+        };
+        let file = user.quota.writer(file, at, len);
+        let started = Instant::now();
+        let result = receive_file(replies, file, stream, data, offset, state).await;
+        log_transfer(data, user, &remote, Direction::Incoming, started, &result, state);
+        upload_complete(replies, data, result, state)
+
+    } else {
+        transfer::queue_response(replies,
+                                 &format!("{} No Such File or Dir\r\n", server::NO_ACCESS));
+        false
+    }
+}
+
+async fn retr_file(replies: &UnboundedSender<String>,
+                   user: &User,
+                   storage: &Storage,
+                   stream: &mut FtpStream,
+                   data: &DataChannel,
+                   args: &str,
+                   offset: u64,
+                   state: &TransferState)
+                   -> bool {
+
+    transfer::queue_response(replies,
+                             &format!("{} Openning {} mode to transfer {}\r\n",
+                                      server::OPENNING_DATA_CONNECTION,
+                                      type_name(data),
+                                      args));
+
+    let local = server::resolve_path(user, args);
+    println!("{:?} requested file", local);
+
+    if storage.stat(&local).await.map(|meta| !meta.is_dir).unwrap_or(false) {
+        let opened = match data.ascii {
+            true => {
+                let path = local.clone();
+                storage.run(move |backend| {
+                        backend.open_read(&path, 0).and_then(|file| to_crlf_from(file, offset))
+                    })
+                    .await
+            }
+            false => storage.open_read(&local, offset).await,
+        };
+        let mut file = match opened {
+            Ok(file) => Blocking::new(file),
+            Err(_) => {
+                transfer::queue_response(replies,
+                                         &format!("{} Could not open file\r\n",
+                                                  server::NO_ACCESS));
+                return false;
+            }
+        };
+        let started = Instant::now();
+        let result = server::write_to_stream(&mut file,
+                                             stream,
+                                             data.transfer_mode,
+                                             offset,
+                                             &data.throttle,
+                                             state).await;
+        log_transfer(data, user, &local, Direction::Outgoing, started, &result, state);
+        data.complete(replies, result, state)
+
+    } else {
+        transfer::queue_response(replies,
+                                 &format!("{} No Such File or Dir\r\n", server::NO_ACCESS));
+        false
     }
 }
@@ -0,0 +1,251 @@
+//! Server metrics in the Prometheus text format, served over plain HTTP on
+//! METRICS_LISTEN. The counters live in one process wide `METRICS`, since
+//! the copy loops in server.rs bump them without knowing whose session they
+//! run for
+//!
+//! - `ftp_sessions` and `ftp_passive_ports_free`/`ftp_passive_ports` gauges
+//! - `ftp_logins_total{outcome}`, `ftp_commands_total{command}` and
+//!   `ftp_replies_total{code}` counters
+//! - `ftp_bytes_received_total` and `ftp_bytes_sent_total`
+//! - `ftp_transfer_duration_seconds{direction}` histogram
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+use crate::xferlog::Direction;
+use crate::Shared;
+
+pub static METRICS: Metrics = Metrics::new();
+
+//Upper bounds of the transfer duration buckets, in seconds
+const BUCKETS: [f64; 8] = [0.1, 0.5, 1.0, 5.0, 10.0, 30.0, 60.0, 300.0];
+
+//Anything else is counted as OTHER, so clients can't make up new series
+const COMMANDS: &'static [&'static str] = &["ABOR", "ACCT", "ALLO", "APPE", "AUTH", "CDUP",
+                                            "CWD", "DELE", "EPRT", "EPSV", "FEAT", "HELP",
+                                            "LIST", "MDTM", "MKD", "MLSD", "MLST", "MODE",
+                                            "NLST", "NOOP", "OPTS", "PASS", "PASV", "PBSZ",
+                                            "PORT", "PROT", "PWD", "QUIT", "REIN", "REST",
+                                            "RETR", "RMD", "RNFR", "RNTO", "SITE", "SIZE",
+                                            "STAT", "STOR", "STOU", "STRU", "SYST", "TYPE",
+                                            "USER"];
+
+//Requests bigger than this are not a scrape
+const MAX_REQUEST: usize = 8192;
+
+#[derive(Debug, Clone, Copy, Default)]
+struct Histogram {
+    buckets: [u64; 8],
+    count: u64,
+    sum: f64,
+}
+
+#[derive(Debug)]
+pub struct Metrics {
+    bytes_in: AtomicU64,
+    bytes_out: AtomicU64,
+    logins: Mutex<BTreeMap<String, u64>>,
+    commands: Mutex<BTreeMap<String, u64>>,
+    replies: Mutex<BTreeMap<u32, u64>>,
+    //Incoming and outgoing
+    durations: Mutex<[Option<Histogram>; 2]>,
+}
+
+impl Metrics {
+    pub const fn new() -> Metrics {
+        Metrics {
+            bytes_in: AtomicU64::new(0),
+            bytes_out: AtomicU64::new(0),
+            logins: Mutex::new(BTreeMap::new()),
+            commands: Mutex::new(BTreeMap::new()),
+            replies: Mutex::new(BTreeMap::new()),
+            durations: Mutex::new([None, None]),
+        }
+    }
+
+    pub fn received(&self, n: usize) {
+        self.bytes_in.fetch_add(n as u64, Ordering::Relaxed);
+    }
+
+    pub fn sent(&self, n: usize) {
+        self.bytes_out.fetch_add(n as u64, Ordering::Relaxed);
+    }
+
+    /// Counts a login, `result` holds why it failed
+    pub fn login(&self, result: Result<(), &str>) {
+        let outcome = match result {
+            Ok(_) => "success".to_string(),
+            Err(reason) => reason.replace(' ', "_"),
+        };
+        *self.logins.lock().unwrap().entry(outcome).or_insert(0) += 1;
+    }
+
+    pub fn command(&self, command: &str) {
+        let command = command.to_uppercase();
+        let label = match COMMANDS.contains(&command.as_ref()) {
+            true => command,
+            false => "OTHER".to_string(),
+        };
+        *self.commands.lock().unwrap().entry(label).or_insert(0) += 1;
+    }
+
+    pub fn reply(&self, code: u32) {
+        *self.replies.lock().unwrap().entry(code).or_insert(0) += 1;
+    }
+
+    pub fn transfer(&self, direction: Direction, took: Duration) {
+        let secs = took.as_secs() as f64 + took.subsec_nanos() as f64 / 1e9;
+        let mut durations = self.durations.lock().unwrap();
+        let histogram = durations[direction_index(direction)].get_or_insert(Histogram::default());
+
+        for (i, bound) in BUCKETS.iter().enumerate() {
+            if secs <= *bound {
+                histogram.buckets[i] += 1;
+            }
+        }
+        histogram.count += 1;
+        histogram.sum += secs;
+    }
+
+    /// The text exposition, with the gauges only the caller knows
+    pub fn render(&self, sessions: usize, ports_free: usize, ports: usize) -> String {
+        let mut out = String::new();
+
+        gauge(&mut out, "ftp_sessions", "Connected clients", sessions as u64);
+        gauge(&mut out,
+              "ftp_passive_ports_free",
+              "Passive ports nobody holds",
+              ports_free as u64);
+        gauge(&mut out, "ftp_passive_ports", "Passive ports in the range", ports as u64);
+
+        header(&mut out, "ftp_logins_total", "Logins by outcome", "counter");
+        for (outcome, count) in self.logins.lock().unwrap().iter() {
+            let _ = writeln!(out, "ftp_logins_total{{outcome=\"{}\"}} {}", outcome, count);
+        }
+
+        header(&mut out, "ftp_commands_total", "Commands received", "counter");
+        for (command, count) in self.commands.lock().unwrap().iter() {
+            let _ = writeln!(out, "ftp_commands_total{{command=\"{}\"}} {}", command, count);
+        }
+
+        header(&mut out, "ftp_replies_total", "Final replies by code", "counter");
+        for (code, count) in self.replies.lock().unwrap().iter() {
+            let _ = writeln!(out, "ftp_replies_total{{code=\"{}\"}} {}", code, count);
+        }
+
+        header(&mut out, "ftp_bytes_received_total", "Bytes uploaded", "counter");
+        let _ = writeln!(out,
+                         "ftp_bytes_received_total {}",
+                         self.bytes_in.load(Ordering::Relaxed));
+        header(&mut out, "ftp_bytes_sent_total", "Bytes downloaded", "counter");
+        let _ = writeln!(out, "ftp_bytes_sent_total {}", self.bytes_out.load(Ordering::Relaxed));
+
+        header(&mut out,
+               "ftp_transfer_duration_seconds",
+               "How long file transfers took",
+               "histogram");
+        let durations = self.durations.lock().unwrap();
+        for &(direction, name) in &[(Direction::Incoming, "in"), (Direction::Outgoing, "out")] {
+            let histogram = durations[direction_index(direction)].unwrap_or_default();
+            for (i, bound) in BUCKETS.iter().enumerate() {
+                let _ = writeln!(out,
+                                 "ftp_transfer_duration_seconds_bucket{{direction=\"{}\",le=\"{}\"}} \
+                                  {}",
+                                 name,
+                                 bound,
+                                 histogram.buckets[i]);
+            }
+            let _ = writeln!(out,
+                             "ftp_transfer_duration_seconds_bucket{{direction=\"{}\",le=\"+Inf\"}} \
+                              {}",
+                             name,
+                             histogram.count);
+            let _ = writeln!(out,
+                             "ftp_transfer_duration_seconds_sum{{direction=\"{}\"}} {}",
+                             name,
+                             histogram.sum);
+            let _ = writeln!(out,
+                             "ftp_transfer_duration_seconds_count{{direction=\"{}\"}} {}",
+                             name,
+                             histogram.count);
+        }
+
+        out
+    }
+}
+
+fn direction_index(direction: Direction) -> usize {
+    match direction {
+        Direction::Incoming => 0,
+        Direction::Outgoing => 1,
+    }
+}
+
+fn header(out: &mut String, name: &str, help: &str, kind: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+fn gauge(out: &mut String, name: &str, help: &str, value: u64) {
+    header(out, name, help, "gauge");
+    let _ = writeln!(out, "{} {}", name, value);
+}
+
+/// Answers scrapes on the metrics port, `GET /metrics` is all there is
+pub async fn accept_scrapers(listener: tokio::net::TcpListener, shared: Arc<Shared>) {
+    loop {
+        let stream = match listener.accept().await {
+            Ok((stream, _)) => stream,
+            Err(e) => {
+                info!("Could not accept scraper: {}", e);
+                continue;
+            }
+        };
+
+        let shared = shared.clone();
+        tokio::spawn(async move {
+            let _ = scrape(stream, &shared).await;
+        });
+    }
+}
+
+async fn scrape(mut stream: tokio::net::TcpStream, shared: &Shared) -> std::io::Result<()> {
+    let mut request = Vec::new();
+    let mut buf = [0; 1024];
+
+    while !request.windows(4).any(|end| end == b"\r\n\r\n") {
+        let n = stream.read(&mut buf).await?;
+        if n == 0 || request.len() > MAX_REQUEST {
+            return Ok(());
+        }
+        request.extend_from_slice(&buf[..n]);
+    }
+
+    let request = String::from_utf8_lossy(&request);
+    let mut line = request.lines().next().unwrap_or("").split(' ');
+    let (method, path) = (line.next().unwrap_or(""), line.next().unwrap_or(""));
+
+    let (status, body) = match (method, path) {
+        ("GET", "/metrics") => {
+            let body = METRICS.render(shared.sessions.len(),
+                                      shared.ports.available(),
+                                      shared.ports.size());
+            ("200 OK", body)
+        }
+        ("GET", _) => ("404 Not Found", "Metrics are at /metrics\n".to_string()),
+        _ => ("405 Method Not Allowed", "Only GET is supported\n".to_string()),
+    };
+
+    let response = format!("HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\n\
+                            Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+                           status,
+                           body.len(),
+                           body);
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
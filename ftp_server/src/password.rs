@@ -0,0 +1,48 @@
+//! Passwords in the user database. Entries are salted hashes recognised by
+//! their prefix, argon2 (`$argon2id$...`), bcrypt (`$2b$...`) or
+//! SHA-crypt (`$6$...`, `$5$...`). Anything else is an old cleartext entry
+
+use argon2::{Argon2, PasswordHash, PasswordHasher, PasswordVerifier};
+use argon2::password_hash::SaltString;
+use constant_time_eq::constant_time_eq;
+use pwhash::{bcrypt, sha256_crypt, sha512_crypt};
+use rand::{self, Rng};
+
+/// Checks a password given at login against the stored entry
+pub fn verify(password: &str, stored: &str) -> bool {
+    if stored.starts_with("$argon2") {
+        match PasswordHash::new(stored) {
+            Ok(hash) => Argon2::default().verify_password(password.as_bytes(), &hash).is_ok(),
+            Err(_) => false,
+        }
+    } else if is_bcrypt(stored) {
+        bcrypt::verify(password, stored)
+    } else if stored.starts_with("$6$") {
+        sha512_crypt::verify(password, stored)
+    } else if stored.starts_with("$5$") {
+        sha256_crypt::verify(password, stored)
+    } else {
+        constant_time_eq(password.as_bytes(), stored.as_bytes())
+    }
+}
+
+/// Whether a stored entry is one of the supported hashes
+pub fn is_hashed(stored: &str) -> bool {
+    stored.starts_with("$argon2") || is_bcrypt(stored) || stored.starts_with("$6$") ||
+    stored.starts_with("$5$")
+}
+
+/// Makes a new argon2id entry with a random salt, for `--hash-password`
+pub fn hash(password: &str) -> String {
+    let salt_bytes: Vec<u8> = rand::thread_rng().gen_iter().take(16).collect();
+    let salt = SaltString::encode_b64(&salt_bytes).expect("Could not encode salt");
+
+    Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .expect("Could not hash password")
+        .to_string()
+}
+
+fn is_bcrypt(stored: &str) -> bool {
+    ["$2a$", "$2b$", "$2x$", "$2y$"].iter().any(|prefix| stored.starts_with(prefix))
+}
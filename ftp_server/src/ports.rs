@@ -0,0 +1,111 @@
+//! Passive data ports. Every PASV or EPSV takes a port from the range in
+//! DATA_PORT_RANGE_MIN..MAX for a single transfer, and it goes back to the
+//! pool as soon as that transfer is done or the client lets it sit unused
+//! for too long
+
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use tokio::net::{TcpListener, TcpSocket};
+use tokio::time::{self, Instant};
+
+//How long a passive port waits for its transfer before it is given back
+pub const PASSIVE_TIMEOUT: u64 = 60;
+
+/// The free ports of the range, shared by every session
+#[derive(Debug, Clone)]
+pub struct PortPool {
+    free: Arc<Mutex<Vec<u16>>>,
+    size: usize,
+}
+
+impl PortPool {
+    pub fn new(ports: &[i32]) -> PortPool {
+        //Handed out from the back, so the lowest ports go first
+        let mut free: Vec<u16> = ports.iter().map(|&port| port as u16).collect();
+        free.reverse();
+
+        PortPool {
+            free: Arc::new(Mutex::new(free)),
+            size: ports.len(),
+        }
+    }
+
+    /// Number of ports in the range
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Number of ports nobody holds
+    pub fn available(&self) -> usize {
+        self.free.lock().unwrap().len()
+    }
+
+    /// Takes a free port and listens on it. Ports something else already
+    /// has bound are skipped, `None` means the pool is used up
+    pub fn bind(&self, ip: IpAddr) -> Option<PassivePort> {
+        let mut skipped = Vec::new();
+        let mut bound = None;
+
+        while let Some(port) = self.free.lock().unwrap().pop() {
+            match listen(SocketAddr::new(ip, port)) {
+                Ok(listener) => {
+                    bound = Some(PassivePort {
+                        listener: listener,
+                        port: port,
+                        opened: Instant::now(),
+                        pool: self.clone(),
+                    });
+                    break;
+                }
+                Err(_) => skipped.push(port),
+            }
+        }
+
+        //Ports that would not bind are tried last next time
+        let mut free = self.free.lock().unwrap();
+        for port in skipped {
+            free.insert(0, port);
+        }
+        bound
+    }
+}
+
+//Binds the way std's TcpListener does, only one client connects to it
+fn listen(addr: SocketAddr) -> io::Result<TcpListener> {
+    let socket = match addr {
+        SocketAddr::V4(_) => TcpSocket::new_v4()?,
+        SocketAddr::V6(_) => TcpSocket::new_v6()?,
+    };
+    socket.set_reuseaddr(true)?;
+    socket.bind(addr)?;
+    socket.listen(1)
+}
+
+/// A port taken from the pool, it is given back when this is dropped
+#[derive(Debug)]
+pub struct PassivePort {
+    pub listener: TcpListener,
+    port: u16,
+    opened: Instant,
+    pool: PortPool,
+}
+
+impl PassivePort {
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    /// Resolves once the port has waited PASSIVE_TIMEOUT for its transfer
+    pub async fn expired(&self) {
+        time::sleep_until(self.opened + Duration::from_secs(PASSIVE_TIMEOUT)).await
+    }
+}
+
+impl Drop for PassivePort {
+    fn drop(&mut self) {
+        self.pool.free.lock().unwrap().push(self.port);
+    }
+}
@@ -0,0 +1,190 @@
+//! Per-user disk quotas. Limits come from the user DB (`quota=100M`,
+//! `quota_files=500`) and the usage is shared by every session of the user,
+//! counted once at startup and then kept up to date as files are written
+//! and deleted
+
+use std::error::Error;
+use std::fmt;
+use std::io::{self, ErrorKind, Write};
+use std::sync::{Arc, Mutex};
+
+use crate::storage::{self, StorageBackend};
+
+/// Bytes and files a user has stored
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Usage {
+    pub bytes: u64,
+    pub files: u64,
+}
+
+#[derive(Debug, Clone)]
+pub struct Quota {
+    pub max_bytes: Option<u64>,
+    pub max_files: Option<u64>,
+    usage: Arc<Mutex<Usage>>,
+}
+
+impl Quota {
+    pub fn unlimited() -> Quota {
+        Quota {
+            max_bytes: None,
+            max_files: None,
+            usage: Arc::new(Mutex::new(Usage::default())),
+        }
+    }
+
+    /// Reads `quota=<size>` and `quota_files=<count>` entries, sizes take a
+    /// K, M or G suffix
+    pub fn parse(specs: &[&str]) -> Result<Quota, String> {
+        let mut quota = Quota::unlimited();
+
+        for spec in specs {
+            let (key, value) = match spec.find('=') {
+                Some(pos) => (&spec[..pos], &spec[pos + 1..]),
+                None => return Err(format!("{} has no value", spec)),
+            };
+            match key {
+                "quota" => quota.max_bytes = Some(parse_size(value)?),
+                "quota_files" => {
+                    quota.max_files = Some(value.parse::<u64>()
+                        .map_err(|_| format!("Bad file count {}", value))?)
+                }
+                _ => return Err(format!("Unknown quota setting {}", key)),
+            }
+        }
+
+        Ok(quota)
+    }
+
+    pub fn usage(&self) -> Usage {
+        *self.usage.lock().unwrap()
+    }
+
+    /// Counts against the usage of `other` from now on, sessions holding
+    /// either one then see the same files
+    pub fn share_usage(&mut self, other: &Quota) {
+        self.usage = other.usage.clone();
+    }
+
+    /// Sets the usage to what is stored below the root of `storage`
+    pub fn scan(&self, storage: &dyn StorageBackend) {
+        let mut usage = Usage::default();
+        let mut dirs = vec!["/".to_string()];
+
+        while let Some(dir) = dirs.pop() {
+            for (name, meta) in storage.list(&dir).unwrap_or(Vec::new()) {
+                match meta.is_dir {
+                    true => dirs.push(storage::join(&dir, &name)),
+                    false => {
+                        usage.bytes += meta.len;
+                        usage.files += 1;
+                    }
+                }
+            }
+        }
+
+        *self.usage.lock().unwrap() = usage;
+    }
+
+    /// Takes room for more bytes and files, or nothing at all when that
+    /// would go over a limit
+    pub fn reserve(&self, bytes: u64, files: u64) -> bool {
+        let mut usage = self.usage.lock().unwrap();
+        let over_bytes = self.max_bytes.map_or(false, |max| usage.bytes + bytes > max);
+        let over_files = self.max_files.map_or(false, |max| usage.files + files > max);
+        if over_bytes || over_files {
+            return false;
+        }
+
+        usage.bytes += bytes;
+        usage.files += files;
+        true
+    }
+
+    /// Gives back the room of data that was truncated or deleted
+    pub fn release(&self, bytes: u64, files: u64) {
+        let mut usage = self.usage.lock().unwrap();
+        usage.bytes = usage.bytes.saturating_sub(bytes);
+        usage.files = usage.files.saturating_sub(files);
+    }
+
+    /// Wraps a file opened at `pos` with `len` bytes in it, so what it
+    /// grows by is counted and a write past the limit fails
+    pub fn writer(&self, inner: Box<dyn Write + Send>, pos: u64, len: u64) -> QuotaWriter {
+        QuotaWriter {
+            inner: inner,
+            quota: self.clone(),
+            pos: pos,
+            len: len,
+        }
+    }
+}
+
+/// Reads a size such as `100M`, with an optional K, M or G suffix
+pub fn parse_size(value: &str) -> Result<u64, String> {
+    let (digits, unit) = match value.chars().last() {
+        Some('K') | Some('k') => (&value[..value.len() - 1], 1 << 10),
+        Some('M') | Some('m') => (&value[..value.len() - 1], 1 << 20),
+        Some('G') | Some('g') => (&value[..value.len() - 1], 1 << 30),
+        _ => (value, 1),
+    };
+
+    match digits.parse::<u64>() {
+        Ok(size) => size.checked_mul(unit).ok_or_else(|| format!("Bad size {}", value)),
+        Err(_) => Err(format!("Bad size {}", value)),
+    }
+}
+
+/// Error of a write that would have gone over the quota
+#[derive(Debug)]
+pub struct QuotaExceeded;
+
+impl fmt::Display for QuotaExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "disk quota exceeded")
+    }
+}
+
+impl Error for QuotaExceeded {
+    fn description(&self) -> &str {
+        "disk quota exceeded"
+    }
+}
+
+/// Whether an error came from going over the quota
+pub fn is_exceeded(err: &io::Error) -> bool {
+    err.get_ref().map_or(false, |inner| inner.is::<QuotaExceeded>())
+}
+
+pub struct QuotaWriter {
+    inner: Box<dyn Write + Send>,
+    quota: Quota,
+    pos: u64,
+    len: u64,
+}
+
+impl Write for QuotaWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let end = self.pos + buf.len() as u64;
+        let growth = end.saturating_sub(self.len);
+        if !self.quota.reserve(growth, 0) {
+            return Err(io::Error::new(ErrorKind::Other, QuotaExceeded));
+        }
+
+        match self.inner.write_all(buf) {
+            Ok(_) => {
+                self.pos = end;
+                self.len += growth;
+                Ok(buf.len())
+            }
+            Err(e) => {
+                self.quota.release(growth, 0);
+                Err(e)
+            }
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
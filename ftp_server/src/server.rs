@@ -1,66 +1,165 @@
 use std::io::prelude::*; //the standard io functions that come with rust
-use std::os::unix::fs::PermissionsExt;
+use std::cell::Cell;
 use std::collections::HashMap;
-use std::io::{BufReader, Write};
+use std::future::Future;
+use std::io::Write;
+use std::io;
+use std::mem;
 use std::string::String;
-use std::net::{TcpStream, SocketAddrV4};
-use std::path::Path;
-use std::fs;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::Arc;
 use std::fs::File;
-
-use user::User;
-
-
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use flate2::Compression;
+use flate2::write::{ZlibDecoder, ZlibEncoder};
+use rustls::ServerConfig;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::sync::mpsc::UnboundedSender;
+
+use crate::block::{BlockDecoder, BlockWriter};
+use crate::user::{Capability, User};
+use crate::password;
+use crate::metrics::METRICS;
+use crate::ports::{PassivePort, PortPool};
+use crate::sessions::Hangup;
+use crate::stream::FtpStream;
+use crate::storage::{self, Blocking, Metadata, Storage, StorageBackend};
+use crate::throttle::Throttle;
+use crate::transfer::{self, Transfer, TransferState};
+
+
+pub const RESTART_MARKER: u32 = 110;
 pub const OPENNING_DATA_CONNECTION: u32 = 150;
 pub const OPERATION_SUCCESS: u32 = 200;
+pub const SYSTEM_STATUS: u32 = 211;
+pub const FILE_STATUS: u32 = 213;
 pub const SYSTEM_RECEIVED: u32 = 215;
 pub const LOGGED_EXPECTED: u32 = 220;
 pub const GOODBYE: u32 = 221;
+pub const DATA_CONNECTION_OPEN: u32 = 225;
 pub const CLOSING_DATA_CONNECTION: u32 = 226;
 pub const PASSIVE_MODE: u32 = 227;
+pub const EXTENDED_PASSIVE_MODE: u32 = 229;
 pub const LOGGED_IN: u32 = 230;
+pub const SECURITY_EXCHANGE_DONE: u32 = 234;
 pub const CWD_CONFIRMED: u32 = 250;
+pub const FILE_ACTION_OK: u32 = 250;
 pub const PATHNAME_AVAILABLE: u32 = 257;
 pub const PASSWORD_EXPECTED: u32 = 331;
 pub const ITEM_EXISTS: u32 = 350;
+pub const PENDING_FURTHER_INFO: u32 = 350;
+pub const SERVICE_NOT_AVAILABLE: u32 = 421;
+pub const CANT_OPEN_DATA: u32 = 425;
+pub const TRANSFER_ABORTED: u32 = 426;
 pub const INVALID_USER_OR_PASS: u32 = 430;
+pub const TLS_UNAVAILABLE: u32 = 431;
+pub const LOCAL_ERROR: u32 = 451;
 pub const NOT_UNDERSTOOD: u32 = 500;
 pub const BAD_SEQUENCE: u32 = 501;
+pub const OUT_OF_SEQUENCE: u32 = 503;
+pub const NOT_IMPLEMENTED_FOR_PARAMETER: u32 = 504;
+pub const UNSUPPORTED_PROTOCOL: u32 = 522;
 pub const AUTHENTICATION_FAILED: u32 = 530;
+pub const PROTECTION_NOT_SUPPORTED: u32 = 536;
 pub const NO_ACCESS: u32 = 550;
+pub const EXCEEDED_STORAGE: u32 = 552;
 
 
+//Extensions advertised through FEAT (RFC 2389)
+pub const FEATURES: &'static [&'static str] = &["EPRT",
+                                                 "EPSV",
+                                                 "MDTM",
+                                                 "MLST type*;size*;modify*;perm*;unique*;",
+                                                 "MODE B",
+                                                 "MODE Z",
+                                                 "REST STREAM",
+                                                 "SIZE"];
 
 #[derive(Debug, Copy, Clone)]
 pub enum FtpMode {
-    Active(SocketAddrV4),
+    Active(SocketAddr),
     Passive,
 }
 
+//Compression level of MODE Z until OPTS MODE Z LEVEL changes it
+pub const DEFAULT_DEFLATE_LEVEL: u32 = 6;
+
+/// How data is sent over the data connection, picked with MODE
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum TransferMode {
+    Stream,
+    //MODE Z, a zlib stream at the given level
+    Deflate(u32),
+    //MODE B, blocks that end with an EOF one
+    Block,
+}
+
 //Function that automatically writes to any stream wrapped in BufReader
-pub fn write_response(client: &mut BufReader<TcpStream>, cmd: &str) {
+pub async fn write_response(client: &mut BufReader<FtpStream>, cmd: &str) {
     client.get_mut()
-        .write(cmd.to_string().as_bytes())
+        .write_all(cmd.as_bytes())
+        .await
         .expect("Something went wrong writing command");
-    client.get_mut().flush().expect("Something went wrong flushing stream");
+    client.get_mut().flush().await.expect("Something went wrong flushing stream");
+
+    if let Some(code) = reply_code(cmd) {
+        let _ = LAST_REPLY.try_with(|last| last.set(Some(code)));
+    }
+}
+
+tokio::task_local! {
+    //A session writes all of its replies from its own task, so this is the
+    //code of the last one it sent
+    static LAST_REPLY: Cell<Option<u32>>;
+}
+
+/// Runs a session, keeping track of the replies it writes for
+/// `take_last_reply`
+pub async fn track_replies<F: Future>(session: F) -> F::Output {
+    LAST_REPLY.scope(Cell::new(None), session).await
+}
+
+/// The code of the last reply written from this task since the previous
+/// call, for the audit log
+pub fn take_last_reply() -> Option<u32> {
+    LAST_REPLY.try_with(|last| last.replace(None)).unwrap_or(None)
+}
+
+//The code on the final line of a reply, "211 End" of a multi-line one
+fn reply_code(msg: &str) -> Option<u32> {
+    msg.lines()
+        .rev()
+        .filter(|line| line.len() >= 4 && line.as_bytes()[3] == b' ')
+        .filter_map(|line| line[..3].parse::<u32>().ok())
+        .next()
 }
 
 
-//Function that automatically reads from any stream wrapped in BufReader
-pub fn read_message(client: &mut BufReader<TcpStream>) -> String {
+//Function that automatically reads from any stream wrapped in BufReader.
+//Not echoed anywhere, it reads passwords. Comes back empty when the
+//session is hung up on meanwhile
+pub async fn read_message(client: &mut BufReader<FtpStream>, hangup: &Hangup) -> String {
     let mut response = String::new();
-    client.read_line(&mut response).expect("Could not read message");
-    println!("CLIENT: {}", response);
+    tokio::select! {
+        read = client.read_line(&mut response) => {
+            read.expect("Could not read message");
+        }
+        _ = hangup.wait() => response.clear(),
+    }
 
     return response;
 
 }
 
-//Logs in a user based on the current list of users
-pub fn handle_user(mut client: &mut BufReader<TcpStream>,
-                   arg: &str,
-                   map: &HashMap<String, User>)
-                   -> bool {
+/// Logs in a user based on the current list of users. A failed login gives
+/// the reason: "unknown user", "notallowed", "blocked", "no password" or
+/// "bad password"
+pub async fn handle_user(mut client: &mut BufReader<FtpStream>,
+                         arg: &str,
+                         map: &HashMap<String, User>,
+                         hangup: &Hangup)
+                         -> Result<(), &'static str> {
 
     match map.get(arg) {
         Some(user) => {
@@ -69,18 +168,18 @@ pub fn handle_user(mut client: &mut BufReader<TcpStream>,
                     write_response(client,
                                    &format!("{} {} This user is not allowed\r\n",
                                             AUTHENTICATION_FAILED,
-                                            arg));
+                                            arg)).await;
 
                     info!("{} is not allowed", user.name);
-                    return false;
+                    return Err("notallowed");
                 }
                 "blocked" => {
                     write_response(client,
                                    &format!("{} {} This user is blocked\r\n",
                                             AUTHENTICATION_FAILED,
-                                            arg));
+                                            arg)).await;
                     info!("{} is blocked", user.name);
-                    return false;
+                    return Err("blocked");
 
                 }
                 _ => {
@@ -88,8 +187,8 @@ pub fn handle_user(mut client: &mut BufReader<TcpStream>,
                     write_response(client,
                                    &format!("{} Username okay, need password for {}\r\n",
                                             PASSWORD_EXPECTED,
-                                            arg));
-                    let response = read_message(&mut client);
+                                            arg)).await;
+                    let response = read_message(&mut client, hangup).await;
 
                     let line = response.trim();
 
@@ -100,27 +199,27 @@ pub fn handle_user(mut client: &mut BufReader<TcpStream>,
 
                     match cmd {
                         "PASS" | "pass" => {
-                            if password.trim() == user.pass {
+                            if password::verify(password.trim(), &user.pass) {
                                 write_response(client,
                                                &format!("{} Success Login for {}\r\n",
                                                         LOGGED_IN,
-                                                        arg));
-                                return true;
+                                                        arg)).await;
+                                return Ok(());
                             } else {
 
                                 write_response(client,
                                                &format!("{} Invalid Password {}\r\n",
                                                         INVALID_USER_OR_PASS,
-                                                        arg));
-                                return false;
+                                                        arg)).await;
+                                return Err("bad password");
                             }
                         }
                         _ => {
                             write_response(client,
                                            &format!("{} {} not understood\r\n",
                                                     NOT_UNDERSTOOD,
-                                                    cmd));
-                            return false;
+                                                    cmd)).await;
+                            return Err("no password");
                         }
                     }
 
@@ -131,218 +230,852 @@ pub fn handle_user(mut client: &mut BufReader<TcpStream>,
 
             info!("The user does not exist");
             write_response(client,
-                           &format!("{} Invalid Username {}\r\n", INVALID_USER_OR_PASS, arg));
-            return false;
+                           &format!("{} Invalid Username {}\r\n", INVALID_USER_OR_PASS, arg)).await;
+            return Err("unknown user");
         }
     }
 }
 
-//Handles the changing of working directory
-pub fn cwd(client: &mut BufReader<TcpStream>, args: &str, user: &mut User) {
-    println!("user path: {}", user.path);
-    println!("user path: {}", user.path);
+//Handles the changing of working directory, never above the user's root
+pub async fn cwd(client: &mut BufReader<FtpStream>,
+                 args: &str,
+                 user: &mut User,
+                 storage: &Storage) {
     info!("cur path: {}", user.cur_dir);
+
+    let new_path = resolve_path(user, args);
+    match storage.stat(&new_path).await {
+        Ok(ref meta) if meta.is_dir => {
+            debug!("New path exists");
+            user.cur_dir = new_path;
+            write_response(client,
+                           &format!("{} CWD Command Success \r\n", CWD_CONFIRMED)).await;
+        }
+        _ => {
+            debug!("New path doesn't  exists");
+            write_response(client,
+                           &format!("{} {} No Such File or Directory \r\n", NO_ACCESS, args)).await;
+        }
+    }
+
+    info!("new cur path: {}", user.cur_dir);
+
+}
+
+pub async fn cdup(client: &mut BufReader<FtpStream>, user: &mut User) {
     info!("cur path: {}", user.cur_dir);
 
-    let cur_dir = format!("{}", user.cur_dir).to_string();
-    let arg_dir = format!("{}/{}", user.cur_dir, args).to_string();
-    let user_dir = format!("{}", user.path);
+    //At the root .. resolves to the root itself
+    user.cur_dir = resolve_path(user, "..");
+    write_response(client,
+                   &format!("{} CDUP Command Success \r\n", CWD_CONFIRMED)).await;
 
-    let mut temp_path = Path::new(&cur_dir);
-    let user_root = Path::new(&user_dir);
+    info!("NEW cur path: {}", user.cur_dir);
 
-    if args == ".." {
-        temp_path = temp_path.parent().unwrap();
-    } else if args == "." {
-        //seriously? no sleep is affecting me
-        temp_path = Path::new(&cur_dir);
-    } else {
-        temp_path = Path::new(&arg_dir);
-    }
 
-    let new_path = temp_path;
+}
+
 
-    match new_path < user_root {
-        true => {
-            debug!("new path is less then root");
+
+pub async fn mkd(client: &mut BufReader<FtpStream>,
+                 args: &str,
+                 user: &mut User,
+                 storage: &Storage) {
+
+    let path = resolve_path(user, args);
+    if !permitted(client, user, Capability::Mkdir, &path).await {
+        return;
+    }
+
+    if storage.stat(&path).await.is_err() {
+        if storage.mkdir(&path).await.is_err() {
             write_response(client,
-                           &format!("{} CWD Command Success \r\n", CWD_CONFIRMED));
-        }
-        false => {
-            if new_path.exists() && new_path.is_dir() {
-                debug!("New path exists");
-                user.cur_dir = new_path.display().to_string();
-                write_response(client,
-                               &format!("{} CWD Command Success \r\n", CWD_CONFIRMED));
-            } else {
-                debug!("New path doesn't  exists");
-                write_response(client,
-                               &format!("{} {} No Such File or Directory \r\n", NO_ACCESS, args));
-            }
+                           &format!("{} {} could not be created\r\n", NO_ACCESS, args)).await;
+            return;
         }
     }
 
-    println!("new cur path: {}", user.cur_dir);
-    info!("new cur path: {}", user.cur_dir);
+
+    info!("Creating new directory: {}", args);
+    write_response(client,
+                   &format!("{} {} creation success\r\n", PATHNAME_AVAILABLE, args)).await;
 
 }
 
-pub fn cdup(client: &mut BufReader<TcpStream>, user: &mut User) {
-    info!("user path: {}", user.path);
-    info!("cur path: {}", user.cur_dir);
+/// Checks one of the user's capabilities on a path, replying 550 when they
+/// don't have it
+pub async fn permitted(client: &mut BufReader<FtpStream>,
+                       user: &User,
+                       cap: Capability,
+                       path: &str)
+                       -> bool {
+    if user.can(cap, path) {
+        return true;
+    }
 
-    //REFRACTOR: PutUser directory and path in one line
-    let user_dir = format!("{}", user.path);
-    let user_root = Path::new(&user_dir);
+    info!("{} lacks {:?} on {}", user.name, cap, path);
+    write_response(client, &format!("{} Permission denied\r\n", NO_ACCESS)).await;
+    false
+}
 
-    let cur_dir = format!("{}", user.cur_dir);
-    let cur_path = Path::new(&cur_dir).parent().expect("No parent exists");
+//REFRACTOR: Consider turning type into an ENUM
+//Accepts A and A N for ASCII, I and L 8 for binary. Returns the new type, or
+//an empty string when it is left as it was
+pub async fn handle_type(client: &mut BufReader<FtpStream>, args: &str) -> String {
+    let words: Vec<String> = args.split_whitespace().map(|word| word.to_uppercase()).collect();
+    let words: Vec<&str> = words.iter().map(|word| word.as_str()).collect();
+    match words.as_slice() {
+        ["I"] | ["L", "8"] => {
+            write_response(client, &format!("{} Type set to I\r\n", OPERATION_SUCCESS)).await;
+            info!("Switching type to binary");
+            return "BINARY".to_string();
+        }
+        ["A"] | ["A", "N"] => {
 
+            write_response(client, &format!("{} Type set to A\r\n", OPERATION_SUCCESS)).await;
 
-    match cur_path < user_root {
-        true => {
+            info!("Switching type to ASCII");
+            return "ASCII".to_string();
+        }
+        [] => {
             write_response(client,
-                           &format!("{} CWD Command Success \r\n", CWD_CONFIRMED));
+                           &format!("{} TYPE needs an argument\r\n", BAD_SEQUENCE)).await;
         }
-        false => {
-            user.cur_dir = cur_path.display().to_string();
+        _ => {
             write_response(client,
-                           &format!("{} CDUP Command Success \r\n", CWD_CONFIRMED));
+                           &format!("{} TYPE {} not implemented\r\n",
+                                    NOT_IMPLEMENTED_FOR_PARAMETER,
+                                    args)).await;
         }
     }
+    "".to_string()
+}
 
-    info!("NEW cur path: {}", user.cur_dir);
 
+//Handles MODE, S for stream, B for block and Z for deflate at the session's
+//level
+pub async fn handle_mode(client: &mut BufReader<FtpStream>,
+                         args: &str,
+                         level: u32)
+                         -> Option<TransferMode> {
+    match args.to_uppercase().as_ref() {
+        "S" => {
+            write_response(client, &format!("{} Mode set to S\r\n", OPERATION_SUCCESS)).await;
+            Some(TransferMode::Stream)
+        }
+        "Z" => {
+            write_response(client, &format!("{} Mode set to Z\r\n", OPERATION_SUCCESS)).await;
+            info!("Switching to MODE Z at level {}", level);
+            Some(TransferMode::Deflate(level))
+        }
+        "B" => {
+            write_response(client, &format!("{} Mode set to B\r\n", OPERATION_SUCCESS)).await;
+            info!("Switching to MODE B");
+            Some(TransferMode::Block)
+        }
+        "C" => {
+            write_response(client,
+                           &format!("{} MODE {} not implemented\r\n",
+                                    NOT_IMPLEMENTED_FOR_PARAMETER,
+                                    args)).await;
+            None
+        }
+        _ => {
+            write_response(client,
+                           &format!("{} Unknown MODE {}\r\n", BAD_SEQUENCE, args)).await;
+            None
+        }
+    }
+}
 
+//Handles OPTS, only MODE Z LEVEL <0-9> has options. Returns the new level
+pub async fn handle_opts(client: &mut BufReader<FtpStream>, args: &str) -> Option<u32> {
+    let words: Vec<String> = args.split_whitespace().map(|word| word.to_uppercase()).collect();
+    let level = match words.len() {
+        4 if words[..3] == ["MODE", "Z", "LEVEL"] => words[3].parse::<u32>().ok(),
+        _ => None,
+    };
+
+    match level {
+        Some(level) if level <= 9 => {
+            write_response(client,
+                           &format!("{} MODE Z LEVEL set to {}\r\n",
+                                    OPERATION_SUCCESS,
+                                    level))
+                .await;
+            Some(level)
+        }
+        _ => {
+            write_response(client,
+                           &format!("{} Option {} not understood\r\n", BAD_SEQUENCE, args)).await;
+            None
+        }
+    }
 }
 
+//Handles AUTH TLS by upgrading the control connection (RFC 4217).
+//Returns false when the handshake failed and the session has to end
+pub async fn handle_auth(client: &mut BufReader<FtpStream>,
+                         args: &str,
+                         tls: &Option<Arc<ServerConfig>>)
+                         -> bool {
+    match args.to_uppercase().as_ref() {
+        "TLS" | "TLS-C" | "SSL" => {}
+        _ => {
+            write_response(client,
+                           &format!("{} Unsupported security mechanism {}\r\n",
+                                    NOT_IMPLEMENTED_FOR_PARAMETER,
+                                    args)).await;
+            return true;
+        }
+    }
 
+    if client.get_ref().is_tls() {
+        write_response(client,
+                       &format!("{} Already using TLS\r\n", OUT_OF_SEQUENCE)).await;
+        return true;
+    }
 
-pub fn mkd(client: &mut BufReader<TcpStream>, args: &str, user: &mut User) {
+    match *tls {
+        //Anything sent behind AUTH was sent in the clear, it must not be
+        //read as if it came through the encrypted connection
+        Some(_) if !client.buffer().is_empty() => {
+            write_response(client,
+                           &format!("{} Commands sent before the TLS handshake\r\n",
+                                    OUT_OF_SEQUENCE))
+                .await;
+            false
+        }
+        Some(ref config) => {
+            write_response(client,
+                           &format!("{} AUTH {} successful\r\n",
+                                    SECURITY_EXCHANGE_DONE,
+                                    args))
+                .await;
+            match client.get_mut().upgrade(config).await {
+                Ok(_) => {
+                    info!("Control connection upgraded to TLS");
+                    true
+                }
+                Err(e) => {
+                    info!("TLS handshake failed: {}", e);
+                    false
+                }
+            }
+        }
+        None => {
+            write_response(client,
+                           &format!("{} TLS is not configured on this server\r\n",
+                                    TLS_UNAVAILABLE)).await;
+            true
+        }
+    }
+}
 
-    let new_dir = format!("{}/{}", user.cur_dir, args);
-    let path = Path::new(&new_dir);
+//PBSZ only makes sense after AUTH, and with TLS the buffer size is always 0
+pub async fn handle_pbsz(client: &mut BufReader<FtpStream>, args: &str) {
+    if !client.get_ref().is_tls() {
+        write_response(client,
+                       &format!("{} PBSZ requires AUTH first\r\n", OUT_OF_SEQUENCE)).await;
+        return;
+    }
+
+    info!("PBSZ {} requested", args);
+    write_response(client, &format!("{} PBSZ=0\r\n", OPERATION_SUCCESS)).await;
+}
 
-    if !path.exists() {
-        fs::create_dir_all(&path).expect("Could not create directory");
+//Parses PROT, returning whether data connections should be encrypted
+pub async fn handle_prot(client: &mut BufReader<FtpStream>, args: &str) -> Option<bool> {
+    if !client.get_ref().is_tls() {
+        write_response(client,
+                       &format!("{} PROT requires AUTH first\r\n", OUT_OF_SEQUENCE)).await;
+        return None;
     }
 
+    match args.to_uppercase().as_ref() {
+        "C" => {
+            write_response(client,
+                           &format!("{} Protection set to Clear\r\n", OPERATION_SUCCESS))
+                .await;
+            Some(false)
+        }
+        "P" => {
+            write_response(client,
+                           &format!("{} Protection set to Private\r\n", OPERATION_SUCCESS)).await;
+            Some(true)
+        }
+        "S" | "E" => {
+            write_response(client,
+                           &format!("{} Protection level {} not supported\r\n",
+                                    PROTECTION_NOT_SUPPORTED,
+                                    args)).await;
+            None
+        }
+        _ => {
+            write_response(client,
+                           &format!("{} Unknown protection level {}\r\n",
+                                    NOT_IMPLEMENTED_FOR_PARAMETER,
+                                    args)).await;
+            None
+        }
+    }
+}
+
+//Parses the REST marker, the returned offset applies to the next transfer
+pub async fn handle_rest(client: &mut BufReader<FtpStream>, args: &str) -> u64 {
+    match args.trim().parse::<u64>() {
+        Ok(offset) => {
+            write_response(client,
+                           &format!("{} Restarting at {}. Send STOR or RETR\r\n",
+                                    PENDING_FURTHER_INFO,
+                                    offset)).await;
+            info!("Restart offset set to {}", offset);
+            offset
+        }
+        Err(_) => {
+            write_response(client,
+                           &format!("{} Invalid restart marker {}\r\n", BAD_SEQUENCE, args)).await;
+            0
+        }
+    }
+}
+
+
+//REFRACTOR: Redo this logic to for more succinct code
+/// Takes a passive port from the pool for the next transfer, replying 425
+/// when they are all in use
+pub async fn passive_port(client: &mut BufReader<FtpStream>,
+                          ports: &PortPool)
+                          -> Option<PassivePort> {
+    let port = ports.bind(client.get_mut().local_addr().unwrap().ip());
+    if port.is_none() {
+        info!("No passive port left");
+        write_response(client,
+                       &format!("{} No passive port available, try again later\r\n",
+                                CANT_OPEN_DATA)).await;
+    }
+    port
+}
+
+// PASV, returns whether the client can go passive
+pub async fn handle_pasv(client: &mut BufReader<FtpStream>, data_port: u16) -> bool {
+    //PASV can only describe an IPv4 address
+    let ip = match client.get_mut().local_addr().unwrap().ip() {
+        IpAddr::V4(ip) => format!("{}", ip).replace(".", ","),
+        IpAddr::V6(_) => {
+            write_response(client,
+                           &format!("{} PASV is IPv4 only, use EPSV\r\n", CANT_OPEN_DATA)).await;
+            return false;
+        }
+    };
+    let (port1, port2) = split_port(data_port);
 
-    info!("Creating new directory: {}", args);
     write_response(client,
-                   &format!("{} {} creation success\r\n", PATHNAME_AVAILABLE, args));
+                   &format!("{} Entering Passive Mode ({},{},{}).\r\n",
+                            PASSIVE_MODE,
+                            ip,
+                            port1,
+                            port2)).await;
+    true
+}
+
+// EPSV (RFC 2428), only the port goes back since the client already knows
+// our address. Returns whether the client can go passive
+pub async fn handle_epsv(client: &mut BufReader<FtpStream>, args: &str, data_port: u16) -> bool {
+    let proto = match client.get_mut().local_addr().unwrap() {
+        SocketAddr::V4(_) => "1",
+        SocketAddr::V6(_) => "2",
+    };
+
+    if !args.is_empty() && args != proto {
+        write_response(client,
+                       &format!("{} Network protocol not supported, use ({})\r\n",
+                                UNSUPPORTED_PROTOCOL,
+                                proto)).await;
+        return false;
+    }
 
+    write_response(client,
+                   &format!("{} Entering Extended Passive Mode (|||{}|)\r\n",
+                            EXTENDED_PASSIVE_MODE,
+                            data_port)).await;
+    true
 }
 
-//REFRACTOR: Consider turning type into an ENUM
-pub fn handle_type(client: &mut BufReader<TcpStream>, args: &str) -> String {
-    match args {
-        "i" | "I" => {
-            write_response(client, &format!("{} Type set to I\r\n", OPERATION_SUCCESS));
-            info!("Switching type to binary");
-            return "BINARY".to_string();
+// PORT, the client gives the address the data connection goes out to
+pub async fn handle_port(client: &mut BufReader<FtpStream>,
+                         args: &str,
+                         peer: IpAddr)
+                         -> Option<SocketAddr> {
+    match port_addr(args).and_then(|addr| active_addr(addr, peer)) {
+        Ok(addr) => {
+            write_response(client,
+                           &format!("{} Port command successful\r\n", OPERATION_SUCCESS)).await;
+            Some(addr)
+        }
+        Err(reply) => {
+            write_response(client, &reply).await;
+            None
         }
-        "a" | "A" => {
+    }
+}
 
-            write_response(client, &format!("{} Type set to A\r\n", OPERATION_SUCCESS));
+/// Parses PORT arguments such as `132,235,1,2,24,131`, four numbers for the
+/// address and two for the port. Errors come back as a reply
+pub fn port_addr(args: &str) -> Result<SocketAddr, String> {
+    let nums: Vec<u8> = match args.split(',').map(|x| x.trim().parse::<u8>()).collect() {
+        Ok(nums) => nums,
+        Err(_) => return Err(format!("{} Syntax error in PORT arguments\r\n", BAD_SEQUENCE)),
+    };
+    if nums.len() != 6 {
+        return Err(format!("{} Syntax error in PORT arguments\r\n", BAD_SEQUENCE));
+    }
 
-            info!("Switching type to ASCII");
-            return "ASCII".to_string();
+    let ip = Ipv4Addr::new(nums[0], nums[1], nums[2], nums[3]);
+    let port = to_ftp_port(nums[4] as u16, nums[5] as u16);
+    Ok(SocketAddr::new(IpAddr::V4(ip), port))
+}
+
+/// Only lets an active data connection go back to the client itself, and not
+/// to a privileged port, so the server can't be used to bounce connections
+/// to other hosts (RFC 2577)
+pub fn active_addr(addr: SocketAddr, peer: IpAddr) -> Result<SocketAddr, String> {
+    if !same_host(addr.ip(), peer) {
+        return Err(format!("{} Data connections only go back to {}\r\n",
+                           NOT_IMPLEMENTED_FOR_PARAMETER,
+                           peer.to_canonical()));
+    }
+    if addr.port() < 1024 {
+        return Err(format!("{} Data connections don't go to ports below 1024\r\n",
+                           NOT_IMPLEMENTED_FOR_PARAMETER));
+    }
+
+    Ok(addr)
+}
+
+/// Whether two addresses are the same host, an IPv4 client on a dual stack
+/// socket shows up as an IPv4-mapped IPv6 address
+pub fn same_host(a: IpAddr, b: IpAddr) -> bool {
+    a.to_canonical() == b.to_canonical()
+}
+
+// EPRT (RFC 2428), the extended PORT that takes IPv6 addresses as well
+pub async fn handle_eprt(client: &mut BufReader<FtpStream>,
+                         args: &str,
+                         peer: IpAddr)
+                         -> Option<SocketAddr> {
+    match eprt_addr(args).and_then(|addr| active_addr(addr, peer)) {
+        Ok(addr) => {
+            write_response(client,
+                           &format!("{} EPRT command successful\r\n", OPERATION_SUCCESS)).await;
+            Some(addr)
+        }
+        Err(reply) => {
+            write_response(client, &reply).await;
+            None
         }
-        _ => return "".to_string(),
     }
 }
 
+/// Parses EPRT arguments such as `|1|132.235.1.2|6275|` or `|2|::1|6275|`,
+/// the first character being the delimiter. Errors come back as a reply
+pub fn eprt_addr(args: &str) -> Result<SocketAddr, String> {
+    let syntax_error = format!("{} Syntax error in EPRT arguments\r\n", BAD_SEQUENCE);
+
+    let delim = match args.chars().next() {
+        Some(delim) => delim,
+        None => return Err(syntax_error),
+    };
+    let fields: Vec<&str> = args.split(delim).collect();
+    if fields.len() != 5 || !fields[0].is_empty() || !fields[4].is_empty() {
+        return Err(syntax_error);
+    }
 
-//REFRACTOR: Redo this logic to for more succinct code
-pub fn handle_mode(client: &mut BufReader<TcpStream>, ftp_mode: FtpMode, data_port: &i32) {
+    let ip = match (fields[1], fields[2].parse::<IpAddr>()) {
+        ("1", Ok(ip @ IpAddr::V4(_))) => ip,
+        ("2", Ok(ip @ IpAddr::V6(_))) => ip,
+        ("1", _) | ("2", _) => return Err(syntax_error),
+        _ => {
+            return Err(format!("{} Network protocol not supported, use (1,2)\r\n",
+                               UNSUPPORTED_PROTOCOL))
+        }
+    };
 
-    match ftp_mode {
-        FtpMode::Passive => {
-            let ip = format!("{}", client.get_mut().local_addr().unwrap().ip()).replace(".", ",");
-            let (port1, port2) = split_port(data_port.clone() as u16);
+    match fields[3].parse::<u16>() {
+        Ok(port) if port > 0 => Ok(SocketAddr::new(ip, port)),
+        _ => Err(syntax_error),
+    }
+}
+
+//`entries` is what the backend listed under `path`
+pub fn ftp_ls(entries: &[(String, Metadata)], stream: &mut dyn Write, path: &str) {
+    println!("cur_dir {}", path);
+
+    for (name, meta) in entries {
+        let line = format!("{}\t{}B\t{}", meta.mode, meta.len, storage::join(path, name));
+
+        stream.write(format!("{}\r\n", line).as_bytes()).unwrap();
+    }
 
+}
+
+//Sends one MLSD line per directory entry over the data connection
+pub fn ftp_mlsd(entries: &[(String, Metadata)],
+                user: &User,
+                stream: &mut dyn Write,
+                path: &str) {
+    for (name, meta) in entries {
+        let line = format!("{} {}\r\n",
+                           mlst_facts(meta, user, &storage::join(path, name)),
+                           name);
+        stream.write_all(line.as_bytes()).expect("Could not write listing");
+    }
+}
+
+//Builds the RFC 3659 fact list of a file, e.g. "type=file;size=12;...;".
+//The perm fact lists what the user may do to it, a read-only file only
+//lets them look
+pub fn mlst_facts(meta: &Metadata, user: &User, path: &str) -> String {
+    let (kind, facts) = if meta.is_dir {
+        ("dir",
+         vec![('c', Capability::Write),
+              ('d', Capability::Delete),
+              ('e', Capability::List),
+              ('f', Capability::Rename),
+              ('l', Capability::List),
+              ('m', Capability::Mkdir),
+              ('p', Capability::Rmdir)])
+    } else {
+        ("file",
+         vec![('a', Capability::Append),
+              ('d', Capability::Delete),
+              ('f', Capability::Rename),
+              ('r', Capability::Read),
+              ('w', Capability::Write)])
+    };
+    let perm: String = facts.into_iter()
+        .filter(|&(_, cap)| !meta.readonly || cap == Capability::List || cap == Capability::Read)
+        .filter(|&(_, cap)| user.can(cap, path))
+        .map(|(letter, _)| letter)
+        .collect();
+
+    format!("type={};size={};modify={};perm={};unique={};",
+            kind,
+            meta.len,
+            mdtm_timestamp(meta.modified),
+            perm,
+            meta.unique)
+}
+
+/// Path of `args` as the user sees it, taken from their current directory.
+/// Absolute paths start at the user's root and `..` never climbs above it,
+/// the storage backend keeps symlinks from leading out of it
+pub fn resolve_path(user: &User, args: &str) -> String {
+    storage::join(&user.cur_dir, args)
+}
+
+//Handles STAT, reporting on the running transfer if there is one
+pub async fn stat(client: &mut BufReader<FtpStream>,
+                  user: &User,
+                  ftp_type: &str,
+                  transfer: &Option<Transfer>) {
+    match *transfer {
+        Some(ref running) => {
+            write_response(client,
+                           &format!("{} Status: {}, {} bytes transferred\r\n",
+                                    FILE_STATUS,
+                                    running.command,
+                                    running.state.bytes())).await;
+        }
+        None => {
             write_response(client,
-                           &format!("{} Entering Passive Mode ({},{},{}).\r\n",
-                                    PASSIVE_MODE,
-                                    ip,
-                                    port1,
-                                    port2));
+                           &format!("{}-FTP server status:\r\n Logged in as {}\r\n TYPE: \
+                                     {}\r\n No data connection\r\n{} End of status\r\n",
+                                    SYSTEM_STATUS,
+                                    user.name,
+                                    ftp_type,
+                                    SYSTEM_STATUS)).await;
         }
+    }
+}
 
-        FtpMode::Active(_) => {
+//Handles SITE, QUOTA being the only subcommand for now
+pub async fn site(client: &mut BufReader<FtpStream>, args: &str, user: &User) {
+    match args.trim().to_uppercase().as_ref() {
+        "QUOTA" => {
+            let usage = user.quota.usage();
+            let limit = |max: Option<u64>| {
+                max.map_or("unlimited".to_string(), |max| max.to_string())
+            };
             write_response(client,
-                           &format!("{} Port command successful\r\n",
+                           &format!("{}-Quota for {}\r\n Bytes: {} of {}\r\n Files: {} of \
+                                     {}\r\n{} End\r\n",
                                     OPERATION_SUCCESS,
-                                    ));
+                                    user.name,
+                                    usage.bytes,
+                                    limit(user.quota.max_bytes),
+                                    usage.files,
+                                    limit(user.quota.max_files),
+                                    OPERATION_SUCCESS)).await;
+        }
+        _ => {
+            write_response(client,
+                           &format!("{} SITE {} not understood\r\n",
+                                    NOT_IMPLEMENTED_FOR_PARAMETER,
+                                    args)).await;
         }
     }
 }
 
+//Replies with the list of supported extensions
+pub async fn feat(client: &mut BufReader<FtpStream>, tls: bool) {
+    let mut msg = format!("{}-Features:\r\n", SYSTEM_STATUS);
+    for feature in FEATURES {
+        msg.push_str(&format!(" {}\r\n", feature));
+    }
+    if tls {
+        msg.push_str(" AUTH TLS\r\n PBSZ\r\n PROT\r\n");
+    }
+    msg.push_str(&format!("{} End\r\n", SYSTEM_STATUS));
 
+    write_response(client, &msg).await;
+}
 
+//What goes over the data connection in a given MODE. It is encoded in
+//memory and sent from there, so the data connection is only ever written
+//to without blocking
+enum Encoder {
+    Stream(Vec<u8>),
+    Deflate(ZlibEncoder<Vec<u8>>),
+    Block(BlockWriter<Vec<u8>>),
+}
 
-pub fn ftp_ls(user: &User, mut stream: &mut TcpStream, args: &str) {
-    //HANDLE not a directory
-    let mut cur_dir = format!("{}", user.cur_dir);
+impl Encoder {
+    //Under MODE B, `markers` is the file offset restart markers count from
+    fn new(mode: TransferMode, markers: Option<u64>) -> Encoder {
+        match (mode, markers) {
+            (TransferMode::Stream, _) => Encoder::Stream(Vec::new()),
+            (TransferMode::Deflate(level), _) => {
+                Encoder::Deflate(ZlibEncoder::new(Vec::new(), Compression::new(level)))
+            }
+            (TransferMode::Block, Some(offset)) => {
+                Encoder::Block(BlockWriter::with_markers(Vec::new(), offset))
+            }
+            (TransferMode::Block, None) => Encoder::Block(BlockWriter::new(Vec::new())),
+        }
+    }
 
-    if !args.is_empty() {
-        cur_dir = format!("{}/{}", user.cur_dir, args);
+    //Encoded bytes that have not been sent yet
+    fn pending(&mut self) -> &mut Vec<u8> {
+        match *self {
+            Encoder::Stream(ref mut buf) => buf,
+            Encoder::Deflate(ref mut encoder) => encoder.get_mut(),
+            Encoder::Block(ref mut blocks) => blocks.get_mut(),
+        }
     }
 
-    let path = Path::new(&cur_dir);
+    //Ends the zlib stream or sends the EOF block
+    fn finish(&mut self) -> io::Result<()> {
+        match *self {
+            Encoder::Stream(_) => Ok(()),
+            Encoder::Deflate(ref mut encoder) => encoder.try_finish(),
+            Encoder::Block(ref mut blocks) => blocks.finish(),
+        }
+    }
 
-    println!("cur_dir {}", path.display());
-    let paths = fs::read_dir(path).expect("Could not read directory for listing {}");
+    //Sends what is pending
+    async fn send(&mut self, stream: &mut FtpStream, state: &TransferState) -> io::Result<()> {
+        let pending = self.pending();
+        if !pending.is_empty() {
+            state.unless_aborted(stream.write_all(pending)).await?;
+            pending.clear();
+        }
+        Ok(())
+    }
+}
 
-    for path in paths {
-        let path = path.unwrap().path();
-        let shortpath = path.to_str().unwrap();
-        let pos = shortpath.find("ftproot").unwrap(); //Possible improvement here(error checking)
+impl Write for Encoder {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match *self {
+            Encoder::Stream(ref mut pending) => {
+                pending.extend_from_slice(buf);
+                Ok(buf.len())
+            }
+            Encoder::Deflate(ref mut encoder) => encoder.write(buf),
+            Encoder::Block(ref mut blocks) => blocks.write(buf),
+        }
+    }
 
-        let meta = path.metadata().unwrap();
-        let line = format!("{}\t{}B\t{}",
-                           meta.permissions().mode(),
-                           meta.len(),
-                           &shortpath[pos + 7..]);
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
 
-        stream.write(format!("{}\r\n", line).as_bytes()).unwrap();
+/// Sends the file, read from `offset` on, over the data connection in the
+/// given MODE. The bytes counted and throttled are the file's, before any
+/// compression or block headers
+pub async fn write_to_stream<R>(file: &mut Blocking<R>,
+                                stream: &mut FtpStream,
+                                mode: TransferMode,
+                                offset: u64,
+                                throttle: &Throttle,
+                                state: &TransferState)
+                                -> io::Result<()>
+    where R: Read + Send + 'static
+{
+    //Files get restart markers, listings don't
+    let mut encoder = Encoder::new(mode, Some(offset));
+
+    loop {
+        let buf = file.read(1024).await?;
+        let n = buf.len();
+        if n == 0 || state.aborted() {
+            break;
+        }
+        encoder.write_all(&buf)?;
+        encoder.send(stream, state).await?;
+        state.add_bytes(n);
+        METRICS.sent(n);
+        throttle.download(n).await;
     }
 
+    //An aborted transfer is cut off without the end of the data
+    if state.aborted() {
+        return Ok(());
+    }
+    encoder.finish()?;
+    encoder.send(stream, state).await?;
+    state.unless_aborted(stream.flush()).await
 }
 
-pub fn write_to_stream(file: &mut File, stream: &mut TcpStream) {
-    let mut buf = vec![0; 1024];
-    let mut done = false;
-    while !done {
-        let n = file.read(&mut buf).expect("Could not read local file");
-        if n > 0 {
-            stream.write_all(&buf[..n]).expect("Could not write to remote locatio");
-        } else {
-            done = true;
+/// Lets `write` send data in the given MODE, under MODE Z what it writes
+/// is deflated and the zlib stream ended once it returns, under MODE B it
+/// goes in blocks followed by the EOF one. It all goes out once `write`
+/// returns
+pub async fn write_encoded<F>(stream: &mut FtpStream,
+                               mode: TransferMode,
+                               write: F)
+                               -> io::Result<()>
+    where F: FnOnce(&mut dyn Write) -> io::Result<()>
+{
+    let mut encoder = Encoder::new(mode, None);
+    write(&mut encoder)?;
+    encoder.finish()?;
+
+    stream.write_all(encoder.pending()).await?;
+    stream.flush().await
+}
+
+/// Stores what comes in over the data connection in the given MODE,
+/// inflating it under MODE Z. Under MODE B each restart marker is answered
+/// with a 110 reply giving the matching offset in the file, which was
+/// written from `offset` on
+pub async fn write_to_file<W>(file: &mut Blocking<W>,
+                              stream: &mut FtpStream,
+                              mode: TransferMode,
+                              offset: u64,
+                              throttle: &Throttle,
+                              state: &TransferState,
+                              replies: &UnboundedSender<String>)
+                              -> io::Result<()>
+    where W: Write + Send + 'static
+{
+    match mode {
+        TransferMode::Stream => copy_to_file(file, stream, None, throttle, state).await,
+        TransferMode::Deflate(_) => {
+            let inflate = ZlibDecoder::new(Vec::new());
+            copy_to_file(file, stream, Some(inflate), throttle, state).await
+        }
+        TransferMode::Block => {
+            let mut blocks = BlockDecoder::new();
+            let mut buf = vec![0; 1024];
+            loop {
+                //Never more than the current block, whatever follows the EOF
+                //one is the next transfer's
+                let want = blocks.wanted().min(buf.len());
+                if want == 0 {
+                    return Ok(());
+                }
+                let n = state.unless_aborted(stream.read(&mut buf[..want])).await?;
+                if n == 0 {
+                    return Err(io::Error::new(io::ErrorKind::UnexpectedEof,
+                                              "Data connection closed inside a block"));
+                }
+
+                let data = blocks.decode(&buf[..n]);
+                if let Some((marker, at)) = blocks.take_marker() {
+                    file.flush().await?;
+                    transfer::queue_response(replies,
+                                             &format!("{} MARK {} = {}\r\n",
+                                                      RESTART_MARKER,
+                                                      marker,
+                                                      offset + at));
+                }
+                if state.aborted() {
+                    return Ok(());
+                }
+                store(file, data, throttle, state).await?;
+            }
         }
     }
 }
 
-pub fn write_to_file(file: &mut File, stream: &mut TcpStream) {
+//Copies the data connection into the file until the client closes it,
+//inflating it on the way when there is a decoder
+async fn copy_to_file<W>(file: &mut Blocking<W>,
+                         stream: &mut FtpStream,
+                         mut inflate: Option<ZlibDecoder<Vec<u8>>>,
+                         throttle: &Throttle,
+                         state: &TransferState)
+                         -> io::Result<()>
+    where W: Write + Send + 'static
+{
     let mut buf = vec![0; 1024];
-    let mut done = false;
-    while !done {
-        let n = stream.read(&mut buf).expect("Could not read remote file");
-        if n > 0 {
-            file.write_all(&buf[..n]).expect("Could not write to local locatio");
-        } else {
-            done = true;
+    loop {
+        let n = state.unless_aborted(stream.read(&mut buf)).await?;
+        if n == 0 || state.aborted() {
+            break;
+        }
+        match inflate {
+            Some(ref mut decoder) => {
+                decoder.write_all(&buf[..n])?;
+                let inflated = mem::take(decoder.get_mut());
+                store(file, &inflated, throttle, state).await?;
+            }
+            None => store(file, &buf[..n], throttle, state).await?,
         }
     }
+
+    match inflate {
+        Some(ref mut decoder) if !state.aborted() => {
+            decoder.try_finish()?;
+            let inflated = mem::take(decoder.get_mut());
+            store(file, &inflated, throttle, state).await
+        }
+        _ => Ok(()),
+    }
 }
 
-pub fn append_to_file(file: &mut File, stream: &mut TcpStream) {
-    let mut client = BufReader::new(stream);
+//Writes a piece of an upload to the file and accounts for it
+async fn store<W>(file: &mut Blocking<W>,
+                  data: &[u8],
+                  throttle: &Throttle,
+                  state: &TransferState)
+                  -> io::Result<()>
+    where W: Write + Send + 'static
+{
+    file.write_all(data).await?;
+    state.add_bytes(data.len());
+    METRICS.received(data.len());
+    throttle.upload(data.len()).await;
+    Ok(())
+}
+
+pub async fn append_to_file(file: &mut File, stream: &mut FtpStream) {
     let mut buf_bytes = Vec::new();
 
     println!("Trying to read meassage");
-    client.read_to_end(&mut buf_bytes).expect("could nto read message");
+    stream.read_to_end(&mut buf_bytes).await.expect("could nto read message");
     println!("Message read");
 
     println!("Trying to write meassage");
@@ -350,6 +1083,65 @@ pub fn append_to_file(file: &mut File, stream: &mut TcpStream) {
 }
 
 
+//Number of bytes a RETR of the file would send under the given TYPE.
+//In ASCII mode every bare LF goes out as CRLF so those are counted twice.
+//It reads the whole file, so it goes through Storage::run
+pub fn transfer_size(storage: &dyn StorageBackend, path: &str, ftp_type: &str) -> io::Result<u64> {
+    let meta = storage.stat(path)?;
+
+    if ftp_type != "ASCII" {
+        return Ok(meta.len);
+    }
+
+    let file = storage.open_read(path, 0)?;
+    let mut size = 0;
+    let mut last = 0;
+    for byte in io::BufReader::new(file).bytes() {
+        let byte = byte?;
+        if byte == b'\n' && last != b'\r' {
+            size += 1;
+        }
+        size += 1;
+        last = byte;
+    }
+
+    Ok(size)
+}
+
+//Formats a time as the YYYYMMDDHHMMSS UTC timestamp used by MDTM (RFC 3659)
+pub fn mdtm_timestamp(time: SystemTime) -> String {
+    let secs = match time.duration_since(UNIX_EPOCH) {
+        Ok(elapsed) => elapsed.as_secs(),
+        Err(_) => 0,
+    };
+
+    let (year, month, day) = civil_from_days((secs / 86400) as i64);
+    let rem = secs % 86400;
+
+    format!("{:04}{:02}{:02}{:02}{:02}{:02}",
+            year,
+            month,
+            day,
+            rem / 3600,
+            (rem % 3600) / 60,
+            rem % 60)
+}
+
+//Converts days since the unix epoch to a (year, month, day) date
+pub fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719468;
+    let era = if z >= 0 { z } else { z - 146096 } / 146097;
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    (year, month, day)
+}
+
 //utility operation to combine high and low ports
 pub fn to_ftp_port(b1: u16, b2: u16) -> u16 {
     b1 * 256 + b2
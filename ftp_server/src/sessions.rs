@@ -0,0 +1,250 @@
+//! Connected clients, as the admin port sees them. Every session registers
+//! here for as long as it runs and keeps its user, directory and transfers
+//! up to date, so it can be listed, kicked or waited for on shutdown
+
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use tokio::sync::Notify;
+
+use crate::transfer::TransferState;
+
+/// Tells a session to stop reading commands. It stays set, so a session
+/// that only gets to wait for it later still sees it
+#[derive(Debug, Default)]
+pub struct Hangup {
+    set: AtomicBool,
+    notify: Notify,
+}
+
+impl Hangup {
+    pub fn new() -> Hangup {
+        Hangup::default()
+    }
+
+    pub fn hang_up(&self) {
+        self.set.store(true, Ordering::SeqCst);
+        self.notify.notify_waiters();
+    }
+
+    pub fn is_set(&self) -> bool {
+        self.set.load(Ordering::SeqCst)
+    }
+
+    /// Resolves once `hang_up` has been called
+    pub async fn wait(&self) {
+        let notified = self.notify.notified();
+        if !self.is_set() {
+            notified.await;
+        }
+    }
+}
+
+/// Bytes and transfers of a session so far
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Stats {
+    pub transfers: u64,
+    pub uploaded: u64,
+    pub downloaded: u64,
+}
+
+struct Activity {
+    user: String,
+    cwd: String,
+    current: Option<(String, TransferState)>,
+    //Of the transfers that are over
+    done: Stats,
+}
+
+pub struct Session {
+    pub id: u64,
+    pub peer: SocketAddr,
+    //Set to wake the session up from reading its next command
+    pub hangup: Hangup,
+    //Why the session was closed under it, told to the client
+    kicked: Mutex<Option<String>>,
+    activity: Mutex<Activity>,
+}
+
+impl Session {
+    /// Remembers who is logged in and where they are
+    pub fn update(&self, user: &str, cwd: &str) {
+        let mut activity = self.activity.lock().unwrap();
+        activity.user = user.to_string();
+        activity.cwd = cwd.to_string();
+    }
+
+    /// Records a transfer the session just started, the one before it is
+    /// over by then
+    pub fn start_transfer(&self, command: &str, state: &TransferState) {
+        let mut activity = self.activity.lock().unwrap();
+        if let Some((command, state)) = activity.current.take() {
+            add_transfer(&mut activity.done, &command, state.bytes());
+        }
+        activity.current = Some((command.to_string(), state.clone()));
+    }
+
+    pub fn user(&self) -> String {
+        self.activity.lock().unwrap().user.clone()
+    }
+
+    pub fn cwd(&self) -> String {
+        self.activity.lock().unwrap().cwd.clone()
+    }
+
+    /// The running transfer and the bytes it moved so far
+    pub fn transfer(&self) -> Option<(String, u64)> {
+        match self.activity.lock().unwrap().current {
+            Some((ref command, ref state)) if !state.done() => {
+                Some((command.clone(), state.bytes()))
+            }
+            _ => None,
+        }
+    }
+
+    pub fn stats(&self) -> Stats {
+        let activity = self.activity.lock().unwrap();
+        let mut stats = activity.done;
+        if let Some((ref command, ref state)) = activity.current {
+            add_transfer(&mut stats, command, state.bytes());
+        }
+        stats
+    }
+
+    /// Aborts the running transfer and ends the session, the client gets a
+    /// 421 with the reason
+    pub fn kick(&self, reason: &str) {
+        *self.kicked.lock().unwrap() = Some(reason.to_string());
+        self.abort_transfer();
+        self.close();
+    }
+
+    pub fn kicked(&self) -> Option<String> {
+        self.kicked.lock().unwrap().clone()
+    }
+
+    fn abort_transfer(&self) {
+        if let Some((_, ref state)) = self.activity.lock().unwrap().current {
+            state.abort();
+        }
+    }
+
+    //Makes the session stop at its next read, it still finishes what it is
+    //doing
+    fn close(&self) {
+        self.hangup.hang_up();
+    }
+}
+
+fn add_transfer(stats: &mut Stats, command: &str, bytes: u64) {
+    stats.transfers += 1;
+    match command.split(' ').next().unwrap_or("").to_lowercase().as_ref() {
+        "retr" => stats.downloaded += bytes,
+        _ => stats.uploaded += bytes,
+    }
+}
+
+struct Registry {
+    sessions: Mutex<BTreeMap<u64, Arc<Session>>>,
+    next_id: AtomicU64,
+    paused: AtomicBool,
+    stopping: AtomicBool,
+}
+
+/// Every running session, shared by the listeners and the admin port
+#[derive(Clone)]
+pub struct Sessions {
+    registry: Arc<Registry>,
+}
+
+impl Sessions {
+    pub fn new() -> Sessions {
+        Sessions {
+            registry: Arc::new(Registry {
+                sessions: Mutex::new(BTreeMap::new()),
+                next_id: AtomicU64::new(1),
+                paused: AtomicBool::new(false),
+                stopping: AtomicBool::new(false),
+            }),
+        }
+    }
+
+    /// Adds a session for a new client, it stays listed until the returned
+    /// registration is dropped
+    pub fn register(&self, peer: SocketAddr) -> Registration {
+        let session = Arc::new(Session {
+            id: self.registry.next_id.fetch_add(1, Ordering::SeqCst),
+            peer: peer,
+            hangup: Hangup::new(),
+            kicked: Mutex::new(None),
+            activity: Mutex::new(Activity {
+                user: String::new(),
+                cwd: String::new(),
+                current: None,
+                done: Stats::default(),
+            }),
+        });
+        self.registry.sessions.lock().unwrap().insert(session.id, session.clone());
+
+        Registration {
+            sessions: self.clone(),
+            session: session,
+        }
+    }
+
+    pub fn list(&self) -> Vec<Arc<Session>> {
+        self.registry.sessions.lock().unwrap().values().cloned().collect()
+    }
+
+    pub fn get(&self, id: u64) -> Option<Arc<Session>> {
+        self.registry.sessions.lock().unwrap().get(&id).cloned()
+    }
+
+    pub fn len(&self) -> usize {
+        self.registry.sessions.lock().unwrap().len()
+    }
+
+    /// Stops or resumes taking new logins
+    pub fn set_paused(&self, paused: bool) {
+        self.registry.paused.store(paused, Ordering::SeqCst);
+    }
+
+    pub fn paused(&self) -> bool {
+        self.registry.paused.load(Ordering::SeqCst)
+    }
+
+    /// Turns new clients away and has every session end once its running
+    /// transfer is done
+    pub fn stop(&self) {
+        self.registry.stopping.store(true, Ordering::SeqCst);
+        for session in self.list() {
+            session.close();
+        }
+    }
+
+    /// Aborts every running transfer, for sessions that outlast the grace
+    /// period of a shutdown
+    pub fn abort_transfers(&self) {
+        for session in self.list() {
+            session.abort_transfer();
+        }
+    }
+
+    pub fn stopping(&self) -> bool {
+        self.registry.stopping.load(Ordering::SeqCst)
+    }
+}
+
+/// A session's place in the list
+pub struct Registration {
+    sessions: Sessions,
+    pub session: Arc<Session>,
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        self.sessions.registry.sessions.lock().unwrap().remove(&self.session.id);
+    }
+}
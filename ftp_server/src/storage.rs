@@ -0,0 +1,609 @@
+//! Where the files of a session live. Handlers only deal in paths the way
+//! the client sees them ("/", "/sub/file.txt") and go through a
+//! `StorageBackend`, so the same commands run against the disk or memory
+
+use std::collections::BTreeMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::prelude::*;
+use std::io::{self, Cursor, ErrorKind, SeekFrom};
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+/// What the handlers get to know about a file or directory
+#[derive(Debug, Clone)]
+pub struct Metadata {
+    pub is_dir: bool,
+    pub len: u64,
+    pub modified: SystemTime,
+    /// Unix mode bits as shown by LIST
+    pub mode: u32,
+    pub readonly: bool,
+    /// Identifies the file for as long as it exists, MLST's unique fact
+    pub unique: String,
+}
+
+/// Operations the FTP commands need from a file store. Every path is
+/// absolute and rooted at the user's home, as returned by `join`
+pub trait StorageBackend: Send + Sync {
+    /// Names and metadata of the entries in a directory
+    fn list(&self, path: &str) -> io::Result<Vec<(String, Metadata)>>;
+    fn stat(&self, path: &str) -> io::Result<Metadata>;
+    /// Opens a file to be read from `offset` on
+    fn open_read(&self, path: &str, offset: u64) -> io::Result<Box<dyn Read + Send>>;
+    /// Opens a file to be written from `offset` on, creating it if needed.
+    /// Only writing from the start truncates it
+    fn open_write(&self, path: &str, offset: u64) -> io::Result<Box<dyn Write + Send>>;
+    /// Opens a file to be written at its end, creating it if needed
+    fn append(&self, path: &str) -> io::Result<Box<dyn Write + Send>>;
+    fn rename(&self, from: &str, to: &str) -> io::Result<()>;
+    /// Removes a file
+    fn remove(&self, path: &str) -> io::Result<()>;
+    /// Creates a directory along with any missing parents
+    fn mkdir(&self, path: &str) -> io::Result<()>;
+    /// Removes an empty directory
+    fn rmdir(&self, path: &str) -> io::Result<()>;
+}
+
+/// The backend of a session as the async handlers use it. Each call runs on
+/// tokio's blocking threads, so a slow disk holds up only the session
+/// waiting on it and not every other one sharing the worker
+#[derive(Clone)]
+pub struct Storage {
+    backend: Arc<dyn StorageBackend>,
+}
+
+impl Storage {
+    pub fn new(backend: Arc<dyn StorageBackend>) -> Storage {
+        Storage { backend: backend }
+    }
+
+    /// Runs `work` against the backend on a blocking thread
+    pub async fn run<T, F>(&self, work: F) -> io::Result<T>
+        where F: FnOnce(&dyn StorageBackend) -> io::Result<T> + Send + 'static,
+              T: Send + 'static
+    {
+        let backend = self.backend.clone();
+        blocking(move || work(&*backend)).await
+    }
+
+    pub async fn list(&self, path: &str) -> io::Result<Vec<(String, Metadata)>> {
+        let path = path.to_string();
+        self.run(move |backend| backend.list(&path)).await
+    }
+
+    pub async fn stat(&self, path: &str) -> io::Result<Metadata> {
+        let path = path.to_string();
+        self.run(move |backend| backend.stat(&path)).await
+    }
+
+    pub async fn open_read(&self, path: &str, offset: u64) -> io::Result<Box<dyn Read + Send>> {
+        let path = path.to_string();
+        self.run(move |backend| backend.open_read(&path, offset)).await
+    }
+
+    pub async fn open_write(&self,
+                            path: &str,
+                            offset: u64)
+                            -> io::Result<Box<dyn Write + Send>> {
+        let path = path.to_string();
+        self.run(move |backend| backend.open_write(&path, offset)).await
+    }
+
+    pub async fn append(&self, path: &str) -> io::Result<Box<dyn Write + Send>> {
+        let path = path.to_string();
+        self.run(move |backend| backend.append(&path)).await
+    }
+
+    pub async fn rename(&self, from: &str, to: &str) -> io::Result<()> {
+        let (from, to) = (from.to_string(), to.to_string());
+        self.run(move |backend| backend.rename(&from, &to)).await
+    }
+
+    pub async fn remove(&self, path: &str) -> io::Result<()> {
+        let path = path.to_string();
+        self.run(move |backend| backend.remove(&path)).await
+    }
+
+    pub async fn mkdir(&self, path: &str) -> io::Result<()> {
+        let path = path.to_string();
+        self.run(move |backend| backend.mkdir(&path)).await
+    }
+
+    pub async fn rmdir(&self, path: &str) -> io::Result<()> {
+        let path = path.to_string();
+        self.run(move |backend| backend.rmdir(&path)).await
+    }
+}
+
+/// A file opened by the backend, or anything wrapped around one, whose
+/// reads and writes run on the blocking threads
+pub struct Blocking<T> {
+    //Away on a blocking thread while a call is running
+    inner: Option<T>,
+}
+
+impl<T: Send + 'static> Blocking<T> {
+    pub fn new(inner: T) -> Blocking<T> {
+        Blocking { inner: Some(inner) }
+    }
+
+    /// Runs `work` on the file on a blocking thread
+    pub async fn run<R, F>(&mut self, work: F) -> io::Result<R>
+        where F: FnOnce(&mut T) -> io::Result<R> + Send + 'static,
+              R: Send + 'static
+    {
+        let mut inner = self.inner
+            .take()
+            .ok_or_else(|| io::Error::new(ErrorKind::Other, "An earlier call was cut off"))?;
+        let (inner, result) = blocking(move || {
+                let result = work(&mut inner);
+                Ok((inner, result))
+            })
+            .await?;
+        self.inner = Some(inner);
+        result
+    }
+}
+
+impl<T: Read + Send + 'static> Blocking<T> {
+    /// Reads up to `len` bytes
+    pub async fn read(&mut self, len: usize) -> io::Result<Vec<u8>> {
+        self.run(move |file| {
+                let mut buf = vec![0; len];
+                let n = file.read(&mut buf)?;
+                buf.truncate(n);
+                Ok(buf)
+            })
+            .await
+    }
+}
+
+impl<T: Write + Send + 'static> Blocking<T> {
+    pub async fn write_all(&mut self, data: &[u8]) -> io::Result<()> {
+        let data = data.to_vec();
+        self.run(move |file| file.write_all(&data)).await
+    }
+
+    pub async fn flush(&mut self) -> io::Result<()> {
+        self.run(|file| file.flush()).await
+    }
+}
+
+//Runs blocking I/O on tokio's blocking threads
+async fn blocking<T, F>(work: F) -> io::Result<T>
+    where F: FnOnce() -> io::Result<T> + Send + 'static,
+          T: Send + 'static
+{
+    tokio::task::spawn_blocking(work)
+        .await
+        .unwrap_or_else(|e| Err(io::Error::new(ErrorKind::Other, e)))
+}
+
+/// Joins a path given by the client onto the current directory. The result
+/// is absolute with no `.` or `..` left in it, and `..` stops at `/`
+pub fn join(cur_dir: &str, args: &str) -> String {
+    let start = match args.starts_with('/') {
+        true => "",
+        false => cur_dir,
+    };
+
+    let mut names: Vec<&str> = Vec::new();
+    for name in start.split('/').chain(args.split('/')) {
+        match name {
+            "" | "." => {}
+            ".." => {
+                names.pop();
+            }
+            _ => names.push(name),
+        }
+    }
+
+    format!("/{}", names.join("/"))
+}
+
+//The directory a joined path is in, "/" for the root itself
+fn parent(path: &str) -> &str {
+    match path.rfind('/') {
+        Some(0) | None => "/",
+        Some(pos) => &path[..pos],
+    }
+}
+
+fn not_found(path: &str) -> io::Error {
+    io::Error::new(ErrorKind::NotFound, format!("{} does not exist", path))
+}
+
+/// Files on the local disk below a root directory
+pub struct LocalDisk {
+    root: PathBuf,
+}
+
+impl LocalDisk {
+    pub fn new<P: AsRef<Path>>(root: P) -> LocalDisk {
+        LocalDisk { root: root.as_ref().to_path_buf() }
+    }
+
+    /// Maps a path onto the disk inside the root. The part that exists is
+    /// canonicalized so symlinks are followed, anything that lands outside
+    /// the root, dangling symlinks included, is refused
+    pub fn real_path(&self, path: &str) -> io::Result<PathBuf> {
+        let root = fs::canonicalize(&self.root)?;
+        let denied = || {
+            info!("{} leads outside of {}", path, root.display());
+            io::Error::new(ErrorKind::PermissionDenied,
+                           format!("{} is outside the root", path))
+        };
+
+        let mut existing = root.clone();
+        for name in join("/", path).split('/').filter(|name| !name.is_empty()) {
+            existing.push(name);
+        }
+
+        //Names that don't exist yet (STOR, MKD, RNTO) are put back on after
+        //the existing part is canonicalized
+        let mut missing = Vec::new();
+        let real = loop {
+            match fs::canonicalize(&existing) {
+                Ok(real) => break real,
+                Err(_) => {
+                    if existing.symlink_metadata().is_ok() {
+                        return Err(denied());
+                    }
+                    match existing.file_name() {
+                        Some(name) => missing.push(name.to_os_string()),
+                        None => return Err(denied()),
+                    }
+                    existing.pop();
+                }
+            }
+        };
+
+        if !real.starts_with(&root) {
+            return Err(denied());
+        }
+
+        let mut real = real;
+        for name in missing.iter().rev() {
+            real.push(name);
+        }
+        Ok(real)
+    }
+}
+
+fn local_metadata(meta: &fs::Metadata) -> Metadata {
+    Metadata {
+        is_dir: meta.is_dir(),
+        len: meta.len(),
+        modified: meta.modified().unwrap_or(SystemTime::now()),
+        mode: meta.permissions().mode(),
+        readonly: meta.permissions().readonly(),
+        unique: format!("{:x}g{:x}", meta.dev(), meta.ino()),
+    }
+}
+
+impl StorageBackend for LocalDisk {
+    fn list(&self, path: &str) -> io::Result<Vec<(String, Metadata)>> {
+        let dir = self.real_path(path)?;
+        let mut entries = Vec::new();
+
+        for entry in fs::read_dir(dir)? {
+            let entry = match entry {
+                Ok(entry) => entry,
+                Err(_) => continue,
+            };
+            let name = entry.file_name().to_string_lossy().into_owned();
+
+            //Links out of the root and entries that vanished are left out
+            let meta = match self.real_path(&join(path, &name)).and_then(fs::metadata) {
+                Ok(meta) => meta,
+                Err(_) => continue,
+            };
+            entries.push((name, local_metadata(&meta)));
+        }
+
+        Ok(entries)
+    }
+
+    fn stat(&self, path: &str) -> io::Result<Metadata> {
+        let meta = fs::metadata(self.real_path(path)?)?;
+        Ok(local_metadata(&meta))
+    }
+
+    fn open_read(&self, path: &str, offset: u64) -> io::Result<Box<dyn Read + Send>> {
+        let mut file = File::open(self.real_path(path)?)?;
+        file.seek(SeekFrom::Start(offset))?;
+        Ok(Box::new(file))
+    }
+
+    fn open_write(&self, path: &str, offset: u64) -> io::Result<Box<dyn Write + Send>> {
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(offset == 0)
+            .open(self.real_path(path)?)?;
+        file.seek(SeekFrom::Start(offset))?;
+        Ok(Box::new(file))
+    }
+
+    fn append(&self, path: &str) -> io::Result<Box<dyn Write + Send>> {
+        let file = OpenOptions::new()
+            .append(true)
+            .create(true)
+            .open(self.real_path(path)?)?;
+        Ok(Box::new(file))
+    }
+
+    fn rename(&self, from: &str, to: &str) -> io::Result<()> {
+        fs::rename(self.real_path(from)?, self.real_path(to)?)
+    }
+
+    fn remove(&self, path: &str) -> io::Result<()> {
+        fs::remove_file(self.real_path(path)?)
+    }
+
+    fn mkdir(&self, path: &str) -> io::Result<()> {
+        fs::create_dir_all(self.real_path(path)?)
+    }
+
+    fn rmdir(&self, path: &str) -> io::Result<()> {
+        fs::remove_dir(self.real_path(path)?)
+    }
+}
+
+/// Files kept in memory and gone with the backend, handy for tests
+pub struct MemoryStorage {
+    tree: Mutex<MemoryTree>,
+}
+
+struct MemoryTree {
+    nodes: BTreeMap<String, Node>,
+    next_id: u64,
+}
+
+enum Node {
+    Dir(u64, SystemTime),
+    File(u64, Arc<Mutex<MemoryFile>>),
+}
+
+struct MemoryFile {
+    data: Vec<u8>,
+    modified: SystemTime,
+}
+
+//Writes into a file of a MemoryStorage, which sees the bytes right away
+struct MemoryWriter {
+    file: Arc<Mutex<MemoryFile>>,
+    pos: usize,
+}
+
+impl Write for MemoryWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut file = self.file.lock().unwrap();
+        let end = self.pos + buf.len();
+        if file.data.len() < end {
+            file.data.resize(end, 0);
+        }
+        file.data[self.pos..end].copy_from_slice(buf);
+        file.modified = SystemTime::now();
+        self.pos = end;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl MemoryStorage {
+    /// An empty store holding only the root directory
+    pub fn new() -> MemoryStorage {
+        let mut nodes = BTreeMap::new();
+        nodes.insert("/".to_string(), Node::Dir(0, SystemTime::now()));
+        MemoryStorage { tree: Mutex::new(MemoryTree { nodes: nodes, next_id: 1 }) }
+    }
+}
+
+impl MemoryTree {
+    fn new_id(&mut self) -> u64 {
+        self.next_id += 1;
+        self.next_id - 1
+    }
+
+    fn is_dir(&self, path: &str) -> bool {
+        match self.nodes.get(path) {
+            Some(&Node::Dir(..)) => true,
+            _ => false,
+        }
+    }
+
+    //The file at a path, created empty when its directory exists
+    fn file(&mut self, path: &str, truncate: bool) -> io::Result<Arc<Mutex<MemoryFile>>> {
+        if path == "/" || !self.is_dir(parent(path)) {
+            return Err(not_found(path));
+        }
+
+        let id = self.new_id();
+        let node = self.nodes.entry(path.to_string()).or_insert_with(|| {
+            Node::File(id,
+                       Arc::new(Mutex::new(MemoryFile {
+                           data: Vec::new(),
+                           modified: SystemTime::now(),
+                       })))
+        });
+        match *node {
+            Node::File(_, ref file) => {
+                if truncate {
+                    file.lock().unwrap().data.clear();
+                }
+                Ok(file.clone())
+            }
+            Node::Dir(..) => {
+                Err(io::Error::new(ErrorKind::Other, format!("{} is a directory", path)))
+            }
+        }
+    }
+
+    //Every path at or below the given one
+    fn subtree(&self, path: &str) -> Vec<String> {
+        let prefix = format!("{}/", path);
+        self.nodes
+            .keys()
+            .filter(|key| *key == path || key.starts_with(&prefix))
+            .cloned()
+            .collect()
+    }
+}
+
+fn memory_metadata(node: &Node) -> Metadata {
+    match *node {
+        Node::Dir(id, modified) => {
+            Metadata {
+                is_dir: true,
+                len: 0,
+                modified: modified,
+                mode: 0o40755,
+                readonly: false,
+                unique: format!("m{:x}", id),
+            }
+        }
+        Node::File(id, ref file) => {
+            let file = file.lock().unwrap();
+            Metadata {
+                is_dir: false,
+                len: file.data.len() as u64,
+                modified: file.modified,
+                mode: 0o100644,
+                readonly: false,
+                unique: format!("m{:x}", id),
+            }
+        }
+    }
+}
+
+impl StorageBackend for MemoryStorage {
+    fn list(&self, path: &str) -> io::Result<Vec<(String, Metadata)>> {
+        let path = join("/", path);
+        let tree = self.tree.lock().unwrap();
+        if !tree.is_dir(&path) {
+            return Err(not_found(&path));
+        }
+
+        Ok(tree.nodes
+            .iter()
+            .filter(|&(key, _)| *key != path && parent(key) == path)
+            .map(|(key, node)| {
+                let name = &key[key.rfind('/').unwrap() + 1..];
+                (name.to_string(), memory_metadata(node))
+            })
+            .collect())
+    }
+
+    fn stat(&self, path: &str) -> io::Result<Metadata> {
+        let path = join("/", path);
+        let tree = self.tree.lock().unwrap();
+        tree.nodes.get(&path).map(memory_metadata).ok_or(not_found(&path))
+    }
+
+    fn open_read(&self, path: &str, offset: u64) -> io::Result<Box<dyn Read + Send>> {
+        let path = join("/", path);
+        let tree = self.tree.lock().unwrap();
+        match tree.nodes.get(&path) {
+            Some(&Node::File(_, ref file)) => {
+                let data = file.lock().unwrap().data.clone();
+                let mut reader = Cursor::new(data);
+                reader.set_position(offset);
+                Ok(Box::new(reader))
+            }
+            Some(&Node::Dir(..)) => {
+                Err(io::Error::new(ErrorKind::Other, format!("{} is a directory", path)))
+            }
+            None => Err(not_found(&path)),
+        }
+    }
+
+    fn open_write(&self, path: &str, offset: u64) -> io::Result<Box<dyn Write + Send>> {
+        let file = self.tree.lock().unwrap().file(&join("/", path), offset == 0)?;
+        Ok(Box::new(MemoryWriter {
+            file: file,
+            pos: offset as usize,
+        }))
+    }
+
+    fn append(&self, path: &str) -> io::Result<Box<dyn Write + Send>> {
+        let file = self.tree.lock().unwrap().file(&join("/", path), false)?;
+        let pos = file.lock().unwrap().data.len();
+        Ok(Box::new(MemoryWriter {
+            file: file,
+            pos: pos,
+        }))
+    }
+
+    fn rename(&self, from: &str, to: &str) -> io::Result<()> {
+        let (from, to) = (join("/", from), join("/", to));
+        let mut tree = self.tree.lock().unwrap();
+
+        if from == "/" || !tree.nodes.contains_key(&from) {
+            return Err(not_found(&from));
+        }
+        if !tree.is_dir(parent(&to)) {
+            return Err(not_found(&to));
+        }
+        if to.starts_with(&format!("{}/", from)) || tree.is_dir(&to) {
+            return Err(io::Error::new(ErrorKind::InvalidInput,
+                                      format!("{} can't be moved to {}", from, to)));
+        }
+
+        for key in tree.subtree(&from) {
+            let node = tree.nodes.remove(&key).unwrap();
+            tree.nodes.insert(format!("{}{}", to, &key[from.len()..]), node);
+        }
+        Ok(())
+    }
+
+    fn remove(&self, path: &str) -> io::Result<()> {
+        let path = join("/", path);
+        let mut tree = self.tree.lock().unwrap();
+        match tree.nodes.get(&path) {
+            Some(&Node::File(..)) => {}
+            _ => return Err(not_found(&path)),
+        }
+        tree.nodes.remove(&path);
+        Ok(())
+    }
+
+    fn mkdir(&self, path: &str) -> io::Result<()> {
+        let path = join("/", path);
+        let mut tree = self.tree.lock().unwrap();
+
+        let mut dir = String::new();
+        for name in path.split('/').filter(|name| !name.is_empty()) {
+            dir = format!("{}/{}", dir, name);
+            if tree.nodes.contains_key(&dir) {
+                if !tree.is_dir(&dir) {
+                    return Err(io::Error::new(ErrorKind::AlreadyExists,
+                                              format!("{} is a file", dir)));
+                }
+                continue;
+            }
+            let id = tree.new_id();
+            tree.nodes.insert(dir.clone(), Node::Dir(id, SystemTime::now()));
+        }
+        Ok(())
+    }
+
+    fn rmdir(&self, path: &str) -> io::Result<()> {
+        let path = join("/", path);
+        let mut tree = self.tree.lock().unwrap();
+
+        if path == "/" || !tree.is_dir(&path) {
+            return Err(not_found(&path));
+        }
+        if tree.subtree(&path).len() > 1 {
+            return Err(io::Error::new(ErrorKind::Other, format!("{} is not empty", path)));
+        }
+        tree.nodes.remove(&path);
+        Ok(())
+    }
+}
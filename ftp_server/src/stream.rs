@@ -0,0 +1,162 @@
+//! Control and data connections that may be wrapped in TLS (RFC 4217)
+
+use std::fs::File;
+use std::future::{self, Future};
+use std::io::{self, BufReader, ErrorKind};
+use std::mem;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{ready, Context, Poll};
+
+use rustls::ServerConfig;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::TcpStream;
+use tokio_rustls::server::{Accept, TlsStream};
+use tokio_rustls::TlsAcceptor;
+
+/// A connection to the client, plain until AUTH TLS or PROT P upgrades it
+pub enum FtpStream {
+    Plain(TcpStream),
+    //Protected data connection, the handshake runs on first use so the
+    //150 reply can go out before it
+    TlsPending(Box<Accept<TcpStream>>),
+    Tls(Box<TlsStream<TcpStream>>),
+    //What a failed handshake leaves behind, nothing goes through anymore
+    Closed,
+}
+
+impl FtpStream {
+    /// A data connection that is encrypted once it is first used
+    pub fn protected(stream: TcpStream, config: &Arc<ServerConfig>) -> FtpStream {
+        FtpStream::TlsPending(Box::new(TlsAcceptor::from(config.clone()).accept(stream)))
+    }
+
+    /// The socket underneath, whether or not it is encrypted
+    fn tcp(&self) -> io::Result<&TcpStream> {
+        match *self {
+            FtpStream::Plain(ref stream) => Ok(stream),
+            FtpStream::TlsPending(ref accept) => accept.get_ref().ok_or_else(closed),
+            FtpStream::Tls(ref stream) => Ok(stream.get_ref().0),
+            FtpStream::Closed => Err(closed()),
+        }
+    }
+
+    pub fn is_tls(&self) -> bool {
+        match *self {
+            FtpStream::Plain(_) => false,
+            _ => true,
+        }
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.tcp()?.local_addr()
+    }
+
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.tcp()?.peer_addr()
+    }
+
+    /// Runs the server side of a TLS handshake over this connection
+    pub async fn upgrade(&mut self, config: &Arc<ServerConfig>) -> io::Result<()> {
+        match mem::replace(self, FtpStream::Closed) {
+            FtpStream::Plain(stream) => *self = FtpStream::protected(stream, config),
+            other => {
+                *self = other;
+                return Err(io::Error::new(ErrorKind::Other, "already TLS"));
+            }
+        }
+
+        future::poll_fn(|cx| self.poll_handshake(cx)).await
+    }
+
+    /// Drives the pending handshake of a protected connection
+    fn poll_handshake(&mut self, cx: &mut Context) -> Poll<io::Result<()>> {
+        let result = match *self {
+            FtpStream::TlsPending(ref mut accept) => ready!(Pin::new(&mut **accept).poll(cx)),
+            FtpStream::Closed => return Poll::Ready(Err(closed())),
+            _ => return Poll::Ready(Ok(())),
+        };
+
+        match result {
+            Ok(tls) => {
+                *self = FtpStream::Tls(Box::new(tls));
+                Poll::Ready(Ok(()))
+            }
+            Err(e) => {
+                *self = FtpStream::Closed;
+                Poll::Ready(Err(e))
+            }
+        }
+    }
+}
+
+fn closed() -> io::Error {
+    io::Error::new(ErrorKind::NotConnected, "TLS handshake failed")
+}
+
+impl AsyncRead for FtpStream {
+    fn poll_read(self: Pin<&mut Self>,
+                 cx: &mut Context,
+                 buf: &mut ReadBuf)
+                 -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_handshake(cx))?;
+        match *this {
+            FtpStream::Plain(ref mut stream) => Pin::new(stream).poll_read(cx, buf),
+            FtpStream::Tls(ref mut stream) => Pin::new(&mut **stream).poll_read(cx, buf),
+            _ => Poll::Ready(Err(closed())),
+        }
+    }
+}
+
+impl AsyncWrite for FtpStream {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        ready!(this.poll_handshake(cx))?;
+        match *this {
+            FtpStream::Plain(ref mut stream) => Pin::new(stream).poll_write(cx, buf),
+            FtpStream::Tls(ref mut stream) => Pin::new(&mut **stream).poll_write(cx, buf),
+            _ => Poll::Ready(Err(closed())),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        match *self.get_mut() {
+            FtpStream::Plain(ref mut stream) => Pin::new(stream).poll_flush(cx),
+            FtpStream::Tls(ref mut stream) => Pin::new(&mut **stream).poll_flush(cx),
+            _ => Poll::Ready(Ok(())),
+        }
+    }
+
+    /// Closes the connection, sending a TLS close_notify first when encrypted.
+    /// Even an empty transfer owes the client its handshake
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_handshake(cx))?;
+        match *this {
+            FtpStream::Plain(ref mut stream) => Pin::new(stream).poll_shutdown(cx),
+            FtpStream::Tls(ref mut stream) => Pin::new(&mut **stream).poll_shutdown(cx),
+            _ => Poll::Ready(Ok(())),
+        }
+    }
+}
+
+/// Builds the TLS configuration from a PEM certificate chain and private key
+pub fn tls_config(cert_path: &str, key_path: &str) -> io::Result<Arc<ServerConfig>> {
+    let mut cert_file = BufReader::new(File::open(cert_path)?);
+    let mut key_file = BufReader::new(File::open(key_path)?);
+
+    let certs = rustls_pemfile::certs(&mut cert_file).collect::<io::Result<Vec<_>>>()?;
+    let key = match rustls_pemfile::private_key(&mut key_file)? {
+        Some(key) => key,
+        None => return Err(io::Error::new(ErrorKind::InvalidData, "no private key found")),
+    };
+
+    let config = ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .map_err(|e| io::Error::new(ErrorKind::InvalidData, e))?;
+
+    Ok(Arc::new(config))
+}
@@ -2,7 +2,42 @@
 #[cfg(test)]
 // Running Some unit tests here
 mod tests {
-    use std::net::{Ipv4Addr, SocketAddr, TcpStream, TcpListener, Shutdown, SocketAddrV4};
+    use std::net::{Ipv4Addr, SocketAddr, TcpListener, SocketAddrV4};
+    use std::time::{Duration, Instant, UNIX_EPOCH};
+    use std::collections::HashMap;
+    use std::env;
+    use std::fs::{self, File};
+    use std::os::unix::fs::symlink;
+    use std::path::PathBuf;
+    use std::sync::Arc;
+    use std::io::{Cursor, ErrorKind, Read, Write};
+
+    use flate2::read::ZlibDecoder;
+    use rustls::server::{ClientHello, ResolvesServerCert};
+    use rustls::sign::CertifiedKey;
+    use rustls::ServerConfig;
+    use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+    use tokio::net;
+    use tokio::sync::mpsc;
+    use tokio::time;
+
+    use crate::ascii::{self, FromCrlf, ToCrlf};
+    use crate::audit::{self, Record};
+    use crate::block::{self, BlockDecoder};
+    use crate::main_commands as mc;
+    use crate::metrics::Metrics;
+    use crate::password;
+    use crate::ports::{self, PortPool};
+    use crate::quota::{self, Quota, Usage};
+    use crate::server::{self, TransferMode};
+    use crate::stream::FtpStream;
+    use crate::sessions::{Hangup, Sessions, Stats};
+    use crate::storage::{self, Blocking, LocalDisk, MemoryStorage, Storage, StorageBackend};
+    use crate::throttle::{Bucket, Limits, Throttle};
+    use crate::transfer;
+    use crate::user::{Capability, Permissions, User};
+    use crate::xferlog::{self, Direction, Entry, TransferLog, XferLog};
+    use crate::{carry_over, listen_addrs};
 
     //Testing that listener wworks
 
@@ -14,4 +49,935 @@ mod tests {
                    SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(127, 0, 0, 1), 8080)));
 
     }
+
+    #[test]
+    fn test_mdtm_timestamp() {
+        assert_eq!(server::mdtm_timestamp(UNIX_EPOCH), "19700101000000");
+        assert_eq!(server::mdtm_timestamp(UNIX_EPOCH + Duration::from_secs(1488412799)),
+                   "20170301235959");
+        assert_eq!(server::mdtm_timestamp(UNIX_EPOCH + Duration::from_secs(951782400)),
+                   "20000229000000");
+    }
+
+    #[test]
+    fn test_transfer_size() {
+        let storage = MemoryStorage::new();
+        storage.open_write("/file.txt", 0).unwrap().write_all(b"one\ntwo\r\nthree\n").unwrap();
+
+        assert_eq!(server::transfer_size(&storage, "/file.txt", "BINARY").unwrap(), 15);
+        assert_eq!(server::transfer_size(&storage, "/file.txt", "ASCII").unwrap(), 17);
+        assert!(server::transfer_size(&storage, "/nothing", "BINARY").is_err());
+    }
+
+    #[test]
+    fn test_ascii_line_endings() {
+        let text = b"one\ntwo\r\nthree\n\n";
+
+        //Reads of one byte split every CRLF the adapter makes
+        let mut sent = Vec::new();
+        let mut crlf = ToCrlf::new(&text[..]);
+        let mut buf = [0; 1];
+        while crlf.read(&mut buf).unwrap() > 0 {
+            sent.push(buf[0]);
+        }
+        assert_eq!(sent, b"one\r\ntwo\r\nthree\r\n\r\n");
+
+        let mut sent = Vec::new();
+        ToCrlf::new(&text[..]).read_to_end(&mut sent).unwrap();
+        assert_eq!(sent.len() as u64, 19);
+
+        //A CR that ends a write waits for the next one
+        let mut stored = Vec::new();
+        {
+            let mut lf = FromCrlf::new(&mut stored);
+            lf.write_all(b"one\r").unwrap();
+            lf.write_all(b"\ntwo\rthree\r").unwrap();
+            lf.finish().unwrap();
+        }
+        assert_eq!(stored, b"one\ntwo\rthree\r");
+    }
+
+    #[tokio::test]
+    async fn test_mode_z() {
+        let listener = net::TcpListener::bind("127.0.0.1:27966").await.unwrap();
+        let text = "Oct 18 10:31:02 backup finished\n".repeat(1000);
+        let throttle = Throttle::new(vec![]);
+
+        //Downloads go out as one zlib stream, counted before compression
+        let mut client = net::TcpStream::connect("127.0.0.1:27966").await.unwrap();
+        let mut data = FtpStream::Plain(listener.accept().await.unwrap().0);
+        let state = transfer::TransferState::new();
+        let send = async {
+            server::write_to_stream(&mut Blocking::new(Cursor::new(text.clone())),
+                                    &mut data,
+                                    TransferMode::Deflate(9),
+                                    0,
+                                    &throttle,
+                                    &state)
+                .await
+                .unwrap();
+            data.shutdown().await.unwrap();
+        };
+        let mut compressed = Vec::new();
+        tokio::join!(send, client.read_to_end(&mut compressed)).1.unwrap();
+        assert!(compressed.len() < text.len() / 10);
+        assert_eq!(state.bytes(), text.len() as u64);
+        let mut sent = String::new();
+        ZlibDecoder::new(&compressed[..]).read_to_string(&mut sent).unwrap();
+        assert_eq!(sent, text);
+
+        //Uploads are inflated before they are stored
+        let mut client = net::TcpStream::connect("127.0.0.1:27966").await.unwrap();
+        let mut data = FtpStream::Plain(listener.accept().await.unwrap().0);
+        client.write_all(&compressed).await.unwrap();
+        client.shutdown().await.unwrap();
+        let mut stored = Blocking::new(Vec::new());
+        server::write_to_file(&mut stored,
+                              &mut data,
+                              TransferMode::Deflate(6),
+                              0,
+                              &throttle,
+                              &transfer::TransferState::new(),
+                              &mpsc::unbounded_channel().0)
+            .await
+            .unwrap();
+        assert_eq!(stored.run(|stored| Ok(stored.clone())).await.unwrap(), text.as_bytes());
+    }
+
+    #[tokio::test]
+    async fn test_mode_b() {
+        let listener = net::TcpListener::bind("127.0.0.1:27967").await.unwrap();
+        let file: Vec<u8> = (0..block::MARKER_INTERVAL * 5 / 2).map(|i| i as u8).collect();
+        let throttle = Throttle::new(vec![]);
+
+        //Downloads get a restart marker every MARKER_INTERVAL bytes, giving
+        //the offset in the file, and end with an EOF block
+        let mut client = net::TcpStream::connect("127.0.0.1:27967").await.unwrap();
+        let mut data = FtpStream::Plain(listener.accept().await.unwrap().0);
+        let send = async {
+            server::write_to_stream(&mut Blocking::new(Cursor::new(file[100..].to_vec())),
+                                    &mut data,
+                                    TransferMode::Block,
+                                    100,
+                                    &throttle,
+                                    &transfer::TransferState::new())
+                .await
+                .unwrap();
+            data.shutdown().await.unwrap();
+        };
+        let mut blocks = Vec::new();
+        tokio::join!(send, client.read_to_end(&mut blocks)).1.unwrap();
+        assert_eq!(&blocks[blocks.len() - 3..], &[block::EOF, 0, 0]);
+
+        let mut decoder = BlockDecoder::new();
+        let mut sent = Vec::new();
+        let mut markers = Vec::new();
+        let mut rest = &blocks[..];
+        while decoder.wanted() > 0 {
+            assert!(!rest.is_empty());
+            let n = decoder.wanted().min(rest.len());
+            sent.extend_from_slice(decoder.decode(&rest[..n]));
+            if let Some(marker) = decoder.take_marker() {
+                markers.push(marker);
+            }
+            rest = &rest[n..];
+        }
+        assert!(rest.is_empty());
+        assert_eq!(sent, &file[100..]);
+        assert_eq!(markers.len(), 2);
+        assert_eq!(markers[0].0, (markers[0].1 + 100).to_string());
+        assert!(markers[0].1 + 100 >= block::MARKER_INTERVAL);
+
+        //Uploads answer each marker with the offset it matches on our side,
+        //and leave what follows the EOF block to the next transfer
+        let mut client = net::TcpStream::connect("127.0.0.1:27967").await.unwrap();
+        let mut data = FtpStream::Plain(listener.accept().await.unwrap().0);
+        let (replies, mut received) = mpsc::unbounded_channel();
+        client.write_all(&[0, 0, 3, b'a', b'b', b'c', block::MARKER, 0, 2, b'4', b'2'])
+            .await
+            .unwrap();
+        client.write_all(&[block::EOF, 0, 2, b'd', b'e', 0, 0, 1, b'x']).await.unwrap();
+        let mut stored = Blocking::new(Vec::new());
+        server::write_to_file(&mut stored,
+                              &mut data,
+                              TransferMode::Block,
+                              10,
+                              &throttle,
+                              &transfer::TransferState::new(),
+                              &replies)
+            .await
+            .unwrap();
+        assert_eq!(stored.run(|stored| Ok(stored.clone())).await.unwrap(), b"abcde");
+        assert_eq!(received.try_recv().unwrap(), "110 MARK 42 = 13\r\n");
+        let mut next = [0; 4];
+        data.read_exact(&mut next).await.unwrap();
+        assert_eq!(next, [0, 0, 1, b'x']);
+
+        //A connection that closes before the EOF block cut the file off
+        let mut client = net::TcpStream::connect("127.0.0.1:27967").await.unwrap();
+        let mut data = FtpStream::Plain(listener.accept().await.unwrap().0);
+        client.write_all(&[0, 0, 3, b'a', b'b']).await.unwrap();
+        client.shutdown().await.unwrap();
+        assert!(server::write_to_file(&mut Blocking::new(Vec::new()),
+                                      &mut data,
+                                      TransferMode::Block,
+                                      0,
+                                      &throttle,
+                                      &transfer::TransferState::new(),
+                                      &replies)
+            .await
+            .is_err());
+    }
+
+    //Never gets as far as a handshake, so it needs no certificate
+    #[derive(Debug)]
+    struct NoCertificate;
+
+    impl ResolvesServerCert for NoCertificate {
+        fn resolve(&self, _: ClientHello) -> Option<Arc<CertifiedKey>> {
+            None
+        }
+    }
+
+    #[tokio::test]
+    async fn test_auth_pipelined() {
+        let listener = net::TcpListener::bind("127.0.0.1:27968").await.unwrap();
+        let config = ServerConfig::builder()
+            .with_no_client_auth()
+            .with_cert_resolver(Arc::new(NoCertificate));
+
+        //A command injected behind AUTH before the handshake drops the client
+        let mut client = net::TcpStream::connect("127.0.0.1:27968").await.unwrap();
+        let mut control = BufReader::new(FtpStream::Plain(listener.accept().await.unwrap().0));
+        client.write_all(b"AUTH TLS\r\nUSER x\r\n").await.unwrap();
+        let line = server::read_message(&mut control, &Hangup::new()).await;
+        assert_eq!(line, "AUTH TLS\r\n");
+        assert!(!server::handle_auth(&mut control, "TLS", &Some(Arc::new(config))).await);
+        drop(control);
+        let mut reply = String::new();
+        client.read_to_string(&mut reply).await.unwrap();
+        assert!(reply.starts_with("503 "));
+    }
+
+    //A control connection with the server's end ready for the handlers
+    async fn control(listener: &net::TcpListener)
+                     -> (tokio::io::BufReader<net::TcpStream>, BufReader<FtpStream>) {
+        let client = net::TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let server = FtpStream::Plain(listener.accept().await.unwrap().0);
+        (tokio::io::BufReader::new(client), BufReader::new(server))
+    }
+
+    async fn reply(client: &mut tokio::io::BufReader<net::TcpStream>) -> String {
+        let mut line = String::new();
+        client.read_line(&mut line).await.unwrap();
+        line
+    }
+
+    //Transfers connect out to `listener` in active mode
+    fn data_channel(listener: &net::TcpListener, ascii: bool) -> transfer::DataChannel {
+        transfer::DataChannel {
+            mode: server::FtpMode::Active(listener.local_addr().unwrap()),
+            peer: Ipv4Addr::LOCALHOST.into(),
+            passive: None,
+            tls: None,
+            throttle: Throttle::new(vec![]),
+            xferlog: TransferLog::new(Arc::new(XferLog::open("").unwrap()),
+                                      Ipv4Addr::LOCALHOST.into()),
+            ascii: ascii,
+            transfer_mode: TransferMode::Stream,
+            kept: transfer::KeptConnection::new(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_handle_type() {
+        let listener = net::TcpListener::bind("127.0.0.1:27973").await.unwrap();
+        let (mut client, mut control) = control(&listener).await;
+
+        for &(args, expected, code) in &[("A", "ASCII", "200 "),
+                                          ("a n", "ASCII", "200 "),
+                                          ("I", "BINARY", "200 "),
+                                          ("L 8", "BINARY", "200 "),
+                                          ("E", "", "504 "),
+                                          ("A T", "", "504 "),
+                                          ("L 7", "", "504 "),
+                                          ("", "", "501 ")] {
+            assert_eq!(server::handle_type(&mut control, args).await, expected);
+            assert!(reply(&mut client).await.starts_with(code));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_handlers() {
+        let listener = net::TcpListener::bind("127.0.0.1:27970").await.unwrap();
+        let (mut client, mut control) = control(&listener).await;
+        let backend = Arc::new(MemoryStorage::new());
+        let storage = Storage::new(backend.clone());
+        let mut user = User::new();
+        user.name = "user1".to_string();
+        user.cur_dir = "/".to_string();
+        user.quota = Quota::parse(&["quota_files=10"]).unwrap();
+
+        server::mkd(&mut control, "docs", &mut user, &storage).await;
+        assert!(reply(&mut client).await.starts_with("257 "));
+        assert!(backend.stat("/docs").unwrap().is_dir);
+
+        //Uploads go through the data connection into the storage
+        let data_listener = net::TcpListener::bind("127.0.0.1:27971").await.unwrap();
+        let data = data_channel(&data_listener, false);
+        let (replies, mut queued) = mpsc::unbounded_channel();
+        let state = transfer::TransferState::new();
+        let upload = async {
+            let mut stream = data_listener.accept().await.unwrap().0;
+            stream.write_all(b"quarterly numbers").await.unwrap();
+            stream.shutdown().await.unwrap();
+        };
+        tokio::join!(mc::stor(&replies, &user, &storage, &data, "docs/a.txt", 0, &state),
+                     upload);
+        assert!(queued.try_recv().unwrap().starts_with("150 "));
+        assert!(queued.try_recv().unwrap().starts_with("226 "));
+        let mut stored = String::new();
+        backend.open_read("/docs/a.txt", 0).unwrap().read_to_string(&mut stored).unwrap();
+        assert_eq!(stored, "quarterly numbers");
+        assert_eq!(user.quota.usage(), Usage { bytes: 17, files: 1 });
+
+        //LIST shows it on the data connection
+        let mut listing = Vec::new();
+        let download = async {
+            let mut stream = data_listener.accept().await.unwrap().0;
+            stream.read_to_end(&mut listing).await.unwrap();
+        };
+        tokio::join!(mc::list(&mut control, &user, &storage, &data, "docs"), download);
+        assert!(reply(&mut client).await.starts_with("150 "));
+        assert!(reply(&mut client).await.starts_with("226 "));
+        assert!(String::from_utf8(listing).unwrap().contains("17B\t/docs/a.txt\r\n"));
+
+        //RNFR gives RNTO what to rename
+        let from = mc::rnfr(&mut control, &user, &storage, "docs/a.txt").await;
+        assert!(reply(&mut client).await.starts_with("350 "));
+        assert_eq!(from, Some("/docs/a.txt".to_string()));
+        mc::rnto(&mut control, &user, &storage, from, "docs/b.txt").await;
+        assert!(reply(&mut client).await.starts_with("250 "));
+        assert!(backend.stat("/docs/a.txt").is_err());
+        assert!(mc::rnfr(&mut control, &user, &storage, "docs/a.txt").await.is_none());
+        assert!(reply(&mut client).await.starts_with("550 "));
+        mc::rnto(&mut control, &user, &storage, None, "docs/c.txt").await;
+        assert!(reply(&mut client).await.starts_with("503 "));
+
+        //A directory with something in it stays
+        mc::rmd(&mut control, &user, &storage, "docs").await;
+        assert!(reply(&mut client).await.starts_with("550 "));
+
+        mc::dele(&mut control, &user, &storage, "docs/b.txt").await;
+        assert!(reply(&mut client).await.starts_with("200 "));
+        assert!(backend.stat("/docs/b.txt").is_err());
+        assert_eq!(user.quota.usage(), Usage::default());
+        mc::dele(&mut control, &user, &storage, "docs").await;
+        assert!(reply(&mut client).await.starts_with("550 "));
+
+        mc::rmd(&mut control, &user, &storage, "docs").await;
+        assert!(reply(&mut client).await.starts_with("250 "));
+        assert!(backend.stat("/docs").is_err());
+        mc::rmd(&mut control, &user, &storage, "/").await;
+        assert!(reply(&mut client).await.starts_with("550 "));
+
+        //Without the permission nothing is touched
+        user.perms = Permissions::parse(&["/=lr"]).unwrap();
+        server::mkd(&mut control, "docs", &mut user, &storage).await;
+        assert!(reply(&mut client).await.starts_with("550 Permission denied"));
+        assert!(backend.stat("/docs").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_ascii_restart() {
+        let backend = Arc::new(MemoryStorage::new());
+        let storage = Storage::new(backend.clone());
+        backend.open_write("/a.txt", 0).unwrap().write_all(b"one\ntwo\nthree\n").unwrap();
+        let mut user = User::new();
+        user.cur_dir = "/".to_string();
+
+        //REST offsets count the CRLF line ends the client got
+        assert_eq!(ascii::local_offset(&b"one\ntwo\n"[..], 5).unwrap(), 4);
+        assert_eq!(ascii::local_offset(&b"one\ntwo\n"[..], 4).unwrap(), 3);
+        assert_eq!(ascii::local_offset(&b"one\r\ntwo\n"[..], 5).unwrap(), 5);
+        assert_eq!(ascii::local_offset(&b"one\n"[..], 50).unwrap(), 4);
+
+        let listener = net::TcpListener::bind("127.0.0.1:27972").await.unwrap();
+        let data = data_channel(&listener, true);
+        let (replies, mut queued) = mpsc::unbounded_channel();
+        for &(offset, rest) in &[(5, "two\r\nthree\r\n"), (4, "\ntwo\r\nthree\r\n")] {
+            let mut resumed = Vec::new();
+            let download = async {
+                let mut stream = listener.accept().await.unwrap().0;
+                stream.read_to_end(&mut resumed).await.unwrap();
+            };
+            let state = transfer::TransferState::new();
+            tokio::join!(mc::retr(&replies, &user, &storage, &data, "a.txt", offset, &state),
+                         download);
+            assert!(queued.try_recv().unwrap().starts_with("150 "));
+            assert!(queued.try_recv().unwrap().starts_with("226 "));
+            assert_eq!(String::from_utf8(resumed).unwrap(), rest);
+        }
+
+        //An upload resumed in ASCII mode picks up after the lines stored
+        backend.open_write("/b.txt", 0).unwrap().write_all(b"one\ntw").unwrap();
+        let upload = async {
+            let mut stream = listener.accept().await.unwrap().0;
+            stream.write_all(b"o\r\nthree\r\n").await.unwrap();
+            stream.shutdown().await.unwrap();
+        };
+        let state = transfer::TransferState::new();
+        tokio::join!(mc::stor(&replies, &user, &storage, &data, "b.txt", 7, &state), upload);
+        assert!(queued.try_recv().unwrap().starts_with("150 "));
+        assert!(queued.try_recv().unwrap().starts_with("226 "));
+        let mut stored = String::new();
+        backend.open_read("/b.txt", 0).unwrap().read_to_string(&mut stored).unwrap();
+        assert_eq!(stored, "one\ntwo\nthree\n");
+    }
+
+    #[test]
+    fn test_mlst_facts() {
+        let path = env::temp_dir().join("ftp_server_mlst_facts.txt");
+        File::create(&path).unwrap().write_all(b"hello").unwrap();
+        let storage = LocalDisk::new(env::temp_dir());
+
+        let mut user = User::new();
+        let meta = storage.stat("/ftp_server_mlst_facts.txt").unwrap();
+        let facts = server::mlst_facts(&meta, &user, "/ftp_server_mlst_facts.txt");
+        assert!(facts.starts_with("type=file;size=5;modify="));
+        assert!(facts.contains(";perm=adfrw;unique="));
+        assert!(facts.ends_with(";"));
+
+        let dir_facts = server::mlst_facts(&storage.stat("/").unwrap(), &user, "/");
+        assert!(dir_facts.starts_with("type=dir;"));
+        assert!(dir_facts.contains(";perm=cdeflmp;"));
+
+        user.perms = Permissions::parse(&["/=lr"]).unwrap();
+        let facts = server::mlst_facts(&meta, &user, "/ftp_server_mlst_facts.txt");
+        assert!(facts.contains(";perm=r;unique="));
+        let dir_facts = server::mlst_facts(&storage.stat("/").unwrap(), &user, "/");
+        assert!(dir_facts.contains(";perm=el;"));
+    }
+
+    #[test]
+    fn test_strip_telnet() {
+        assert_eq!(transfer::strip_telnet(b"ABOR\r\n"), "ABOR\r\n");
+        assert_eq!(transfer::strip_telnet(b"\xff\xf4\xff\xf2ABOR\r\n"), "ABOR\r\n");
+        assert_eq!(transfer::strip_telnet(b"\xff\xf4\xffABOR\r\n"), "ABOR\r\n");
+    }
+
+    #[test]
+    fn test_eprt_addr() {
+        assert_eq!(server::eprt_addr("|1|132.235.1.2|6275|").unwrap(),
+                   SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(132, 235, 1, 2), 6275)));
+        assert_eq!(server::eprt_addr("|2|::1|6275|").unwrap(),
+                   "[::1]:6275".parse::<SocketAddr>().unwrap());
+        assert_eq!(server::eprt_addr("!2!1080::8:800:200C:417A!5282!").unwrap(),
+                   "[1080::8:800:200C:417A]:5282".parse::<SocketAddr>().unwrap());
+
+        assert!(server::eprt_addr("|3|1.2.3.4|21|").unwrap_err().starts_with("522"));
+        assert!(server::eprt_addr("|1|::1|6275|").unwrap_err().starts_with("501"));
+        assert!(server::eprt_addr("|1|1.2.3.4|port|").unwrap_err().starts_with("501"));
+        assert!(server::eprt_addr("|1|1.2.3.4|0|").is_err());
+        assert!(server::eprt_addr("").is_err());
+    }
+
+    #[test]
+    fn test_port_addr() {
+        assert_eq!(server::port_addr("132,235,1,2,24,131").unwrap(),
+                   SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(132, 235, 1, 2), 6275)));
+        assert!(server::port_addr("132,235,1,2,24").unwrap_err().starts_with("501"));
+        assert!(server::port_addr("132,235,1,256,24,131").unwrap_err().starts_with("501"));
+        assert!(server::port_addr("").is_err());
+    }
+
+    #[test]
+    fn test_active_addr() {
+        let peer = "132.235.1.2".parse().unwrap();
+        let addr = server::port_addr("132,235,1,2,24,131").unwrap();
+        assert_eq!(server::active_addr(addr, peer).unwrap(), addr);
+
+        //The data connection can't be bounced to another host or a
+        //privileged port of the client
+        let other = server::port_addr("10,0,0,1,24,131").unwrap();
+        assert!(server::active_addr(other, peer).unwrap_err().starts_with("504"));
+        let ssh = server::eprt_addr("|1|132.235.1.2|22|").unwrap();
+        assert!(server::active_addr(ssh, peer).unwrap_err().starts_with("504"));
+
+        //A client on a dual stack socket is the same host as its IPv4 address
+        let mapped = "::ffff:132.235.1.2".parse().unwrap();
+        assert_eq!(server::active_addr(addr, mapped).unwrap(), addr);
+    }
+
+    #[tokio::test]
+    async fn test_accept_data() {
+        let listener = net::TcpListener::bind("127.0.0.1:27969").await.unwrap();
+        let client = Ipv4Addr::new(127, 0, 0, 2);
+
+        //Whoever else connects to the passive port first is turned away
+        let mut other = net::TcpStream::connect("127.0.0.1:27969").await.unwrap();
+        let socket = net::TcpSocket::new_v4().unwrap();
+        socket.bind(SocketAddr::new(client.into(), 0)).unwrap();
+        let accept = transfer::accept_data(&listener, client.into());
+        let (data, _) = tokio::join!(accept, socket.connect("127.0.0.1:27969".parse().unwrap()));
+        assert_eq!(data.unwrap().peer_addr().unwrap().ip(), client);
+        assert_eq!(other.read(&mut [0; 1]).await.unwrap(), 0);
+    }
+
+    #[test]
+    fn test_listen_addrs() {
+        let addrs = listen_addrs("127.0.0.1, [::1]:2121 0.0.0.0:21 ::", "2115").unwrap();
+        assert_eq!(addrs,
+                   vec!["127.0.0.1:2115".parse::<SocketAddr>().unwrap(),
+                        "[::1]:2121".parse().unwrap(),
+                        "0.0.0.0:21".parse().unwrap(),
+                        "[::]:2115".parse().unwrap()]);
+
+        assert_eq!(listen_addrs("[::1]", "2115").unwrap(),
+                   vec!["[::1]:2115".parse::<SocketAddr>().unwrap()]);
+        assert_eq!(listen_addrs("127.0.0.1,127.0.0.1:2115", "2115").unwrap().len(), 1);
+        assert!(listen_addrs("localhost", "2115").is_err());
+        assert!(listen_addrs("", "2115").is_err());
+        assert!(listen_addrs("127.0.0.1", "port").is_err());
+    }
+
+    //A jail with a file and a subdirectory, a secret next to it and links
+    //pointing out of it
+    fn jail(name: &str) -> (User, LocalDisk, PathBuf) {
+        let base = env::temp_dir().join(name);
+        let _ = fs::remove_dir_all(&base);
+        let root = base.join("ftproot").join("user1");
+        fs::create_dir_all(root.join("sub")).unwrap();
+        File::create(root.join("file.txt")).unwrap();
+        File::create(base.join("secret")).unwrap();
+        symlink(&base, root.join("escape")).unwrap();
+        symlink(base.join("nothing"), root.join("dangling")).unwrap();
+        symlink(root.join("sub"), root.join("inside")).unwrap();
+
+        let mut user = User::new();
+        user.name = "user1".to_string();
+        user.path = root.display().to_string();
+        user.cur_dir = "/sub".to_string();
+        (user, LocalDisk::new(&root), fs::canonicalize(&root).unwrap())
+    }
+
+    #[test]
+    fn test_resolve_path() {
+        let (user, disk, root) = jail("ftp_server_resolve_path");
+
+        assert_eq!(server::resolve_path(&user, ""), "/sub");
+        assert_eq!(server::resolve_path(&user, ".."), "/");
+        assert_eq!(server::resolve_path(&user, "../file.txt"), "/file.txt");
+        assert_eq!(server::resolve_path(&user, "/file.txt"), "/file.txt");
+        assert_eq!(server::resolve_path(&user, "./new/../new.txt"), "/sub/new.txt");
+
+        assert_eq!(disk.real_path("/").unwrap(), root);
+        assert_eq!(disk.real_path("/sub/new.txt").unwrap(), root.join("sub/new.txt"));
+        assert_eq!(disk.real_path("/inside").unwrap(), root.join("sub"));
+        assert!(disk.stat("/inside").unwrap().is_dir);
+    }
+
+    #[test]
+    fn test_resolve_path_traversal() {
+        let (user, disk, root) = jail("ftp_server_resolve_traversal");
+
+        //Climbing out lexically stops at the root
+        assert_eq!(server::resolve_path(&user, "../../../secret"), "/secret");
+        assert_eq!(server::resolve_path(&user, "/../../etc/passwd"), "/etc/passwd");
+        assert_eq!(server::resolve_path(&user, "sub/../../../../.."), "/");
+        assert_eq!(disk.real_path("../../secret").unwrap(), root.join("secret"));
+
+        //Symlinks out of the jail are refused, existing or not
+        for link in &["/escape", "/escape/secret", "/escape/new.txt", "/dangling"] {
+            assert_eq!(disk.real_path(link).unwrap_err().kind(), ErrorKind::PermissionDenied);
+        }
+        assert!(disk.open_read("/escape/secret", 0).is_err());
+        assert!(disk.open_write("/escape/new.txt", 0).is_err());
+
+        //Listings leave them out as well
+        let names: Vec<String> =
+            disk.list("/").unwrap().into_iter().map(|(name, _)| name).collect();
+        assert!(names.contains(&"inside".to_string()));
+        assert!(!names.contains(&"escape".to_string()));
+        assert!(!names.contains(&"dangling".to_string()));
+
+        //Everything that resolves stays below the root
+        for attack in &["..", "../..", "/..", "//etc/passwd", "....//....//etc", "sub/./../.."] {
+            let path = server::resolve_path(&user, attack);
+            assert!(disk.real_path(&path).unwrap().starts_with(&root));
+        }
+    }
+
+    #[test]
+    fn test_storage_join() {
+        assert_eq!(storage::join("/", ""), "/");
+        assert_eq!(storage::join("/a/b", "c/./d"), "/a/b/c/d");
+        assert_eq!(storage::join("/a/b", "/c"), "/c");
+        assert_eq!(storage::join("/a/b", "../../../.."), "/");
+        assert_eq!(storage::join("/a", "b//c/"), "/a/b/c");
+    }
+
+    #[test]
+    fn test_memory_storage() {
+        let storage = MemoryStorage::new();
+
+        storage.mkdir("/docs/old").unwrap();
+        storage.open_write("/docs/a.txt", 0).unwrap().write_all(b"hello").unwrap();
+        storage.append("/docs/a.txt").unwrap().write_all(b" world").unwrap();
+        assert!(storage.open_write("/missing/a.txt", 0).is_err());
+        assert!(storage.open_write("/docs", 0).is_err());
+
+        let meta = storage.stat("/docs/a.txt").unwrap();
+        assert!(!meta.is_dir);
+        assert_eq!(meta.len, 11);
+        assert!(storage.stat("/docs").unwrap().is_dir);
+
+        //Restarted reads and writes
+        let mut rest = String::new();
+        storage.open_read("/docs/a.txt", 6).unwrap().read_to_string(&mut rest).unwrap();
+        assert_eq!(rest, "world");
+        storage.open_write("/docs/a.txt", 6).unwrap().write_all(b"there").unwrap();
+        let mut all = String::new();
+        storage.open_read("/docs/a.txt", 0).unwrap().read_to_string(&mut all).unwrap();
+        assert_eq!(all, "hello there");
+
+        let mut names: Vec<String> =
+            storage.list("/docs").unwrap().into_iter().map(|(name, _)| name).collect();
+        names.sort();
+        assert_eq!(names, vec!["a.txt".to_string(), "old".to_string()]);
+        assert_eq!(storage.list("/").unwrap().len(), 1);
+
+        //Renaming a directory takes what is in it along
+        storage.rename("/docs", "/archive").unwrap();
+        assert!(storage.stat("/docs/a.txt").is_err());
+        assert_eq!(storage.stat("/archive/a.txt").unwrap().len, 11);
+        assert!(storage.rename("/archive", "/archive/inner").is_err());
+
+        assert!(storage.rmdir("/archive").is_err());
+        assert!(storage.remove("/archive/old").is_err());
+        storage.remove("/archive/a.txt").unwrap();
+        storage.rmdir("/archive/old").unwrap();
+        storage.rmdir("/archive").unwrap();
+        assert!(storage.rmdir("/").is_err());
+        assert!(storage.list("/").unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_password_verify() {
+        let argon = password::hash("pw");
+        assert!(argon.starts_with("$argon2id$"));
+        assert!(password::verify("pw", &argon));
+        assert!(!password::verify("pW", &argon));
+        assert!(password::hash("pw") != argon);
+
+        let bcrypt = "$2b$10$dMwYm5ktu20BbGpsvuDcheQkxHuxkuoKdZGDMSiSJd40o0GJRbMXK";
+        assert!(password::verify("pw", bcrypt));
+        assert!(!password::verify("pw2", bcrypt));
+
+        let sha512 = "$6$VB7zFMAufx4cCgEf$fmxR5TWgJaSIuu6sxtPGp5WLY99taOlyqeZ9qtUdoSTRwVN3IeuHx/\
+                      gfqiKgMsgmM2YLHm5Z1hTTa5G0t3e1j0";
+        assert!(password::verify("pw", sha512));
+        assert!(!password::verify("", sha512));
+
+        //Cleartext entries still work, but a hash is never taken as one
+        assert!(password::verify("dummy", "dummy"));
+        assert!(!password::verify("dumm", "dummy"));
+        assert!(!password::verify(bcrypt, bcrypt));
+        assert!(!password::is_hashed("dummy"));
+        assert!(password::is_hashed(&argon) && password::is_hashed(bcrypt));
+    }
+
+    #[test]
+    fn test_permissions() {
+        let everything = Permissions::parse(&[]).unwrap();
+        assert!(everything.allows(Capability::Rmdir, "/"));
+        assert!(everything.allows(Capability::Write, "/a/b.txt"));
+
+        let read_only = Permissions::parse(&["lr"]).unwrap();
+        assert!(read_only.allows(Capability::List, "/"));
+        assert!(read_only.allows(Capability::Read, "/sub/file.txt"));
+        for cap in &[Capability::Write, Capability::Append, Capability::Delete,
+                     Capability::Rename, Capability::Mkdir, Capability::Rmdir] {
+            assert!(!read_only.allows(*cap, "/sub/file.txt"));
+        }
+
+        //The most specific directory wins, and it only covers what is below it
+        let partner = Permissions::parse(&["/=lr", "/drop/=lwa", "/drop/sealed=none"]).unwrap();
+        assert!(partner.allows(Capability::Write, "/drop/new.txt"));
+        assert!(partner.allows(Capability::Write, "/drop"));
+        assert!(!partner.allows(Capability::Read, "/drop/new.txt"));
+        assert!(!partner.allows(Capability::Write, "/dropbox/new.txt"));
+        assert!(!partner.allows(Capability::List, "/drop/sealed/x"));
+        assert!(partner.allows(Capability::Read, "/dropbox/new.txt"));
+
+        let scoped = Permissions::parse(&["/pub=all"]).unwrap();
+        assert!(scoped.allows(Capability::Delete, "/pub/x"));
+        assert!(!scoped.allows(Capability::List, "/"));
+
+        assert!(Permissions::parse(&["lrx"]).is_err());
+        assert!(Permissions::parse(&["pub=lr"]).is_err());
+    }
+
+    #[test]
+    fn test_quota() {
+        let quota = Quota::parse(&["quota=1K", "quota_files=2"]).unwrap();
+        assert_eq!((quota.max_bytes, quota.max_files), (Some(1024), Some(2)));
+        assert_eq!(Quota::parse(&["quota=3M"]).unwrap().max_bytes, Some(3 << 20));
+        assert!(Quota::parse(&["quota=lots"]).is_err());
+        assert!(Quota::parse(&["quota=99999999999G"]).is_err());
+        assert!(Quota::parse(&["quota_bytes=1"]).is_err());
+
+        //Usage is counted from what is already stored
+        let storage = MemoryStorage::new();
+        storage.mkdir("/sub").unwrap();
+        storage.open_write("/sub/a.txt", 0).unwrap().write_all(&[0; 1000]).unwrap();
+        quota.scan(&storage);
+        assert_eq!(quota.usage(), Usage { bytes: 1000, files: 1 });
+
+        //Sessions of the same user share it
+        let session = quota.clone();
+        assert!(session.reserve(0, 1));
+        assert!(!quota.reserve(0, 1));
+
+        //Writing stops at the limit, rewriting what is there costs nothing
+        let file = storage.open_write("/b.txt", 0).unwrap();
+        let mut writer = session.writer(file, 0, 0);
+        writer.write_all(&[0; 24]).unwrap();
+        let err = writer.write_all(&[0; 1]).unwrap_err();
+        assert!(quota::is_exceeded(&err));
+        assert_eq!(quota.usage(), Usage { bytes: 1024, files: 2 });
+
+        let file = storage.open_write("/sub/a.txt", 500).unwrap();
+        session.writer(file, 500, 1000).write_all(&[1; 500]).unwrap();
+        assert_eq!(quota.usage().bytes, 1024);
+
+        quota.release(1000, 1);
+        assert_eq!(quota.usage(), Usage { bytes: 24, files: 1 });
+        assert!(Quota::unlimited().reserve(u32::max_value() as u64, 1000));
+    }
+
+    #[test]
+    fn test_reload_keeps_usage() {
+        let user = |quota: &[&str], rates: &[&str]| {
+            let mut user = User::new();
+            user.path = "ftproot/user1".to_string();
+            user.quota = Quota::parse(quota).unwrap();
+            user.limits = Limits::parse(rates).unwrap();
+            user
+        };
+        let mut old = HashMap::new();
+        old.insert("user1".to_string(), user(&["quota=1K"], &["upload_rate=1K"]));
+        assert!(old["user1"].quota.reserve(1000, 1));
+
+        //What sessions logged in before the reload stored still counts, and
+        //they and the new ones keep sharing it
+        let mut users = HashMap::new();
+        users.insert("user1".to_string(), user(&["quota=1K"], &["upload_rate=1K"]));
+        users.insert("user2".to_string(), user(&["quota=1K"], &[]));
+        carry_over(&mut users, &old);
+        assert_eq!(users["user1"].quota.usage(), Usage { bytes: 1000, files: 1 });
+        assert!(!users["user1"].quota.reserve(100, 1));
+        assert!(users["user1"].quota.reserve(24, 1));
+        assert_eq!(old["user1"].quota.usage().bytes, 1024);
+        assert_eq!(users["user2"].quota.usage(), Usage::default());
+
+        //Unchanged rates keep their buckets, new ones start afresh
+        let (before, after) = (&old["user1"].limits, &users["user1"].limits);
+        assert!(Arc::ptr_eq(before.upload.as_ref().unwrap(), after.upload.as_ref().unwrap()));
+        let mut users = HashMap::new();
+        users.insert("user1".to_string(), user(&["quota=2K"], &["upload_rate=2K"]));
+        carry_over(&mut users, &old);
+        assert_eq!(users["user1"].limits.rates(), (2048, 0));
+        assert!(users["user1"].quota.reserve(1024, 1));
+    }
+
+    #[tokio::test]
+    async fn test_throttle() {
+        let limits = Limits::parse(&["upload_rate=2K", "download_rate=1M"]).unwrap();
+        assert!(limits.upload.is_some() && limits.download.is_some());
+        assert!(Limits::parse(&["upload_rate=0"]).unwrap().upload.is_none());
+        assert!(Limits::parse(&["upload_rate=fast"]).is_err());
+        assert!(Limits::parse(&["rate=1K"]).is_err());
+
+        //A full bucket lets a second's worth through, then makes you wait
+        let bucket = Bucket::new(1000);
+        assert_eq!(bucket.take(1000), Duration::from_millis(0));
+        let delay = bucket.take(500);
+        assert!(delay > Duration::from_millis(450) && delay <= Duration::from_millis(500));
+
+        //The tightest level holds the transfer back
+        let throttle = Throttle::new(vec![Limits::new(0, 4000), Limits::new(0, 100000)]);
+        throttle.download(4000).await;
+        let start = Instant::now();
+        throttle.download(400).await;
+        assert!(start.elapsed() >= Duration::from_millis(80));
+        throttle.upload(1 << 20).await;
+    }
+
+    #[tokio::test]
+    async fn test_port_pool() {
+        let localhost = "127.0.0.1".parse().unwrap();
+        let pool = PortPool::new(&[27960, 27961]);
+
+        //Every PASV takes its own port until there are none left
+        let first = pool.bind(localhost).unwrap();
+        let second = pool.bind(localhost).unwrap();
+        assert_eq!((first.port(), second.port()), (27960, 27961));
+        assert_eq!(pool.available(), 0);
+        assert!(pool.bind(localhost).is_none());
+
+        //A finished transfer gives its port back
+        drop(first);
+        assert_eq!(pool.available(), 1);
+        let third = pool.bind(localhost).unwrap();
+        assert_eq!(third.port(), 27960);
+        assert!(time::timeout(Duration::from_millis(50), third.expired()).await.is_err());
+
+        //Ports something else holds are skipped but stay in the pool
+        let taken = TcpListener::bind("127.0.0.1:27962").unwrap();
+        let pool = PortPool::new(&[27962, 27963]);
+        assert_eq!(pool.bind(localhost).unwrap().port(), 27963);
+        let port = pool.bind(localhost).unwrap();
+        assert!(pool.bind(localhost).is_none());
+        drop(port);
+        drop(taken);
+        let (a, b) = (pool.bind(localhost).unwrap(), pool.bind(localhost).unwrap());
+        assert_eq!((a.port(), b.port()), (27963, 27962));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_passive_expiry() {
+        let listener = net::TcpListener::bind("127.0.0.1:27974").await.unwrap();
+        let (mut client, mut control) = control(&listener).await;
+        let pool = PortPool::new(&[27975]);
+        let mut passive = pool.bind("127.0.0.1".parse().unwrap());
+        let hangup = Hangup::new();
+        let mut transfer = None;
+
+        //The client goes quiet after PASV, the port is back in the pool
+        //before its next command shows up
+        let late = async {
+            time::sleep(Duration::from_secs(ports::PASSIVE_TIMEOUT + 1)).await;
+            client.get_mut().write_all(b"NOOP\r\n").await.unwrap();
+        };
+        let (command, _) = tokio::join!(transfer::read_command(&mut control,
+                                                               &mut transfer,
+                                                               &mut passive,
+                                                               &hangup),
+                                        late);
+        assert_eq!(command.unwrap(), "NOOP\r\n");
+        assert!(passive.is_none());
+        assert_eq!(pool.available(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_sessions() {
+        let sessions = Sessions::new();
+
+        let registration = sessions.register("127.0.0.1:27964".parse().unwrap());
+        let session = registration.session.clone();
+        session.update("user1", "/pub");
+        assert_eq!(sessions.len(), 1);
+        assert_eq!(sessions.get(session.id).unwrap().cwd(), "/pub");
+
+        //Running transfers are listed and counted, finished ones only counted
+        let download = transfer::TransferState::new();
+        session.start_transfer("RETR big.bin", &download);
+        download.add_bytes(100);
+        assert_eq!(session.transfer(), Some(("RETR big.bin".to_string(), 100)));
+        let upload = transfer::TransferState::new();
+        session.start_transfer("STOR up.txt", &upload);
+        upload.add_bytes(30);
+        assert_eq!(session.stats(),
+                   Stats {
+                       transfers: 2,
+                       uploaded: 30,
+                       downloaded: 100,
+                   });
+
+        //A shutdown past its grace period aborts what is still running
+        sessions.abort_transfers();
+        assert!(upload.aborted() && !download.aborted());
+
+        //Kicking aborts the transfer and wakes the session's read
+        assert_eq!(session.kicked(), None);
+        let hangup = session.hangup.wait();
+        session.kick("Disconnected by the administrator");
+        assert_eq!(session.kicked(), Some("Disconnected by the administrator".to_string()));
+        hangup.await;
+        assert!(session.hangup.is_set());
+
+        drop(registration);
+        assert_eq!(sessions.len(), 0);
+    }
+
+    #[test]
+    fn test_xferlog_entry() {
+        let mut entry = Entry {
+            time: UNIX_EPOCH + Duration::from_secs(1792319462),
+            seconds: 3,
+            host: "127.0.0.1".parse().unwrap(),
+            bytes: 50000000,
+            path: "/pub/big file.bin",
+            direction: Direction::Outgoing,
+            ascii: false,
+            user: "user1",
+            complete: true,
+        };
+        assert_eq!(xferlog::format_entry(&entry),
+                   "Sun Oct 18 10:31:02 2026 3 127.0.0.1 50000000 /pub/big_file.bin b _ o r \
+                    user1 ftp 0 * c\n");
+
+        //Single digit days are padded like ctime does
+        entry.time = UNIX_EPOCH + Duration::from_secs(86400 * 3);
+        entry.direction = Direction::Incoming;
+        entry.ascii = true;
+        entry.complete = false;
+        assert!(xferlog::format_entry(&entry)
+            .starts_with("Sun Jan  4 00:00:00 1970 3 127.0.0.1 50000000 /pub/big_file.bin a _ i"));
+        assert!(xferlog::format_entry(&entry).ends_with(" i\n"));
+    }
+
+    #[test]
+    fn test_audit_record() {
+        assert_eq!(audit::quote("a \"b\" c:\\d\r\n\u{1}"),
+                   "\"a \\\"b\\\" c:\\\\d\\r\\n\\u0001\"");
+
+        let record = Record::new()
+            .number("session", 3)
+            .string("event", "login")
+            .boolean("success", false)
+            .code("reply", Some(530))
+            .code("data", None);
+        assert_eq!(record.finish(),
+                   "{\"session\":3,\"event\":\"login\",\"success\":false,\"reply\":530,\
+                    \"data\":null}");
+    }
+
+    #[test]
+    fn test_metrics() {
+        let metrics = Metrics::new();
+        metrics.login(Ok(()));
+        metrics.login(Err("bad password"));
+        metrics.command("retr");
+        metrics.command("XYZZY");
+        metrics.reply(226);
+        metrics.sent(100);
+        metrics.transfer(Direction::Outgoing, Duration::from_millis(700));
+
+        let text = metrics.render(2, 498, 500);
+        for line in &["ftp_sessions 2",
+                      "ftp_passive_ports_free 498",
+                      "ftp_logins_total{outcome=\"success\"} 1",
+                      "ftp_logins_total{outcome=\"bad_password\"} 1",
+                      "ftp_commands_total{command=\"RETR\"} 1",
+                      "ftp_commands_total{command=\"OTHER\"} 1",
+                      "ftp_replies_total{code=\"226\"} 1",
+                      "ftp_bytes_sent_total 100",
+                      "ftp_transfer_duration_seconds_bucket{direction=\"out\",le=\"0.5\"} 0",
+                      "ftp_transfer_duration_seconds_bucket{direction=\"out\",le=\"1\"} 1",
+                      "ftp_transfer_duration_seconds_count{direction=\"in\"} 0"] {
+            assert!(text.lines().any(|l| l == *line), "missing {}", line);
+        }
+    }
 }
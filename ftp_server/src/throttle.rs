@@ -0,0 +1,145 @@
+//! Bandwidth limits. Each limit is a token bucket holding up to a second's
+//! worth of bytes, refilled at the configured rate. Limits are set server
+//! wide and per session in fsys.cfg and per user in the user DB, and a
+//! transfer waits on all of them so the tightest one wins
+
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use tokio::time;
+
+use crate::quota;
+
+#[derive(Debug)]
+struct BucketState {
+    tokens: f64,
+    last: Instant,
+}
+
+#[derive(Debug)]
+pub struct Bucket {
+    rate: u64,
+    state: Mutex<BucketState>,
+}
+
+impl Bucket {
+    /// A bucket for `rate` bytes per second, starting out full
+    pub fn new(rate: u64) -> Bucket {
+        Bucket {
+            rate: rate,
+            state: Mutex::new(BucketState {
+                tokens: rate as f64,
+                last: Instant::now(),
+            }),
+        }
+    }
+
+    /// Takes `n` bytes out of the bucket, going into debt when there is not
+    /// enough. Returns how long the caller should wait for the debt to be
+    /// paid back
+    pub fn take(&self, n: u64) -> Duration {
+        let mut state = self.state.lock().unwrap();
+        let now = Instant::now();
+        let elapsed = now.duration_since(state.last);
+        let refill = elapsed.as_secs() as f64 * self.rate as f64 +
+                     elapsed.subsec_nanos() as f64 * self.rate as f64 / 1e9;
+
+        state.tokens = (state.tokens + refill).min(self.rate as f64) - n as f64;
+        state.last = now;
+
+        match state.tokens < 0.0 {
+            true => Duration::from_millis((-state.tokens * 1000.0 / self.rate as f64) as u64),
+            false => Duration::from_millis(0),
+        }
+    }
+}
+
+/// Upload and download limits of one level, cloning shares the buckets
+#[derive(Debug, Clone)]
+pub struct Limits {
+    pub upload: Option<Arc<Bucket>>,
+    pub download: Option<Arc<Bucket>>,
+}
+
+impl Limits {
+    pub fn unlimited() -> Limits {
+        Limits {
+            upload: None,
+            download: None,
+        }
+    }
+
+    /// Rates are in bytes per second, 0 leaves that direction unlimited
+    pub fn new(upload: u64, download: u64) -> Limits {
+        let bucket = |rate| match rate {
+            0 => None,
+            rate => Some(Arc::new(Bucket::new(rate))),
+        };
+
+        Limits {
+            upload: bucket(upload),
+            download: bucket(download),
+        }
+    }
+
+    /// The upload and download rates, 0 where unlimited
+    pub fn rates(&self) -> (u64, u64) {
+        let rate = |bucket: &Option<Arc<Bucket>>| bucket.as_ref().map_or(0, |bucket| bucket.rate);
+        (rate(&self.upload), rate(&self.download))
+    }
+
+    /// Reads `upload_rate=<size>` and `download_rate=<size>` entries of the
+    /// user DB, sizes are bytes per second and take a K, M or G suffix
+    pub fn parse(specs: &[&str]) -> Result<Limits, String> {
+        let (mut upload, mut download) = (0, 0);
+
+        for spec in specs {
+            let (key, value) = match spec.find('=') {
+                Some(pos) => (&spec[..pos], &spec[pos + 1..]),
+                None => return Err(format!("{} has no value", spec)),
+            };
+            match key {
+                "upload_rate" => upload = quota::parse_size(value)?,
+                "download_rate" => download = quota::parse_size(value)?,
+                _ => return Err(format!("Unknown rate setting {}", key)),
+            }
+        }
+
+        Ok(Limits::new(upload, download))
+    }
+}
+
+/// The limits a session's transfers are held to, usually its own, its
+/// user's and the server's
+#[derive(Debug, Clone)]
+pub struct Throttle {
+    levels: Vec<Limits>,
+}
+
+impl Throttle {
+    pub fn new(levels: Vec<Limits>) -> Throttle {
+        Throttle { levels: levels }
+    }
+
+    /// Accounts for `n` bytes received, sleeping when that goes over a limit
+    pub async fn upload(&self, n: usize) {
+        wait(self.levels.iter().filter_map(|limits| limits.upload.as_ref()), n).await;
+    }
+
+    /// Accounts for `n` bytes sent, sleeping when that goes over a limit
+    pub async fn download(&self, n: usize) {
+        wait(self.levels.iter().filter_map(|limits| limits.download.as_ref()), n).await;
+    }
+}
+
+async fn wait<'a, I>(buckets: I, n: usize)
+    where I: Iterator<Item = &'a Arc<Bucket>>
+{
+    let delay = buckets.map(|bucket| bucket.take(n as u64)).max();
+
+    if let Some(delay) = delay {
+        if delay > Duration::from_millis(0) {
+            time::sleep(delay).await;
+        }
+    }
+}
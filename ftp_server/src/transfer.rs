@@ -0,0 +1,413 @@
+//! Data transfers that run beside the control connection
+//!
+//! RETR, STOR, APPE and STOU are handed to a task of their own so the
+//! session keeps reading commands while the data moves. The task never
+//! touches the control connection itself, it queues its replies on a channel
+//! and the session writes them out. That way ABOR, STAT and NOOP are
+//! answered mid-transfer.
+
+use std::future::{self, Future};
+use std::io::{self, ErrorKind};
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use rustls::ServerConfig;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use tokio::sync::Notify;
+use tokio::task::JoinHandle;
+use tokio::time;
+
+use crate::ports::{PassivePort, PASSIVE_TIMEOUT};
+use crate::server::{self, FtpMode, TransferMode};
+use crate::sessions::Hangup;
+use crate::stream::FtpStream;
+use crate::throttle::Throttle;
+use crate::xferlog::TransferLog;
+
+/// Shared between the session and the task of a single transfer
+#[derive(Clone)]
+pub struct TransferState {
+    aborted: Arc<AtomicBool>,
+    //Wakes the task up from waiting on the data connection
+    abort_notify: Arc<Notify>,
+    done: Arc<AtomicBool>,
+    bytes: Arc<AtomicU64>,
+}
+
+impl TransferState {
+    pub fn new() -> TransferState {
+        TransferState {
+            aborted: Arc::new(AtomicBool::new(false)),
+            abort_notify: Arc::new(Notify::new()),
+            done: Arc::new(AtomicBool::new(false)),
+            bytes: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    /// Flags the transfer as aborted, which cuts short whatever it is
+    /// waiting for on the data connection
+    pub fn abort(&self) {
+        self.aborted.store(true, Ordering::SeqCst);
+        self.abort_notify.notify_waiters();
+    }
+
+    pub fn aborted(&self) -> bool {
+        self.aborted.load(Ordering::SeqCst)
+    }
+
+    /// Runs `io` on the data connection, giving up with an Interrupted error
+    /// once the transfer is aborted
+    pub async fn unless_aborted<F, T>(&self, io: F) -> io::Result<T>
+        where F: Future<Output = io::Result<T>>
+    {
+        let notified = self.abort_notify.notified();
+        if self.aborted() {
+            return Err(aborted());
+        }
+
+        tokio::select! {
+            result = io => result,
+            _ = notified => Err(aborted()),
+        }
+    }
+
+    /// Whether the task has returned
+    pub fn done(&self) -> bool {
+        self.done.load(Ordering::SeqCst)
+    }
+
+    pub fn add_bytes(&self, n: usize) {
+        self.bytes.fetch_add(n as u64, Ordering::SeqCst);
+    }
+
+    pub fn bytes(&self) -> u64 {
+        self.bytes.load(Ordering::SeqCst)
+    }
+}
+
+fn aborted() -> io::Error {
+    io::Error::new(ErrorKind::Interrupted, "Transfer aborted")
+}
+
+/// A transfer running as its own task
+pub struct Transfer {
+    pub command: String,
+    pub state: TransferState,
+    replies: UnboundedReceiver<String>,
+    handle: JoinHandle<()>,
+}
+
+/// Spawns `work` as a new task. Everything it sends on the reply channel is
+/// written to the client by the session
+pub fn start<F, R>(command: &str, work: F) -> Transfer
+    where F: FnOnce(UnboundedSender<String>, TransferState) -> R,
+          R: Future<Output = ()> + Send + 'static
+{
+    let (sender, replies) = mpsc::unbounded_channel();
+    let state = TransferState::new();
+    let done = state.done.clone();
+    let work = work(sender, state.clone());
+
+    let handle = tokio::spawn(async move {
+        work.await;
+        done.store(true, Ordering::SeqCst);
+    });
+
+    Transfer {
+        command: command.to_string(),
+        state: state,
+        replies: replies,
+        handle: handle,
+    }
+}
+
+/// Queues a reply for the session to send
+pub fn queue_response(replies: &UnboundedSender<String>, msg: &str) {
+    let _ = replies.send(msg.to_string());
+}
+
+/// Waits for the client at `peer` to connect to the passive data port.
+/// Connections from anywhere else are turned away. Gives up when nobody
+/// shows up in PASSIVE_TIMEOUT
+pub async fn accept_data(listener: &TcpListener, peer: IpAddr) -> io::Result<TcpStream> {
+    let accept = async {
+        loop {
+            let (stream, addr) = listener.accept().await?;
+            if server::same_host(addr.ip(), peer) {
+                return Ok(stream);
+            }
+            info!("Refused data connection from {}, the client is {}", addr, peer);
+        }
+    };
+
+    match time::timeout(Duration::from_secs(PASSIVE_TIMEOUT), accept).await {
+        Ok(accepted) => accepted,
+        Err(_) => Err(io::Error::new(ErrorKind::TimedOut, "Nobody connected to the data port")),
+    }
+}
+
+/// Everything needed to open the data connection of a transfer
+pub struct DataChannel {
+    pub mode: FtpMode,
+    //The client on the control connection, passive mode only lets it in
+    pub peer: IpAddr,
+    //Taken by PASV or EPSV, it goes back to the pool with the channel
+    pub passive: Option<PassivePort>,
+    //Set after PROT P, the data connection is then wrapped in TLS as well
+    pub tls: Option<Arc<ServerConfig>>,
+    //Bandwidth limits the copy is held to
+    pub throttle: Throttle,
+    //Uploads and downloads are logged here once they end
+    pub xferlog: TransferLog,
+    //Set under TYPE A, line endings are translated on the way
+    pub ascii: bool,
+    //Stream, deflate after MODE Z or blocks after MODE B
+    pub transfer_mode: TransferMode,
+    //Where MODE B leaves the connection for the next transfer
+    pub kept: KeptConnection,
+}
+
+/// The data connection MODE B leaves open after a transfer. The session's
+/// next transfer goes over it instead of a new one, until the client asks
+/// for another with PASV, PORT and the like or changes the MODE
+#[derive(Clone, Default)]
+pub struct KeptConnection {
+    stream: Arc<Mutex<Option<FtpStream>>>,
+}
+
+impl KeptConnection {
+    pub fn new() -> KeptConnection {
+        KeptConnection::default()
+    }
+
+    pub fn take(&self) -> Option<FtpStream> {
+        self.stream.lock().unwrap().take()
+    }
+
+    pub fn put(&self, stream: FtpStream) {
+        *self.stream.lock().unwrap() = Some(stream);
+    }
+
+    /// Closes the kept connection, if there is one
+    pub async fn clear(&self) {
+        if let Some(mut stream) = self.take() {
+            let _ = stream.shutdown().await;
+        }
+    }
+}
+
+impl DataChannel {
+    /// Opens the data connection, connecting out in active mode or waiting
+    /// for the client in passive mode
+    pub async fn open(&self, state: &TransferState) -> Option<FtpStream> {
+        if self.transfer_mode == TransferMode::Block {
+            if let Some(stream) = self.kept.take() {
+                return Some(stream);
+            }
+        }
+
+        let stream = match self.mode {
+            FtpMode::Passive => {
+                match self.passive {
+                    Some(ref port) => {
+                        state.unless_aborted(accept_data(&port.listener, self.peer)).await
+                    }
+                    None => return None,
+                }
+            }
+            FtpMode::Active(addr) => state.unless_aborted(TcpStream::connect(addr)).await,
+        };
+
+        stream.ok().map(|stream| match self.tls {
+            Some(ref config) => FtpStream::protected(stream, config),
+            None => FtpStream::Plain(stream),
+        })
+    }
+
+    /// Whether a transfer that ended like this leaves its data connection
+    /// open, only MODE B ones that went through do
+    pub fn keeps_open(&self, result: &io::Result<()>, state: &TransferState) -> bool {
+        self.transfer_mode == TransferMode::Block && result.is_ok() && !state.aborted()
+    }
+
+    /// Sends the final reply of a transfer, 250 when the data connection
+    /// stays open. Returns whether it does
+    pub fn complete(&self,
+                    replies: &UnboundedSender<String>,
+                    result: io::Result<()>,
+                    state: &TransferState)
+                    -> bool {
+        if self.keeps_open(&result, state) {
+            queue_response(replies, &kept_open_reply());
+            return true;
+        }
+
+        complete(replies, result, state);
+        false
+    }
+
+    /// Done with the data connection of a transfer, it is kept for the next
+    /// one or closed. One that was aborted is dropped as it is
+    pub async fn close(&self, mut stream: FtpStream, keep: bool, state: &TransferState) {
+        match keep {
+            true => self.kept.put(stream),
+            false => {
+                let _ = state.unless_aborted(stream.shutdown()).await;
+            }
+        }
+    }
+}
+
+/// The final reply of a MODE B transfer that left the data connection open
+pub fn kept_open_reply() -> String {
+    format!("{} Transfer complete, data connection left open\r\n",
+            server::FILE_ACTION_OK)
+}
+
+/// Sends the final reply of a transfer based on how the copy went
+pub fn complete(replies: &UnboundedSender<String>,
+                result: io::Result<()>,
+                state: &TransferState) {
+    match result {
+        Ok(_) if !state.aborted() => {
+            queue_response(replies,
+                           &format!("{} Transfer Complete\r\n", server::CLOSING_DATA_CONNECTION));
+        }
+        _ => {
+            queue_response(replies,
+                           &format!("{} Connection closed; transfer aborted\r\n",
+                                    server::TRANSFER_ABORTED));
+        }
+    }
+}
+
+//What wakes a session up while it waits for the next command
+enum Event {
+    Line(io::Result<usize>),
+    Reply(Option<String>),
+    Expired,
+    Hangup,
+}
+
+//Resolves when the passive port has gone unused for too long, never when
+//there is none
+async fn expiry(passive: &Option<PassivePort>) {
+    match *passive {
+        Some(ref port) => port.expired().await,
+        None => future::pending().await,
+    }
+}
+
+/// Reads the next command line from the client. While a transfer is running
+/// its replies are written out in between, and a passive port left waiting
+/// too long goes back to the pool. Returns `None` once the client hangs up or
+/// the session is told to
+pub async fn read_command(client: &mut BufReader<FtpStream>,
+                          transfer: &mut Option<Transfer>,
+                          passive: &mut Option<PassivePort>,
+                          hangup: &Hangup)
+                          -> Option<String> {
+    let mut buf = Vec::new();
+
+    loop {
+        //Reading a line can be cut short and picked up again, what came in
+        //so far stays in buf
+        let event = match *transfer {
+            Some(ref mut running) => {
+                tokio::select! {
+                    read = client.read_until(b'\n', &mut buf) => Event::Line(read),
+                    msg = running.replies.recv() => Event::Reply(msg),
+                    _ = expiry(passive) => Event::Expired,
+                    _ = hangup.wait() => Event::Hangup,
+                }
+            }
+            None => {
+                tokio::select! {
+                    read = client.read_until(b'\n', &mut buf) => Event::Line(read),
+                    _ = expiry(passive) => Event::Expired,
+                    _ = hangup.wait() => Event::Hangup,
+                }
+            }
+        };
+
+        match event {
+            Event::Line(Ok(n)) if n > 0 && buf.ends_with(b"\n") => {
+                return Some(strip_telnet(&buf))
+            }
+            Event::Line(_) | Event::Hangup => return None,
+            Event::Reply(Some(msg)) => server::write_response(client, &msg).await,
+            //The task is done once it drops its end of the channel
+            Event::Reply(None) => join(client, transfer.take().unwrap()).await,
+            Event::Expired => {
+                info!("Passive port left unused, giving it back");
+                *passive = None;
+            }
+        }
+    }
+}
+
+/// Waits until the running transfer is done, sending its replies along
+/// the way
+pub async fn finish(client: &mut BufReader<FtpStream>, transfer: &mut Option<Transfer>) {
+    if let Some(mut running) = transfer.take() {
+        while let Some(msg) = running.replies.recv().await {
+            server::write_response(client, &msg).await;
+        }
+        join(client, running).await;
+    }
+}
+
+/// Handles ABOR. A running transfer gets its 426 from the task followed
+/// by our 226, otherwise there is nothing to abort
+pub async fn abort(client: &mut BufReader<FtpStream>, transfer: &mut Option<Transfer>) {
+    match *transfer {
+        Some(ref running) => {
+            info!("Aborting {}", running.command);
+            running.state.abort();
+        }
+        None => {
+            server::write_response(client,
+                                   &format!("{} No transfer to abort\r\n",
+                                            server::DATA_CONNECTION_OPEN)).await;
+            return;
+        }
+    }
+
+    finish(client, transfer).await;
+    server::write_response(client,
+                           &format!("{} ABOR command successful\r\n",
+                                    server::CLOSING_DATA_CONNECTION)).await;
+}
+
+async fn join(client: &mut BufReader<FtpStream>, running: Transfer) {
+    if running.handle.await.is_err() {
+        info!("{} failed", running.command);
+        server::write_response(client,
+                               &format!("{} Transfer failed, local error\r\n",
+                                        server::LOCAL_ERROR)).await;
+    }
+}
+
+/// Drops the Telnet IP/Synch sequence clients send ahead of ABOR
+pub fn strip_telnet(buf: &[u8]) -> String {
+    let mut line = Vec::with_capacity(buf.len());
+    let mut bytes = buf.iter().peekable();
+
+    while let Some(&byte) = bytes.next() {
+        if byte == 0xff {
+            if let Some(&&next) = bytes.peek() {
+                if next >= 0xf0 {
+                    bytes.next();
+                }
+            }
+            continue;
+        }
+        line.push(byte);
+    }
+
+    String::from_utf8_lossy(&line).into_owned()
+}
@@ -1,3 +1,5 @@
+use crate::quota::Quota;
+use crate::throttle::Limits;
 
 #[derive(Debug, Clone)]
 pub struct User {
@@ -6,6 +8,9 @@ pub struct User {
     pub role: String,
     pub path: String,
     pub cur_dir: String,
+    pub perms: Permissions,
+    pub quota: Quota,
+    pub limits: Limits,
 }
 
 impl User {
@@ -16,10 +21,113 @@ impl User {
             role: "user".to_string(),
             path: "".to_string(),
             cur_dir: "".to_string(),
+            perms: Permissions::all(),
+            quota: Quota::unlimited(),
+            limits: Limits::unlimited(),
         }
     }
 
     pub fn set_role(&mut self, new_role: &str) {
         self.role = new_role.to_string();
     }
+
+    /// Whether the user may do `cap` to a path below their root
+    pub fn can(&self, cap: Capability, path: &str) -> bool {
+        self.perms.allows(cap, path)
+    }
+}
+
+/// Something a user may be allowed to do to a file or directory. The
+/// letters are the ones of the MLST perm fact (RFC 3659)
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Capability {
+    List,
+    Read,
+    Write,
+    Append,
+    Delete,
+    Rename,
+    Mkdir,
+    Rmdir,
+}
+
+impl Capability {
+    pub fn letter(&self) -> char {
+        match *self {
+            Capability::List => 'l',
+            Capability::Read => 'r',
+            Capability::Write => 'w',
+            Capability::Append => 'a',
+            Capability::Delete => 'd',
+            Capability::Rename => 'f',
+            Capability::Mkdir => 'm',
+            Capability::Rmdir => 'p',
+        }
+    }
+}
+
+const ALL_CAPABILITIES: &'static str = "lrwadfmp";
+
+/// Capabilities of a user by directory, as given after the role in the user
+/// DB. `lr` applies to the whole root, `/=lr /drop=lrw` gives more in
+/// `/drop` and below. The most specific directory wins and paths no rule
+/// covers get nothing
+#[derive(Debug, Clone)]
+pub struct Permissions {
+    rules: Vec<(String, String)>,
+}
+
+impl Permissions {
+    /// Everything everywhere, for users without any rules
+    pub fn all() -> Permissions {
+        Permissions { rules: vec![("/".to_string(), ALL_CAPABILITIES.to_string())] }
+    }
+
+    pub fn parse(specs: &[&str]) -> Result<Permissions, String> {
+        if specs.is_empty() {
+            return Ok(Permissions::all());
+        }
+
+        let mut rules = Vec::new();
+        for spec in specs {
+            let (dir, letters) = match spec.find('=') {
+                Some(pos) => (&spec[..pos], &spec[pos + 1..]),
+                None => ("/", *spec),
+            };
+
+            if !dir.starts_with('/') {
+                return Err(format!("{} is not an absolute directory", dir));
+            }
+            let letters = match letters {
+                "all" => ALL_CAPABILITIES,
+                "none" => "",
+                _ => letters,
+            };
+            if let Some(bad) = letters.chars().find(|c| !ALL_CAPABILITIES.contains(*c)) {
+                return Err(format!("Unknown capability {} in {}", bad, spec));
+            }
+
+            let dir = match dir.trim_right_matches('/') {
+                "" => "/",
+                dir => dir,
+            };
+            rules.push((dir.to_string(), letters.to_string()));
+        }
+
+        Ok(Permissions { rules: rules })
+    }
+
+    pub fn allows(&self, cap: Capability, path: &str) -> bool {
+        let rule = self.rules
+            .iter()
+            .filter(|&&(ref dir, _)| {
+                dir == "/" || path == dir || path.starts_with(&format!("{}/", dir))
+            })
+            .max_by_key(|&&(ref dir, _)| dir.len());
+
+        match rule {
+            Some(&(_, ref letters)) => letters.contains(cap.letter()),
+            None => false,
+        }
+    }
 }
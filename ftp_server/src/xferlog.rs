@@ -0,0 +1,166 @@
+//! The transfer log, one line per upload or download in the wu-ftpd xferlog
+//! format that log analyzers and billing scripts read
+//!
+//! ```text
+//! Sun Oct 18 10:31:02 2026 3 127.0.0.1 50000000 /big.bin b _ o r user1 ftp 0 * c
+//! ```
+//!
+//! The fields are the time the transfer ended, how many seconds it took, the
+//! client's address, the bytes moved, the file, `a`scii or `b`inary, the
+//! special action (`_`, none), `o`utgoing or `i`ncoming, `r`eal user, the
+//! user name, the service, no RFC 931 authentication (`0 *`) and whether it
+//! was `c`omplete or `i`ncomplete. Times are UTC and files are the paths the
+//! user sees below their root, with spaces turned into underscores so the
+//! line stays splittable on whitespace
+
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::prelude::*;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use crate::server;
+
+const DAYS: [&'static str; 7] = ["Sun", "Mon", "Tue", "Wed", "Thu", "Fri", "Sat"];
+const MONTHS: [&'static str; 12] = ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug",
+                                    "Sep", "Oct", "Nov", "Dec"];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Direction {
+    Incoming,
+    Outgoing,
+}
+
+/// One finished transfer
+#[derive(Debug)]
+pub struct Entry<'a> {
+    pub time: SystemTime,
+    pub seconds: u64,
+    pub host: IpAddr,
+    pub bytes: u64,
+    pub path: &'a str,
+    pub direction: Direction,
+    pub ascii: bool,
+    pub user: &'a str,
+    pub complete: bool,
+}
+
+/// The log file, shared by every session. Lines are appended, the file is
+/// never truncated
+#[derive(Debug)]
+pub struct XferLog {
+    file: Option<Mutex<File>>,
+}
+
+impl XferLog {
+    /// Opens `path` for appending, an empty path turns the log off
+    pub fn open(path: &str) -> io::Result<XferLog> {
+        if path.is_empty() {
+            return Ok(XferLog { file: None });
+        }
+
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(XferLog { file: Some(Mutex::new(file)) })
+    }
+
+    pub fn write(&self, entry: &Entry) {
+        if let Some(ref file) = self.file {
+            let line = format_entry(entry);
+            if let Err(e) = file.lock().unwrap().write_all(line.as_bytes()) {
+                info!("Could not write to the transfer log: {}", e);
+            }
+        }
+    }
+}
+
+/// A session's handle on the log, it knows the client's address
+#[derive(Debug, Clone)]
+pub struct TransferLog {
+    log: Arc<XferLog>,
+    host: IpAddr,
+}
+
+impl TransferLog {
+    pub fn new(log: Arc<XferLog>, host: IpAddr) -> TransferLog {
+        TransferLog {
+            log: log,
+            host: host,
+        }
+    }
+
+    /// Logs a transfer that began at `started` and has just ended
+    pub fn record(&self,
+                  user: &str,
+                  path: &str,
+                  direction: Direction,
+                  ascii: bool,
+                  bytes: u64,
+                  started: Instant,
+                  complete: bool) {
+        self.log.write(&Entry {
+            time: SystemTime::now(),
+            seconds: started.elapsed().as_secs(),
+            host: self.host,
+            bytes: bytes,
+            path: path,
+            direction: direction,
+            ascii: ascii,
+            user: user,
+            complete: complete,
+        });
+    }
+}
+
+/// Formats an entry as an xferlog line, newline included
+pub fn format_entry(entry: &Entry) -> String {
+    let path: String = entry.path
+        .chars()
+        .map(|c| match c.is_whitespace() {
+            true => '_',
+            false => c,
+        })
+        .collect();
+
+    format!("{} {} {} {} {} {} _ {} r {} ftp 0 * {}\n",
+            ctime(entry.time),
+            entry.seconds,
+            entry.host,
+            entry.bytes,
+            path,
+            match entry.ascii {
+                true => "a",
+                false => "b",
+            },
+            match entry.direction {
+                Direction::Incoming => "i",
+                Direction::Outgoing => "o",
+            },
+            entry.user,
+            match entry.complete {
+                true => "c",
+                false => "i",
+            })
+}
+
+//Formats a time like ctime(3) does, "Sun Oct 18 10:31:02 2026"
+fn ctime(time: SystemTime) -> String {
+    let secs = match time.duration_since(UNIX_EPOCH) {
+        Ok(elapsed) => elapsed.as_secs(),
+        Err(_) => 0,
+    };
+
+    let days = (secs / 86400) as i64;
+    let (year, month, day) = server::civil_from_days(days);
+    let rem = secs % 86400;
+
+    //The epoch was a Thursday
+    format!("{} {} {:2} {:02}:{:02}:{:02} {}",
+            DAYS[((days + 4) % 7) as usize],
+            MONTHS[(month - 1) as usize],
+            day,
+            rem / 3600,
+            (rem % 3600) / 60,
+            rem % 60,
+            year)
+}